@@ -0,0 +1,88 @@
+//! `--pin-cpu`: pins the process to a set of cores so other work on the
+//! machine doesn't move the benchmark's threads around.
+
+use anyhow::Result;
+
+use crate::config::CpuSet;
+
+/// Pins the calling thread, and so every thread it starts afterwards,
+/// DuckDB's workers included, to `cpus`. Call it before the connection is
+/// opened. Only Linux is supported; elsewhere this warns and does nothing.
+#[cfg(target_os = "linux")]
+pub fn pin_to_cpus(cpus: &CpuSet) -> Result<()> {
+    use anyhow::Context;
+
+    // SAFETY: cpu_set_t is a plain bit mask, for which all zeroes is the
+    // empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in &cpus.cores {
+        if core >= libc::CPU_SETSIZE as usize {
+            anyhow::bail!(
+                "Core {} is beyond the {} cores Linux can pin to",
+                core,
+                libc::CPU_SETSIZE
+            );
+        }
+        // SAFETY: core is within the set, checked above
+        unsafe { libc::CPU_SET(core, &mut set) };
+    }
+    // SAFETY: set is a valid cpu_set_t of the size passed; pid 0 is the
+    // calling thread
+    let status =
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+    if status != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to pin to CPUs {}", cpus));
+    }
+    log::info!("Pinned to CPUs {}", cpus);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_cpus(cpus: &CpuSet) -> Result<()> {
+    log::warn!(
+        "--pin-cpu {} isn't supported on this platform; running unpinned",
+        cpus
+    );
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::config::parse_cpu_set;
+
+    /// The cores the calling thread may run on.
+    fn current_cpus() -> Vec<usize> {
+        // SAFETY: as in pin_to_cpus
+        let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+        let status =
+            unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+        assert_eq!(status, 0);
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|&core| unsafe { libc::CPU_ISSET(core, &set) })
+            .collect()
+    }
+
+    #[test]
+    fn pins_the_thread_to_the_listed_cores() {
+        let cpus = parse_cpu_set("2-3,0,3").unwrap();
+        assert_eq!(cpus.cores, vec![0, 2, 3]);
+        assert_eq!(cpus.to_string(), "0,2-3");
+        assert!(parse_cpu_set("3-1").is_err());
+        assert!(parse_cpu_set("a").is_err());
+
+        // On a thread of its own, so the other tests keep every core
+        std::thread::spawn(|| {
+            let first = current_cpus()[0];
+            pin_to_cpus(&CpuSet { cores: vec![first] }).unwrap();
+            assert_eq!(current_cpus(), vec![first]);
+            let unavailable = CpuSet {
+                cores: vec![libc::CPU_SETSIZE as usize],
+            };
+            assert!(pin_to_cpus(&unavailable).is_err());
+        })
+        .join()
+        .unwrap();
+    }
+}
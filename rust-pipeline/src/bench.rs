@@ -0,0 +1,229 @@
+//! The `bench` subcommand: runs the Rust and the Python pipeline on the same
+//! data as child processes, measured the same way, and compares them.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use sysinfo::{Pid, System};
+
+use crate::metrics::MEMORY_SAMPLE_INTERVAL;
+use crate::stats::Stats;
+use crate::term::{summary_rule, title};
+
+/// What `run_bench` runs.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub data_dir: PathBuf,
+    /// Both pipelines' outputs and logs go here.
+    pub out_dir: PathBuf,
+    /// Program and arguments for each side; `{data_dir}` and `{output}` in
+    /// an argument are filled in.
+    pub rust_cmd: Vec<String>,
+    pub python_cmd: Vec<String>,
+    pub runs: u32,
+}
+
+/// One pipeline's measurements over the runs.
+#[derive(Debug, Clone, Serialize)]
+pub struct PipelineTimings {
+    pub command: Vec<String>,
+    pub durations_secs: Vec<f64>,
+    pub peak_memory_mb: Vec<f64>,
+    pub median_duration_secs: f64,
+    pub median_peak_memory_mb: f64,
+}
+
+/// Written to the `--json` file, one per bench run, for trend tracking.
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchReport {
+    pub timestamp: String,
+    pub data_dir: String,
+    pub runs: u32,
+    pub rust: PipelineTimings,
+    pub python: PipelineTimings,
+    /// Python's median duration over Rust's.
+    pub speedup: f64,
+    /// Python's median peak memory over Rust's.
+    pub memory_ratio: f64,
+}
+
+/// `command` with the placeholders filled in. Substituted per argument, so
+/// paths with spaces stay one argument.
+fn fill_command(command: &[String], data_dir: &Path, output: &Path) -> Vec<String> {
+    command
+        .iter()
+        .map(|arg| {
+            arg.replace("{data_dir}", &data_dir.to_string_lossy())
+                .replace("{output}", &output.to_string_lossy())
+        })
+        .collect()
+}
+
+/// Runs `command` to completion with its output in `log`, sampling the
+/// child's resident memory on another thread. Returns the wall-clock
+/// seconds and the peak in MB.
+fn measure(command: &[String], log: &Path) -> Result<(f64, f64)> {
+    let (program, args) = command.split_first().context("Empty benchmark command")?;
+    let log_file =
+        File::create(log).with_context(|| format!("Failed to create {}", log.display()))?;
+    let start = Instant::now();
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+    let pid = Pid::from_u32(child.id());
+    let peak_bytes = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+    let status = std::thread::scope(|scope| {
+        let sampler = scope.spawn(|| {
+            let mut system = System::new();
+            while !done.load(Ordering::Relaxed) {
+                if system.refresh_process(pid) {
+                    if let Some(process) = system.process(pid) {
+                        peak_bytes.fetch_max(process.memory(), Ordering::Relaxed);
+                    }
+                }
+                std::thread::park_timeout(MEMORY_SAMPLE_INTERVAL);
+            }
+        });
+        let status = child.wait();
+        done.store(true, Ordering::Relaxed);
+        sampler.thread().unpark();
+        status
+    })?;
+    let secs = start.elapsed().as_secs_f64();
+    if !status.success() {
+        anyhow::bail!(
+            "`{}` failed ({}); see {}",
+            command.join(" "),
+            status,
+            log.display()
+        );
+    }
+    let peak_mb = peak_bytes.load(Ordering::Relaxed) as f64 / 1024.0 / 1024.0;
+    Ok((secs, peak_mb))
+}
+
+fn run_side(options: &BenchOptions, name: &str, command: &[String]) -> Result<PipelineTimings> {
+    let output = options.out_dir.join(format!("{}_output.csv", name));
+    let command = fill_command(command, &options.data_dir, &output);
+    let mut durations = Vec::new();
+    let mut peaks = Vec::new();
+    for run in 1..=options.runs {
+        log::info!("{} pipeline, run {}/{}", name, run, options.runs);
+        let log = options.out_dir.join(format!("{}_run{}.log", name, run));
+        let (secs, peak_mb) = measure(&command, &log)?;
+        durations.push(secs);
+        peaks.push(peak_mb);
+    }
+    let median = |samples: &[f64]| Stats::from_samples(samples).map_or(0.0, |s| s.median);
+    Ok(PipelineTimings {
+        command,
+        median_duration_secs: median(&durations),
+        median_peak_memory_mb: median(&peaks),
+        durations_secs: durations,
+        peak_memory_mb: peaks,
+    })
+}
+
+/// All Rust runs, then all Python runs, each in a fresh child process so
+/// both sides pay the same start-up cost.
+pub fn run_bench(options: &BenchOptions) -> Result<BenchReport> {
+    std::fs::create_dir_all(&options.out_dir)
+        .with_context(|| format!("Failed to create {}", options.out_dir.display()))?;
+    let rust = run_side(options, "rust", &options.rust_cmd)?;
+    let python = run_side(options, "python", &options.python_cmd)?;
+    let ratio = |python: f64, rust: f64| if rust > 0.0 { python / rust } else { 0.0 };
+    Ok(BenchReport {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        data_dir: options.data_dir.to_string_lossy().into_owned(),
+        runs: options.runs,
+        speedup: ratio(python.median_duration_secs, rust.median_duration_secs),
+        memory_ratio: ratio(python.median_peak_memory_mb, rust.median_peak_memory_mb),
+        rust,
+        python,
+    })
+}
+
+pub fn print_bench(report: &BenchReport) {
+    println!("\n{}", summary_rule());
+    println!(
+        "{}",
+        title(format!(
+            "Rust vs Python ({} run{}, medians)",
+            report.runs,
+            if report.runs == 1 { "" } else { "s" }
+        ))
+    );
+    println!("{}", summary_rule());
+    println!(
+        "{:<18} {:>12} {:>12} {:>12}",
+        "", "Rust", "Python", "Python/Rust"
+    );
+    println!(
+        "{:<18} {:>12.2} {:>12.2} {:>11.2}x",
+        "Duration (s)",
+        report.rust.median_duration_secs,
+        report.python.median_duration_secs,
+        report.speedup
+    );
+    println!(
+        "{:<18} {:>12.2} {:>12.2} {:>11.2}x",
+        "Peak Memory (MB)",
+        report.rust.median_peak_memory_mb,
+        report.python.median_peak_memory_mb,
+        report.memory_ratio
+    );
+    println!("{}", summary_rule());
+    println!();
+}
+
+pub fn write_bench_json(path: &Path, report: &BenchReport) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create the bench JSON directory")?;
+    }
+    let json = serde_json::to_string_pretty(report)?;
+    std::fs::write(path, json).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_times_both_commands_and_fills_placeholders() {
+        let dir = tempfile::tempdir().unwrap();
+        let sh = |script: &str| ["sh", "-c", script].map(String::from).to_vec();
+        let options = BenchOptions {
+            data_dir: PathBuf::from("my data"),
+            out_dir: dir.path().to_path_buf(),
+            rust_cmd: sh("sleep 0.1; echo \"$0\" > '{output}'"),
+            python_cmd: [
+                sh("sleep 0.3; echo \"$0\" > '{output}'"),
+                vec!["{data_dir}".into()],
+            ]
+            .concat(),
+            runs: 2,
+        };
+        let report = run_bench(&options).unwrap();
+        assert_eq!(report.rust.durations_secs.len(), 2);
+        assert!(report.speedup > 1.5, "speedup {}", report.speedup);
+        assert!(report.python.median_peak_memory_mb > 0.0);
+        let python_output = std::fs::read_to_string(dir.path().join("python_output.csv")).unwrap();
+        assert_eq!(python_output, "my data\n");
+
+        let failing = BenchOptions {
+            python_cmd: sh("exit 3"),
+            runs: 1,
+            ..options
+        };
+        let err = run_bench(&failing).unwrap_err().to_string();
+        assert!(err.contains("python_run1.log"), "{}", err);
+    }
+}
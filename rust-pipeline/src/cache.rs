@@ -0,0 +1,161 @@
+//! `--cache`: keeps `cleaned_data` in a Parquet file, so later runs on the
+//! same input skip loading and cleaning.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::metrics::{
+    CastFailure, DateFormatMatch, ImputedValues, PipelineMetrics, RowCounts, RuleRemoval,
+    UnnestedJson,
+};
+use crate::sql::{build_cache_copy_sql, pending_output_path, PipelineSql};
+
+/// Written next to the cache. The SQL ties the cache to the input globs and
+/// the cleaning options it was built with, the file list to the files the
+/// globs matched then.
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheKey {
+    sql: String,
+    /// Sorted, so a deleted or added file shows as a difference.
+    files: Vec<String>,
+    metrics: CachedMetrics,
+}
+
+/// The counts loading and cleaning record, kept with the cache so a hit
+/// reports them as the run that wrote it did.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CachedMetrics {
+    rows: RowCounts,
+    removed_by_filter: Vec<RuleRemoval>,
+    imputed: Vec<ImputedValues>,
+    date_formats: Vec<DateFormatMatch>,
+    cast_failures: Vec<CastFailure>,
+    unnested: Option<UnnestedJson>,
+}
+
+impl CachedMetrics {
+    fn of(metrics: &PipelineMetrics) -> Self {
+        CachedMetrics {
+            rows: metrics.rows.clone(),
+            removed_by_filter: metrics.removed_by_filter.clone(),
+            imputed: metrics.imputed.clone(),
+            date_formats: metrics.date_formats.clone(),
+            cast_failures: metrics.cast_failures.clone(),
+            unnested: metrics.unnested.clone(),
+        }
+    }
+
+    pub(crate) fn restore(self, metrics: &mut PipelineMetrics) {
+        metrics.rows = self.rows;
+        metrics.removed_by_filter = self.removed_by_filter;
+        metrics.imputed = self.imputed;
+        metrics.date_formats = self.date_formats;
+        metrics.cast_failures = self.cast_failures;
+        metrics.unnested = self.unnested;
+    }
+}
+
+fn sorted(files: &[String]) -> Vec<String> {
+    let mut files = files.to_vec();
+    files.sort();
+    files
+}
+
+fn key_path(path: &Path) -> PathBuf {
+    let mut key = path.as_os_str().to_owned();
+    key.push(".key.json");
+    PathBuf::from(key)
+}
+
+/// What a cache has to match: the statements that build `raw_data` and
+/// `cleaned_data`.
+pub(crate) fn cache_key_sql(sql: &PipelineSql) -> String {
+    format!("{};\n{}", sql.raw, sql.clean)
+}
+
+/// The counts the cache at `path` was built with, if it can stand in for
+/// loading and cleaning: it was built from `key_sql` over the same
+/// `input_files`, and is newer than each of them. Logs why not otherwise.
+pub(crate) fn read_cache(
+    path: &Path,
+    key_sql: &str,
+    input_files: &[String],
+) -> Result<Option<CachedMetrics>> {
+    if !path.exists() {
+        info!(
+            "No cache at {} yet; writing it after cleaning",
+            path.display()
+        );
+        return Ok(None);
+    }
+    let key: Option<CacheKey> = std::fs::read_to_string(key_path(path))
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok());
+    let Some(key) = key.filter(|k| k.sql == key_sql) else {
+        info!(
+            "Cache {} was built with other input or cleaning options; rebuilding it",
+            path.display()
+        );
+        return Ok(None);
+    };
+    if key.files != sorted(input_files) {
+        info!(
+            "Input files were added or removed since cache {} was written; rebuilding it",
+            path.display()
+        );
+        return Ok(None);
+    }
+    let built = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .with_context(|| format!("Failed to stat {}", path.display()))?;
+    for file in input_files {
+        let modified = std::fs::metadata(file)
+            .and_then(|m| m.modified())
+            .with_context(|| format!("Failed to stat {}", file))?;
+        if modified >= built {
+            info!(
+                "{} changed after cache {} was written; rebuilding it",
+                file,
+                path.display()
+            );
+            return Ok(None);
+        }
+    }
+    Ok(Some(key.metrics))
+}
+
+/// Writes `cleaned_data` to `path`, through a pending file so a killed run
+/// leaves no partial cache, then the key and `metrics`' counts next to it.
+pub(crate) fn write_cache(
+    conn: &Connection,
+    path: &Path,
+    key_sql: &str,
+    input_files: &[String],
+    metrics: &PipelineMetrics,
+) -> Result<()> {
+    if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let pending = pending_output_path(path);
+    conn.execute(&build_cache_copy_sql(&pending), [])
+        .with_context(|| format!("Failed to write cache {}", path.display()))?;
+    std::fs::rename(&pending, path).with_context(|| {
+        format!(
+            "Failed to rename {} to {}",
+            pending.display(),
+            path.display()
+        )
+    })?;
+    let key = CacheKey {
+        sql: key_sql.to_string(),
+        files: sorted(input_files),
+        metrics: CachedMetrics::of(metrics),
+    };
+    let key_path = key_path(path);
+    std::fs::write(&key_path, serde_json::to_string_pretty(&key)?)
+        .with_context(|| format!("Failed to write {}", key_path.display()))
+}
@@ -0,0 +1,186 @@
+//! The `compare` subcommand, which diffs two `--metrics-json` reports, and
+//! `--baseline`, which diffs a run against one.
+
+use anyhow::Result;
+use log::warn;
+use std::path::Path;
+
+use crate::metrics::{read_metrics_json, MetricsReport};
+use crate::term::{bad, good, summary_rule, title};
+
+/// One row of the `compare` table.
+#[derive(Debug)]
+pub struct MetricDelta {
+    pub name: String,
+    pub baseline: f64,
+    pub candidate: f64,
+    /// `None` when the baseline is zero and a percentage is meaningless.
+    pub delta_pct: Option<f64>,
+    pub regressed: bool,
+}
+
+/// Duration, and peak memory when both runs tracked it.
+fn headline_pairs(baseline: &MetricsReport, candidate: &MetricsReport) -> Vec<(String, f64, f64)> {
+    let mut pairs = vec![(
+        "Duration (s)".to_string(),
+        baseline.duration_secs,
+        candidate.duration_secs,
+    )];
+    if let (Some(base), Some(other)) = (baseline.peak_memory_mb, candidate.peak_memory_mb) {
+        pairs.push(("Peak Memory (MB)".to_string(), base, other));
+    }
+    pairs
+}
+
+/// Duration, peak memory when both runs tracked it, and every step present
+/// in both reports. Higher is
+/// worse for all of them.
+pub fn compare_reports(
+    baseline: &MetricsReport,
+    candidate: &MetricsReport,
+    threshold_pct: f64,
+) -> Vec<MetricDelta> {
+    let mut pairs = headline_pairs(baseline, candidate);
+    for step in &baseline.steps {
+        if let Some(other) = candidate.steps.iter().find(|s| s.name == step.name) {
+            pairs.push((format!("{} (s)", step.name), step.secs, other.secs));
+        }
+    }
+    deltas(pairs, threshold_pct)
+}
+
+/// Like `compare_reports`, but only duration and peak memory: single steps
+/// are too short for a CI gate not to flap on them.
+pub fn compare_to_baseline(
+    baseline: &MetricsReport,
+    candidate: &MetricsReport,
+    tolerance_pct: f64,
+) -> Vec<MetricDelta> {
+    deltas(headline_pairs(baseline, candidate), tolerance_pct)
+}
+
+fn deltas(pairs: Vec<(String, f64, f64)>, threshold_pct: f64) -> Vec<MetricDelta> {
+    pairs
+        .into_iter()
+        .map(|(name, baseline, candidate)| {
+            let delta_pct = (baseline > 0.0).then(|| (candidate - baseline) / baseline * 100.0);
+            MetricDelta {
+                name,
+                baseline,
+                candidate,
+                delta_pct,
+                regressed: delta_pct.is_some_and(|d| d > threshold_pct),
+            }
+        })
+        .collect()
+}
+
+pub fn print_comparison(deltas: &[MetricDelta], threshold_pct: f64) {
+    println!("\n{}", summary_rule());
+    println!(
+        "{}",
+        title(format!(
+            "Metrics Comparison (regression threshold {:.1}%)",
+            threshold_pct
+        ))
+    );
+    println!("{}", summary_rule());
+    println!(
+        "{:<20} {:>10} {:>10} {:>9}",
+        "", "baseline", "candidate", "delta"
+    );
+    for delta in deltas {
+        let pct = match delta.delta_pct {
+            Some(pct) => format!("{:+.1}%", pct),
+            None => "n/a".to_string(),
+        };
+        // Padded before styling, since the escape codes would count as width
+        let pct = format!("{:>9}", pct);
+        let pct = match delta.delta_pct {
+            _ if delta.regressed => bad(pct),
+            Some(change) if change < 0.0 => good(pct),
+            _ => console::style(pct),
+        };
+        println!(
+            "{:<20} {:>10.2} {:>10.2} {}{}",
+            delta.name,
+            delta.baseline,
+            delta.candidate,
+            pct,
+            if delta.regressed {
+                bad("  REGRESSION").to_string()
+            } else {
+                String::new()
+            }
+        );
+    }
+    println!("{}", summary_rule());
+    println!();
+}
+
+/// Reports from different DuckDB versions are compared but warned about.
+fn warn_on_version_change(baseline: &MetricsReport, candidate: &MetricsReport) {
+    if let (Some(old), Some(new)) = (&baseline.duckdb_version, &candidate.duckdb_version) {
+        if old != new {
+            warn!(
+                "Comparing DuckDB {} (baseline) with {} (candidate)",
+                old, new
+            );
+        }
+    }
+}
+
+/// Runs the `compare` subcommand; the exit code is non-zero on regression.
+pub fn run_compare(baseline: &Path, candidate: &Path, threshold_pct: f64) -> Result<bool> {
+    let baseline = read_metrics_json(baseline)?;
+    let candidate = read_metrics_json(candidate)?;
+    warn_on_version_change(&baseline, &candidate);
+    let deltas = compare_reports(&baseline, &candidate, threshold_pct);
+    print_comparison(&deltas, threshold_pct);
+    Ok(deltas.iter().any(|d| d.regressed))
+}
+
+/// `--baseline`: compares a run's report with the one in `path`.
+pub fn check_baseline(
+    path: &Path,
+    report: &MetricsReport,
+    tolerance_pct: f64,
+) -> Result<Vec<MetricDelta>> {
+    if !path.exists() {
+        anyhow::bail!(
+            "Baseline {} doesn't exist; --update-baseline writes it",
+            path.display()
+        );
+    }
+    let baseline = read_metrics_json(path)?;
+    warn_on_version_change(&baseline, report);
+    Ok(compare_to_baseline(&baseline, report, tolerance_pct))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{write_metrics_json, PipelineMetrics};
+
+    #[test]
+    fn compare_flags_regressions_past_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut metrics = PipelineMetrics::new();
+        metrics.update_memory();
+        metrics.record_step("Load");
+        metrics.record_step("Save");
+        let path = dir.path().join("metrics.json");
+        write_metrics_json(&path, &metrics.report(None)).unwrap();
+        let baseline = read_metrics_json(&path).unwrap();
+        assert_eq!(baseline.steps.len(), 2);
+
+        let mut candidate = read_metrics_json(&path).unwrap();
+        candidate.duration_secs = baseline.duration_secs * 1.05;
+        candidate.peak_memory_mb = baseline.peak_memory_mb.map(|mb| mb * 1.5);
+        let deltas = compare_reports(&baseline, &candidate, 10.0);
+        assert_eq!(deltas.len(), 2 + baseline.steps.len());
+        assert!(!deltas[0].regressed);
+        assert!(deltas[1].regressed);
+        assert!(deltas[2..].iter().all(|d| !d.regressed));
+    }
+}
@@ -0,0 +1,1546 @@
+//! Run configuration: everything `run_pipeline` needs to know, plus the
+//! parsers behind the command-line options that build it.
+
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::expect::Expectations;
+use crate::s3::is_s3;
+use crate::sql::{quote_ident, sql_string, try_date_sql, try_strptime_sql};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Parquet,
+    Json,
+    #[value(name = "jsonl", alias = "ndjson")]
+    JsonLines,
+}
+
+impl OutputFormat {
+    /// Options passed to DuckDB's `COPY ... TO` for this format.
+    pub(crate) fn copy_options(&self) -> &'static str {
+        match self {
+            // The delimiter comes from `PipelineConfig::output_delimiter`
+            OutputFormat::Csv => "HEADER",
+            OutputFormat::Parquet => "FORMAT PARQUET",
+            OutputFormat::Json => "FORMAT JSON, ARRAY true",
+            OutputFormat::JsonLines => "FORMAT JSON",
+        }
+    }
+
+    /// The format an `--output` path's extension stands for.
+    fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "csv" => Some(OutputFormat::Csv),
+            "parquet" => Some(OutputFormat::Parquet),
+            "json" => Some(OutputFormat::Json),
+            "jsonl" | "ndjson" => Some(OutputFormat::JsonLines),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Json => "json",
+            OutputFormat::JsonLines => "jsonl",
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Csv => "CSV",
+            OutputFormat::Parquet => "Parquet",
+            OutputFormat::Json => "JSON",
+            OutputFormat::JsonLines => "JSON Lines",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A check a row must pass to survive the cleaning step.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Predicate {
+    NotNull,
+    Positive,
+    ValidDate,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationRule {
+    pub column: String,
+    pub predicate: Predicate,
+}
+
+impl ValidationRule {
+    pub fn new(column: &str, predicate: Predicate) -> Self {
+        Self {
+            column: column.to_string(),
+            predicate,
+        }
+    }
+
+    /// SQL condition that holds for rows passing this rule. Dates must match
+    /// one of `date_formats` when any are given.
+    pub(crate) fn condition(&self, date_formats: &[String]) -> String {
+        let column = quote_ident(&self.column);
+        match self.predicate {
+            Predicate::NotNull => format!("{} IS NOT NULL", column),
+            Predicate::Positive => format!("{} > 0", column),
+            Predicate::ValidDate if date_formats.is_empty() => {
+                format!("TRY_CAST({} AS DATE) IS NOT NULL", column)
+            }
+            Predicate::ValidDate => {
+                format!(
+                    "{} IS NOT NULL",
+                    try_strptime_sql(&self.column, date_formats)
+                )
+            }
+        }
+    }
+
+    /// What failing the rule means, e.g. `null product_id`.
+    pub(crate) fn describe_failure(&self) -> String {
+        let failure = match self.predicate {
+            Predicate::NotNull => "null",
+            Predicate::Positive => "non-positive",
+            Predicate::ValidDate => "invalid",
+        };
+        format!("{} {}", failure, self.column)
+    }
+}
+
+impl fmt::Display for ValidationRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let check = match self.predicate {
+            Predicate::NotNull => "not-null",
+            Predicate::Positive => "positive",
+            Predicate::ValidDate => "valid-date",
+        };
+        write!(f, "{}:{}", self.column, check)
+    }
+}
+
+/// Parses `column:check`, where check is `not-null`, `positive` or `valid-date`.
+pub fn parse_rule(value: &str) -> std::result::Result<ValidationRule, String> {
+    let (column, check) = value
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' should look like COLUMN:CHECK", value))?;
+    let predicate = match check.trim() {
+        "not-null" => Predicate::NotNull,
+        "positive" => Predicate::Positive,
+        "valid-date" => Predicate::ValidDate,
+        other => {
+            return Err(format!(
+                "unknown check '{}' (expected not-null, positive or valid-date)",
+                other
+            ))
+        }
+    };
+    let column = column.trim();
+    if column.is_empty() {
+        return Err(format!("'{}' is missing a column name", value));
+    }
+    Ok(ValidationRule::new(column, predicate))
+}
+
+/// A business filter from `--exclude-products`, `--max-price` or
+/// `--max-quantity`, applied on top of the validity rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BusinessFilter {
+    /// How the filter is reported, e.g. `max-price:1000000`.
+    pub(crate) name: String,
+    /// Holds for rows the filter keeps. Missing values are left to the rules.
+    pub(crate) condition: String,
+}
+
+/// The checks the pipeline has always applied to the sales schema.
+pub fn default_rules(date_column: &str) -> Vec<ValidationRule> {
+    vec![
+        ValidationRule::new("product_id", Predicate::NotNull),
+        ValidationRule::new("quantity", Predicate::Positive),
+        ValidationRule::new("price", Predicate::Positive),
+        ValidationRule::new(date_column, Predicate::ValidDate),
+    ]
+}
+
+/// A function `--aggregations` can apply to a column per group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Median,
+}
+
+/// One measure of the aggregate, e.g. `sum:revenue`; `count:*` counts rows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aggregation {
+    pub function: AggregateFunction,
+    pub column: String,
+}
+
+impl Aggregation {
+    pub fn new(function: AggregateFunction, column: &str) -> Self {
+        Self {
+            function,
+            column: column.to_string(),
+        }
+    }
+
+    /// The output column: `total_revenue`, `avg_price`, `max_price`, ... and
+    /// `row_count` for `count:*`.
+    pub(crate) fn alias(&self) -> String {
+        if self.column == "*" {
+            return "row_count".to_string();
+        }
+        let prefix = match self.function {
+            AggregateFunction::Sum => "total",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Count => "count",
+            AggregateFunction::Median => "median",
+        };
+        format!("{}_{}", prefix, self.column)
+    }
+
+    /// The aggregate call, e.g. `SUM("revenue")`.
+    pub(crate) fn sql(&self) -> String {
+        let argument = if self.column == "*" {
+            "*".to_string()
+        } else {
+            quote_ident(&self.column)
+        };
+        let function = match self.function {
+            AggregateFunction::Sum => "SUM",
+            AggregateFunction::Avg => "AVG",
+            AggregateFunction::Min => "MIN",
+            AggregateFunction::Max => "MAX",
+            AggregateFunction::Count => "COUNT",
+            AggregateFunction::Median => "MEDIAN",
+        };
+        format!("{}({})", function, argument)
+    }
+
+    /// Whether `--round` applies: measures of the revenue and price columns
+    /// other than counts.
+    pub(crate) fn is_monetary(&self) -> bool {
+        self.function != AggregateFunction::Count
+            && (self.column == "revenue" || self.column == "price")
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let function = match self.function {
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Count => "count",
+            AggregateFunction::Median => "median",
+        };
+        write!(f, "{}:{}", function, self.column)
+    }
+}
+
+/// Parses `function:column`, where function is `sum`, `avg`, `min`, `max`,
+/// `count` or `median`; only `count` takes `*`.
+pub fn parse_aggregation(value: &str) -> std::result::Result<Aggregation, String> {
+    let (function, column) = value
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' should look like FUNCTION:COLUMN", value))?;
+    let function = match function.trim().to_ascii_lowercase().as_str() {
+        "sum" => AggregateFunction::Sum,
+        "avg" => AggregateFunction::Avg,
+        "min" => AggregateFunction::Min,
+        "max" => AggregateFunction::Max,
+        "count" => AggregateFunction::Count,
+        "median" => AggregateFunction::Median,
+        other => {
+            return Err(format!(
+                "unknown aggregate function '{}' (expected sum, avg, min, max, count or median)",
+                other
+            ))
+        }
+    };
+    let column = column.trim();
+    if column.is_empty() {
+        return Err(format!("'{}' is missing a column name", value));
+    }
+    if column == "*" && function != AggregateFunction::Count {
+        return Err(format!("'{}': only count can take *", value));
+    }
+    Ok(Aggregation::new(function, column))
+}
+
+/// The measures the pipeline has always computed per group.
+pub fn default_aggregations() -> Vec<Aggregation> {
+    vec![
+        Aggregation::new(AggregateFunction::Sum, "quantity"),
+        Aggregation::new(AggregateFunction::Sum, "revenue"),
+        Aggregation::new(AggregateFunction::Avg, "price"),
+    ]
+}
+
+/// Everything `run_pipeline` needs to know about a single run.
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub data_dir: String,
+    pub output_path: String,
+    pub format: OutputFormat,
+    pub recursive: bool,
+    pub input_format: InputFormat,
+    pub memory_limit: Option<String>,
+    pub threads: Option<u32>,
+    /// Rerun once with one thread and half the memory limit after a DuckDB
+    /// out-of-memory error.
+    pub auto_degrade: bool,
+    /// Where DuckDB spills data that doesn't fit in `memory_limit`.
+    pub temp_dir: Option<PathBuf>,
+    /// Runs `PipelineSql::warmup` before the timed steps.
+    pub warmup_db: bool,
+    pub db_path: Option<PathBuf>,
+    pub rules: Vec<ValidationRule>,
+    pub group_by: Vec<String>,
+    /// Calendar fields of the date column added as columns by the transform.
+    pub time_dims: Vec<TimeDim>,
+    /// Measures computed per group, in output column order.
+    pub aggregations: Vec<Aggregation>,
+    pub allow_empty: bool,
+    pub column_types: Vec<ColumnType>,
+    pub revenue_expr: RevenueExpr,
+    pub dedup: Dedup,
+    pub date_column: String,
+    /// strptime formats tried in order; dates are cast when empty.
+    pub date_formats: Vec<String>,
+    pub top_n: Option<u64>,
+    /// Output column the aggregate is sorted by, and so which groups
+    /// `top_n` keeps.
+    pub order_by: String,
+    pub order: SortOrder,
+    /// Adds a grand-total row, with NULL group-by keys, after the groups.
+    pub with_totals: bool,
+    pub max_retries: u32,
+    /// Price quantiles added to the aggregate as `price_pNN` columns.
+    pub quantiles: Vec<f64>,
+    /// Periods in the `--window` moving average. When set, the output has a
+    /// row per group and period rather than per group.
+    pub window: Option<u32>,
+    /// The period columns `--window` orders by, oldest first.
+    pub window_order: Vec<String>,
+    /// Output revenue per group and year, with the year before's and the
+    /// change in percent, instead of the `aggregations` measures.
+    pub yoy: bool,
+    /// Column to partition Parquet output by; `output_path` is then a directory.
+    pub partition_by: Option<String>,
+    /// Order the output by year and quarter before `order_by`, and list each
+    /// quarter's revenue in the summary. Needs both in `group_by`.
+    pub quarterly: bool,
+    /// With `quarterly`, partition the output by year and quarter, so each
+    /// quarter gets a file of its own.
+    pub split_quarters: bool,
+    /// Capture each step's query plan into `PipelineResult::plans`.
+    pub explain: Option<ExplainMode>,
+    /// CSV field delimiter and quote character; sniffed when `None`.
+    pub csv_delimiter: Option<char>,
+    pub csv_quote: Option<char>,
+    /// Profile `raw_data` into `PipelineResult::profile` before cleaning.
+    pub profile: bool,
+    /// Buckets one numeric column of `transformed_data` into
+    /// `PipelineResult::histogram`.
+    pub histogram: Option<HistogramSpec>,
+    /// Log each view's row count once it's created, counting the views the
+    /// run wouldn't otherwise.
+    pub verbose: bool,
+    /// Field delimiter for CSV output.
+    pub output_delimiter: char,
+    pub null_strategy: NullStrategy,
+    /// Set when `data_dir` holds input spooled from stdin; only shows in the
+    /// summary.
+    pub stdin_input: bool,
+    /// Directory to write `cleaned_data` and `transformed_data` to as Parquet.
+    pub dump_intermediate: Option<PathBuf>,
+    /// Parquet file keeping `cleaned_data` between runs; reused while it's
+    /// newer than the input and was built with the same cleaning options.
+    pub cache: Option<PathBuf>,
+    /// Written from the same aggregate as `output_path`.
+    pub extra_outputs: Vec<OutputDestination>,
+    /// Glob of product IDs to drop, e.g. `TEST_*`.
+    pub exclude_products: Option<String>,
+    pub max_price: Option<f64>,
+    pub max_quantity: Option<f64>,
+    /// Write JSON Lines outputs row by row instead of with `COPY`.
+    pub stream_jsonl: bool,
+    /// Write CSV outputs from Rust with thousands separators in numbers
+    /// instead of with `COPY`.
+    pub format_numbers: bool,
+    /// Hash each output into `WrittenOutput::sha256`.
+    pub checksum: bool,
+    /// Share of input rows to keep, sampled row by row; `None` reads them all.
+    pub sample: Option<f64>,
+    /// Seed making `sample` pick the same rows on every run.
+    pub seed: u64,
+    /// Read only the first this many input rows, after any `sample`.
+    pub limit_rows: Option<u64>,
+    /// Count the rows failing each rule into `removed_by_rule`.
+    pub diagnose: bool,
+    /// Shows this many removed rows with the rules and filters they failed.
+    pub explain_removed: Option<u64>,
+    /// CSV file for the `explain_removed` rows; stderr when `None`.
+    pub explain_removed_to: Option<PathBuf>,
+    /// Add the results to outputs that already exist instead of overwriting
+    /// them; the existing columns have to match.
+    pub append: bool,
+    /// Database table the results are written to instead of output files.
+    pub to_table: Option<TableDestination>,
+    /// Replace an existing `to_table` table; without this or `append` an
+    /// existing table is an error.
+    pub replace_table: bool,
+    /// Decimal places to round the revenue and price measures and the price
+    /// quantiles to; unrounded when `None`.
+    pub round: Option<u32>,
+    /// Peak memory above this many MB is highlighted in the summary.
+    pub memory_warn_mb: Option<f64>,
+    /// Raw columns `cleaned_data` keeps, besides those the pipeline needs;
+    /// every column when empty.
+    pub columns: Vec<String>,
+    /// Input columns renamed as they're read, so inputs from other systems
+    /// get the column names the pipeline expects.
+    pub renames: Vec<ColumnRename>,
+    /// `currency,rate` CSV; revenue is multiplied by the rate of the row's
+    /// `currency` into the base currency the rates are quoted in.
+    pub rates: Option<PathBuf>,
+    /// Replaces the built-in transform: SQL creating `user_transformed`,
+    /// which aggregation then reads.
+    pub sql_file: Option<SqlFile>,
+    /// Only rows dated on or after this; the rest count as before the
+    /// watermark.
+    pub since: Option<NaiveDate>,
+    /// Where a successful run records the latest date it processed.
+    pub state_file: Option<PathBuf>,
+    /// Fail the run when cleaning removes more than this percentage of rows.
+    pub fail_on_removed_pct: Option<f64>,
+    /// More directories read along with `data_dir`, as a single input.
+    pub extra_data_dirs: Vec<String>,
+    /// Read exactly these files instead of listing the data directories.
+    pub files: Vec<String>,
+    /// Array in each JSON document whose elements become the input rows.
+    pub json_unnest: Option<JsonUnnest>,
+    /// Constraints the aggregated output must meet before it's written.
+    pub expect: Option<Expectations>,
+    /// Stop after cleaning, with the per-rule breakdown and no output; the
+    /// run fails if more than `fail_on_removed_pct` was removed.
+    pub validate_only: bool,
+    /// Region and S3-compatible endpoint for `s3://` data locations.
+    pub s3_region: Option<String>,
+    pub s3_endpoint: Option<String>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: "data".to_string(),
+            output_path: "results/rust_output".to_string(),
+            format: OutputFormat::Csv,
+            recursive: false,
+            input_format: InputFormat::Auto,
+            memory_limit: None,
+            temp_dir: None,
+            warmup_db: false,
+            threads: None,
+            auto_degrade: false,
+            db_path: None,
+            rules: default_rules("date"),
+            aggregations: default_aggregations(),
+            group_by: vec!["product_id".to_string()],
+            time_dims: default_time_dims(),
+            allow_empty: false,
+            column_types: Vec::new(),
+            revenue_expr: RevenueExpr::default(),
+            dedup: Dedup::Off,
+            date_column: "date".to_string(),
+            date_formats: Vec::new(),
+            top_n: None,
+            order_by: "total_revenue".to_string(),
+            order: SortOrder::Desc,
+            with_totals: false,
+            max_retries: 3,
+            quantiles: Vec::new(),
+            window: None,
+            window_order: vec!["year".to_string(), "month".to_string()],
+            yoy: false,
+            partition_by: None,
+            quarterly: false,
+            split_quarters: false,
+            explain: None,
+            csv_delimiter: None,
+            csv_quote: None,
+            profile: false,
+            histogram: None,
+            verbose: false,
+            output_delimiter: ',',
+            null_strategy: NullStrategy::Drop,
+            stdin_input: false,
+            dump_intermediate: None,
+            cache: None,
+            extra_outputs: Vec::new(),
+            exclude_products: None,
+            max_price: None,
+            max_quantity: None,
+            stream_jsonl: false,
+            format_numbers: false,
+            checksum: false,
+            sample: None,
+            limit_rows: None,
+            seed: 42,
+            diagnose: false,
+            explain_removed: None,
+            explain_removed_to: None,
+            append: false,
+            to_table: None,
+            replace_table: false,
+            round: None,
+            memory_warn_mb: None,
+            columns: Vec::new(),
+            renames: Vec::new(),
+            rates: None,
+            sql_file: None,
+            since: None,
+            state_file: None,
+            fail_on_removed_pct: None,
+            extra_data_dirs: Vec::new(),
+            files: Vec::new(),
+            json_unnest: None,
+            expect: None,
+            validate_only: false,
+            s3_region: None,
+            s3_endpoint: None,
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Input columns the cleaning and transform steps depend on. Group-by
+    /// columns are checked separately since they may be derived ones.
+    pub(crate) fn required_columns(&self) -> Vec<String> {
+        let mut required: Vec<String> = Vec::new();
+        let referenced = self
+            .rules
+            .iter()
+            .map(|r| r.column.as_str())
+            .chain(self.revenue_expr.columns.iter().map(|c| c.as_str()))
+            .chain(self.dedup.columns().iter().map(|c| c.as_str()))
+            .chain(self.column_types.iter().map(|t| t.column.as_str()))
+            .chain(self.exclude_products.as_ref().map(|_| "product_id"))
+            .chain(self.rates.as_ref().map(|_| "currency"))
+            .chain(["quantity", "price", self.date_column.as_str()]);
+        for column in referenced {
+            if !required.iter().any(|r| r == column) {
+                required.push(column.to_string());
+            }
+        }
+        required
+    }
+
+    /// The name `column` has in the input files, before `--rename`.
+    pub(crate) fn source_column<'a>(&'a self, column: &'a str) -> &'a str {
+        self.renames
+            .iter()
+            .find(|r| r.to == column)
+            .map_or(column, |r| r.from.as_str())
+    }
+
+    /// The `--columns` projection: the listed columns, then the required and
+    /// group-by ones not already listed. Group-by columns the transform
+    /// computes are left out. `None` keeps every column.
+    pub(crate) fn projected_columns(&self) -> Option<Vec<String>> {
+        if self.columns.is_empty() {
+            return None;
+        }
+        let mut projected = self.columns.clone();
+        let group_by = self
+            .group_by
+            .iter()
+            .chain(self.window_periods())
+            .filter(|c| !self.is_computed(c))
+            .cloned();
+        for column in self.required_columns().into_iter().chain(group_by) {
+            if !projected.contains(&column) {
+                projected.push(column);
+            }
+        }
+        Some(projected)
+    }
+
+    /// Whether the transform step adds `column` to `transformed_data`.
+    pub(crate) fn is_computed(&self, column: &str) -> bool {
+        column == "revenue" || self.time_dims.iter().any(|d| d.column() == column)
+    }
+
+    /// The `--window-order` columns, or none without `--window`.
+    pub(crate) fn window_periods(&self) -> &[String] {
+        match self.window {
+            Some(_) => &self.window_order,
+            None => &[],
+        }
+    }
+
+    /// The columns of `aggregated_data`, in order.
+    pub(crate) fn output_columns(&self) -> Vec<String> {
+        let mut columns = self.group_by.clone();
+        if self.yoy {
+            columns.push(YOY_PERIOD.to_string());
+            columns.extend(YOY_COLUMNS.iter().map(|c| c.to_string()));
+            return columns;
+        }
+        columns.extend(self.window_periods().iter().cloned());
+        columns.extend(self.aggregations.iter().map(Aggregation::alias));
+        columns.extend(self.quantiles.iter().map(|q| quantile_column(*q)));
+        if self.window.is_some() {
+            columns.push(MOVING_AVERAGE_COLUMN.to_string());
+        }
+        columns
+    }
+
+    /// Columns whose bad values `null_strategy` imputes instead of dropping.
+    pub(crate) fn imputed_columns(&self) -> &'static [&'static str] {
+        match self.null_strategy {
+            NullStrategy::Drop => &[],
+            NullStrategy::Zero | NullStrategy::Mean => &["quantity", "price"],
+        }
+    }
+
+    /// The rules rows are filtered on; those on imputed columns decide which
+    /// values get imputed instead.
+    pub(crate) fn cleaning_rules(&self) -> Vec<ValidationRule> {
+        let imputed = self.imputed_columns();
+        self.rules
+            .iter()
+            .filter(|r| !imputed.contains(&r.column.as_str()))
+            .cloned()
+            .collect()
+    }
+
+    /// The business filters in the order they're reported.
+    pub(crate) fn business_filters(&self) -> Vec<BusinessFilter> {
+        let mut filters = Vec::new();
+        if let Some(pattern) = &self.exclude_products {
+            filters.push(BusinessFilter {
+                name: format!("exclude-products:{}", pattern),
+                condition: format!(
+                    "NOT COALESCE({} GLOB {}, false)",
+                    quote_ident("product_id"),
+                    sql_string(pattern)
+                ),
+            });
+        }
+        for (option, column, max) in [
+            ("max-price", "price", self.max_price),
+            ("max-quantity", "quantity", self.max_quantity),
+        ] {
+            if let Some(max) = max {
+                filters.push(BusinessFilter {
+                    name: format!("{}:{}", option, max),
+                    condition: format!("COALESCE({} <= {}, true)", quote_ident(column), max),
+                });
+            }
+        }
+        if let Some(since) = self.since {
+            // An unreadable date can't be placed after the watermark
+            filters.push(BusinessFilter {
+                name: format!("since:{}", since),
+                condition: format!(
+                    "COALESCE({} >= DATE '{}', false)",
+                    try_date_sql(&self.date_column, &self.date_formats),
+                    since
+                ),
+            });
+        }
+        filters
+    }
+
+    /// The columns the output is partitioned by, outermost first; empty when
+    /// it's a single file.
+    pub(crate) fn partition_columns(&self) -> Vec<&str> {
+        if self.split_quarters {
+            vec!["year", "quarter"]
+        } else {
+            self.partition_by.iter().map(|c| c.as_str()).collect()
+        }
+    }
+
+    /// The option that partitions the output, for messages.
+    pub(crate) fn partition_option(&self) -> Option<&'static str> {
+        if self.split_quarters {
+            Some("--split-quarters")
+        } else {
+            self.partition_by.as_ref().map(|_| "--partition-by")
+        }
+    }
+
+    /// Where the output goes: a directory when partitioning, otherwise a file
+    /// with the format's extension.
+    pub(crate) fn output_target(&self) -> PathBuf {
+        match self.partition_option() {
+            Some(_) => PathBuf::from(&self.output_path),
+            None => resolve_output_path(&self.output_path, self.format),
+        }
+    }
+
+    /// `output_target` followed by the extra outputs; none when the results
+    /// go to a `--to-table` table.
+    pub(crate) fn destinations(&self) -> Vec<OutputDestination> {
+        if self.to_table.is_some() {
+            return Vec::new();
+        }
+        let mut destinations = vec![OutputDestination {
+            path: self.output_target(),
+            format: self.format,
+        }];
+        destinations.extend(self.extra_outputs.iter().cloned());
+        destinations
+    }
+
+    /// Whether `destination` is written by `--stream-jsonl`.
+    pub(crate) fn streams(&self, destination: &OutputDestination) -> bool {
+        self.stream_jsonl && destination.format == OutputFormat::JsonLines
+    }
+
+    /// Whether `destination` is written by `--format-numbers`.
+    pub(crate) fn formats_numbers(&self, destination: &OutputDestination) -> bool {
+        self.format_numbers && destination.format == OutputFormat::Csv
+    }
+
+    /// How `--sample` was applied, for the banner and summary.
+    pub(crate) fn sample_description(&self) -> Option<String> {
+        self.sample.map(|fraction| {
+            format!(
+                "{}% of input rows (bernoulli, seed {})",
+                fraction * 100.0,
+                self.seed
+            )
+        })
+    }
+
+    pub(crate) fn storage_description(&self) -> String {
+        match &self.db_path {
+            Some(path) => format!("file-backed ({})", path.display()),
+            None => "in-memory".to_string(),
+        }
+    }
+
+    /// `data_dir` followed by `extra_data_dirs`, or the `files` when those
+    /// are given instead.
+    pub(crate) fn data_dirs(&self) -> Vec<&str> {
+        if !self.files.is_empty() {
+            return self.files.iter().map(|f| f.as_str()).collect();
+        }
+        std::iter::once(self.data_dir.as_str())
+            .chain(self.extra_data_dirs.iter().map(|d| d.as_str()))
+            .collect()
+    }
+
+    /// The data directories, or the input files, for messages.
+    pub(crate) fn data_dir_description(&self) -> String {
+        self.data_dirs().join(", ")
+    }
+
+    /// Glob handed to DuckDB for locating input files in `dir` with `extension`.
+    /// An S3 location that's already a glob, e.g. `s3://bucket/*.csv`, is
+    /// used as is.
+    pub(crate) fn input_pattern(&self, dir: &str, extension: &str) -> String {
+        if is_s3_glob(dir) {
+            dir.to_string()
+        } else if self.recursive {
+            format!("{}/**/*.{}", glob_base(dir), extension)
+        } else {
+            format!("{}/*.{}", glob_base(dir), extension)
+        }
+    }
+
+    /// Glob matching every file in `dir`, used to detect the input format.
+    pub(crate) fn discovery_pattern(&self, dir: &str) -> String {
+        if is_s3_glob(dir) {
+            dir.to_string()
+        } else if self.recursive {
+            format!("{}/**/*", glob_base(dir))
+        } else {
+            format!("{}/*", glob_base(dir))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// Pick the reader from the file extensions found in the data directory
+    Auto,
+    Csv,
+    Parquet,
+    Json,
+}
+
+impl InputFormat {
+    pub(crate) const CONCRETE: [InputFormat; 3] =
+        [InputFormat::Csv, InputFormat::Parquet, InputFormat::Json];
+
+    /// File name suffixes read as this format. DuckDB detects the gzip
+    /// compression of `.csv.gz` files from the name.
+    pub(crate) fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            InputFormat::Auto => &[],
+            InputFormat::Csv => &["csv", "csv.gz"],
+            InputFormat::Parquet => &["parquet"],
+            InputFormat::Json => &["json", "jsonl", "ndjson"],
+        }
+    }
+}
+
+impl fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            InputFormat::Auto => "auto",
+            InputFormat::Csv => "CSV",
+            InputFormat::Parquet => "Parquet",
+            InputFormat::Json => "JSON",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A `--column-types` entry forcing the type DuckDB reads a CSV column as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnType {
+    pub column: String,
+    pub sql_type: String,
+}
+
+/// A `--rename` entry: input column `from` is read as `to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnRename {
+    pub from: String,
+    pub to: String,
+}
+
+/// Parses one `FROM=TO` entry of `--rename`.
+pub fn parse_rename(value: &str) -> std::result::Result<ColumnRename, String> {
+    match value.split_once('=') {
+        Some((from, to)) if !from.trim().is_empty() && !to.trim().is_empty() => Ok(ColumnRename {
+            from: from.trim().to_string(),
+            to: to.trim().to_string(),
+        }),
+        _ => Err(format!("'{}' should look like FROM=TO", value)),
+    }
+}
+
+/// `--json-unnest`: the dotted path to an array in each JSON document, e.g.
+/// `order.items`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonUnnest {
+    /// The structs leading to the array, then the array itself.
+    pub path: Vec<String>,
+}
+
+impl fmt::Display for JsonUnnest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.join("."))
+    }
+}
+
+pub fn parse_json_unnest(value: &str) -> std::result::Result<JsonUnnest, String> {
+    let path: Vec<String> = value.split('.').map(|s| s.trim().to_string()).collect();
+    if path.iter().any(|field| field.is_empty()) {
+        return Err(format!(
+            "'{}' should be a dotted path to an array, e.g. order.items",
+            value
+        ));
+    }
+    Ok(JsonUnnest { path })
+}
+
+/// `--histogram COLUMN:BUCKETS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSpec {
+    pub column: String,
+    pub buckets: u32,
+}
+
+/// Most `--histogram` buckets; more wouldn't fit on a screen.
+const MAX_HISTOGRAM_BUCKETS: u32 = 100;
+
+pub fn parse_histogram(value: &str) -> std::result::Result<HistogramSpec, String> {
+    let (column, buckets) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("'{}' should look like COLUMN:BUCKETS, e.g. price:10", value))?;
+    let buckets = match buckets.trim().parse::<u32>() {
+        Ok(n) if (1..=MAX_HISTOGRAM_BUCKETS).contains(&n) => n,
+        _ => {
+            return Err(format!(
+                "'{}' is not a bucket count between 1 and {}",
+                buckets, MAX_HISTOGRAM_BUCKETS
+            ))
+        }
+    };
+    if column.trim().is_empty() {
+        return Err(format!("'{}' doesn't name a column", value));
+    }
+    Ok(HistogramSpec {
+        column: column.trim().to_string(),
+        buckets,
+    })
+}
+
+/// `--pin-cpu` cores, e.g. `0-3` or `0,2,4-5`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet {
+    /// Sorted, without duplicates.
+    pub cores: Vec<usize>,
+}
+
+impl fmt::Display for CpuSet {
+    /// Consecutive cores are written as a range again.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for &core in &self.cores {
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == core => *last = core,
+                _ => ranges.push((core, core)),
+            }
+        }
+        let parts: Vec<String> = ranges
+            .iter()
+            .map(|&(first, last)| {
+                if first == last {
+                    first.to_string()
+                } else {
+                    format!("{}-{}", first, last)
+                }
+            })
+            .collect();
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+pub fn parse_cpu_set(value: &str) -> std::result::Result<CpuSet, String> {
+    let core = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("'{}' is not a core number", s.trim()))
+    };
+    let mut cores = Vec::new();
+    for part in value.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (core(first)?, core(last)?);
+                if first > last {
+                    return Err(format!("'{}' is an empty core range", part.trim()));
+                }
+                cores.extend(first..=last);
+            }
+            None => cores.push(core(part)?),
+        }
+    }
+    cores.sort_unstable();
+    cores.dedup();
+    Ok(CpuSet { cores })
+}
+
+/// All entries of one `--column-types` argument.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnTypes(pub Vec<ColumnType>);
+
+/// Upper-cases `NAME`, `NAME(p)` or `NAME(p,s)` and rejects anything else,
+/// since the type is spliced into SQL as is.
+fn normalize_sql_type(value: &str) -> Option<String> {
+    let (name, args) = match value.trim().split_once('(') {
+        Some((name, rest)) => (name.trim(), Some(rest.strip_suffix(')')?)),
+        None => (value.trim(), None),
+    };
+    let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        return None;
+    }
+    let name = name.to_ascii_uppercase();
+    let Some(args) = args else {
+        return Some(name);
+    };
+    let args: Vec<&str> = args.split(',').map(str::trim).collect();
+    let numeric = |a: &&str| !a.is_empty() && a.chars().all(|c| c.is_ascii_digit());
+    if args.len() > 2 || !args.iter().all(numeric) {
+        return None;
+    }
+    Some(format!("{}({})", name, args.join(",")))
+}
+
+/// Parses `col=TYPE,col=TYPE`; commas inside parentheses (as in
+/// `DECIMAL(10,2)`) don't split entries.
+pub fn parse_column_types(value: &str) -> std::result::Result<ColumnTypes, String> {
+    let mut entries = Vec::new();
+    let mut depth = 0;
+    let mut current = String::new();
+    for c in value.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                entries.push(std::mem::take(&mut current));
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+    entries.push(current);
+
+    let mut types = Vec::new();
+    for entry in entries {
+        let (column, sql_type) = entry
+            .split_once('=')
+            .ok_or_else(|| format!("'{}' should look like COLUMN=TYPE", entry))?;
+        let column = column.trim();
+        let sql_type = match normalize_sql_type(sql_type) {
+            Some(sql_type) if !column.is_empty() => sql_type,
+            _ => return Err(format!("'{}' is not a valid COLUMN=TYPE override", entry)),
+        };
+        types.push(ColumnType {
+            column: column.to_string(),
+            sql_type,
+        });
+    }
+    Ok(ColumnTypes(types))
+}
+
+/// `dir` as the start of an input glob, without trailing separators so
+/// `data/` doesn't become `data//*.csv`.
+fn glob_base(dir: &str) -> String {
+    glob_base_for(dir, cfg!(windows))
+}
+
+/// On Windows, where `\` and `/` both separate, the directory also gets
+/// `/` throughout so the pattern doesn't mix `C:\data` with the `/*.csv`
+/// suffix. Elsewhere a backslash is part of a file name and is kept.
+pub(crate) fn glob_base_for(dir: &str, windows: bool) -> String {
+    let dir = if windows {
+        dir.replace('\\', "/")
+    } else {
+        dir.to_string()
+    };
+    // A bare root keeps its separator through the pattern's own `/`
+    dir.trim_end_matches('/').to_string()
+}
+
+fn is_s3_glob(location: &str) -> bool {
+    is_s3(location) && location.contains(['*', '?', '['])
+}
+
+/// The directories in a comma-separated data directory list. A directory
+/// whose own name has a comma in it is taken whole.
+pub fn split_data_dirs(value: &str) -> Vec<String> {
+    if value == "-" || PathBuf::from(value).is_dir() {
+        return vec![value.to_string()];
+    }
+    value
+        .split(',')
+        .map(|dir| dir.trim().to_string())
+        .filter(|dir| !dir.is_empty())
+        .collect()
+}
+
+/// Rejects data directories that don't exist so we fail before touching DuckDB.
+/// `-` stands for stdin, which can't be mixed with directories. `s3://`
+/// locations are only listed once httpfs is loaded.
+pub fn parse_data_dir(value: &str) -> std::result::Result<String, String> {
+    let dirs = split_data_dirs(value);
+    if dirs.len() > 1 && dirs.iter().any(|dir| dir == "-") {
+        return Err("'-' (stdin) can't be combined with data directories".to_string());
+    }
+    match dirs
+        .iter()
+        .find(|dir| *dir != "-" && !is_s3(dir) && !PathBuf::from(dir).is_dir())
+    {
+        Some(dir) => Err(format!("'{}' is not an existing directory", dir)),
+        None if dirs.is_empty() => Err("no data directory given".to_string()),
+        None => Ok(value.to_string()),
+    }
+}
+
+/// Accepts sizes like `4GB`, `512MB` or `1.5GiB` as understood by DuckDB.
+pub fn parse_memory_limit(value: &str) -> std::result::Result<String, String> {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB", "KIB", "MIB", "GIB", "TIB"];
+    let trimmed = value.trim();
+    let split = trimmed
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(trimmed.len());
+    let (number, unit) = trimmed.split_at(split);
+    let valid_number = number.parse::<f64>().map(|n| n > 0.0).unwrap_or(false);
+    if valid_number && UNITS.contains(&unit.trim().to_ascii_uppercase().as_str()) {
+        Ok(trimmed.to_string())
+    } else {
+        Err(format!(
+            "'{}' is not a valid size (expected a number followed by B, KB, MB, GB or TB, e.g. 4GB)",
+            value
+        ))
+    }
+}
+
+/// Bytes in a `parse_memory_limit` size. DuckDB reads KB, MB and so on as
+/// powers of 1000 and KiB, MiB and so on as powers of 1024.
+pub(crate) fn memory_limit_bytes(limit: &str) -> Option<f64> {
+    let split = limit.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (number, unit) = limit.split_at(split);
+    let scale = match unit.trim().to_ascii_uppercase().as_str() {
+        "B" => 1.0,
+        "KB" => 1e3,
+        "MB" => 1e6,
+        "GB" => 1e9,
+        "TB" => 1e12,
+        "KIB" => 1024.0,
+        "MIB" => 1024f64.powi(2),
+        "GIB" => 1024f64.powi(3),
+        "TIB" => 1024f64.powi(4),
+        _ => return None,
+    };
+    Some(number.parse::<f64>().ok()? * scale)
+}
+
+/// A finite number above zero, for the `--max-*` filters and `--timeout`.
+pub fn parse_positive_number(value: &str) -> std::result::Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() && n > 0.0 => Ok(n),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+/// A single CSV dialect character; `\t` and `tab` mean a tab.
+pub fn parse_csv_char(value: &str) -> std::result::Result<char, String> {
+    if value == "\\t" || value.eq_ignore_ascii_case("tab") {
+        return Ok('\t');
+    }
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(format!(
+            "'{}' should be a single character (or \\t for tab)",
+            value
+        )),
+    }
+}
+
+/// A number between 0 and 1 inclusive; `what` names it in the error.
+fn parse_unit_interval(value: &str, what: &str) -> std::result::Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        _ => Err(format!("'{}' is not a {} between 0 and 1", value, what)),
+    }
+}
+
+/// Accepts a fraction between 0 and 1 inclusive, e.g. `0.05`.
+pub fn parse_fraction(value: &str) -> std::result::Result<f64, String> {
+    parse_unit_interval(value, "fraction")
+}
+
+/// A percentage between 0 and 100 inclusive, e.g. `20` or `2.5`.
+pub fn parse_percentage(value: &str) -> std::result::Result<f64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(v) if (0.0..=100.0).contains(&v) => Ok(v),
+        _ => Err(format!("'{}' is not a percentage between 0 and 100", value)),
+    }
+}
+
+/// Where `--since` starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    Date(NaiveDate),
+    /// The date `--state-file` recorded in the previous run.
+    Auto,
+}
+
+/// A `--since` value: a YYYY-MM-DD date or `auto`.
+pub fn parse_since(value: &str) -> std::result::Result<Since, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(Since::Auto);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(Since::Date)
+        .map_err(|_| format!("'{}' is neither a YYYY-MM-DD date nor 'auto'", value))
+}
+
+/// A `--sample` fraction; like `parse_fraction`, but a sample of nothing is
+/// no use.
+pub fn parse_sample_fraction(value: &str) -> std::result::Result<f64, String> {
+    match parse_unit_interval(value, "fraction")? {
+        f if f > 0.0 => Ok(f),
+        _ => Err(format!(
+            "--sample needs a fraction above 0, not '{}'",
+            value
+        )),
+    }
+}
+
+/// Accepts a quantile between 0 and 1 inclusive.
+pub fn parse_quantile(value: &str) -> std::result::Result<f64, String> {
+    parse_unit_interval(value, "quantile")
+}
+
+/// Output column for a price quantile: 0.5 is `price_p50`, 0.995 `price_p99_5`.
+pub(crate) fn quantile_column(q: f64) -> String {
+    let pct = (q * 100.0 * 1e4).round() / 1e4;
+    format!("price_p{}", pct).replace('.', "_")
+}
+
+/// One file the aggregate is written to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputDestination {
+    pub path: PathBuf,
+    pub format: OutputFormat,
+}
+
+/// Parses an `--output` path, taking the format from its extension.
+pub fn parse_output_destination(value: &str) -> std::result::Result<OutputDestination, String> {
+    let path = PathBuf::from(value);
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match OutputFormat::from_extension(extension) {
+        Some(format) => Ok(OutputDestination { path, format }),
+        None => Err(format!(
+            "can't tell the format of '{}'; use a .csv, .parquet, .json or .jsonl extension",
+            value
+        )),
+    }
+}
+
+/// A `--to-table` target: a table in a DuckDB database file, or in a SQLite
+/// one for a `.sqlite` or `.sqlite3` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDestination {
+    pub database: PathBuf,
+    pub table: String,
+}
+
+impl TableDestination {
+    pub(crate) fn is_sqlite(&self) -> bool {
+        let extension = self.database.extension().and_then(|e| e.to_str());
+        matches!(extension, Some("sqlite" | "sqlite3"))
+    }
+}
+
+impl fmt::Display for TableDestination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {}", self.table, self.database.display())
+    }
+}
+
+/// Parses `DB_PATH:TABLE`, splitting at the last colon so Windows paths
+/// keep their drive letter.
+pub fn parse_table_destination(value: &str) -> std::result::Result<TableDestination, String> {
+    match value.rsplit_once(':') {
+        Some((database, table)) if !database.is_empty() && !table.trim().is_empty() => {
+            Ok(TableDestination {
+                database: PathBuf::from(database),
+                table: table.trim().to_string(),
+            })
+        }
+        _ => Err(format!("'{}' should look like DB_PATH:TABLE", value)),
+    }
+}
+
+/// Appends the format's default extension when the output path has none.
+pub fn resolve_output_path(output_path: &str, format: OutputFormat) -> PathBuf {
+    let path = PathBuf::from(output_path);
+    if path.extension().is_some() {
+        path
+    } else {
+        path.with_extension(format.extension())
+    }
+}
+
+/// What cleaning does with rows whose price or quantity is missing or fails
+/// its rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum NullStrategy {
+    /// Drop the row
+    #[default]
+    Drop,
+    /// Keep the row with the value set to 0
+    Zero,
+    /// Keep the row with the value set to the column's mean over valid values
+    /// (which makes an integer column DOUBLE)
+    Mean,
+}
+
+/// A calendar field of the date column that the transform step adds as a
+/// column, for grouping by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimeDim {
+    Year,
+    Month,
+    Quarter,
+    /// ISO week of the year, 1 to 53
+    Week,
+    /// 0 for Sunday to 6 for Saturday
+    DayOfWeek,
+    /// 1 to 366
+    DayOfYear,
+}
+
+impl TimeDim {
+    /// The column name in `transformed_data`.
+    pub(crate) fn column(self) -> &'static str {
+        match self {
+            TimeDim::Year => "year",
+            TimeDim::Month => "month",
+            TimeDim::Quarter => "quarter",
+            TimeDim::Week => "week",
+            TimeDim::DayOfWeek => "day_of_week",
+            TimeDim::DayOfYear => "day_of_year",
+        }
+    }
+
+    /// The `EXTRACT` part name.
+    pub(crate) fn part(self) -> &'static str {
+        match self {
+            TimeDim::Year => "YEAR",
+            TimeDim::Month => "MONTH",
+            TimeDim::Quarter => "QUARTER",
+            TimeDim::Week => "WEEK",
+            TimeDim::DayOfWeek => "DOW",
+            TimeDim::DayOfYear => "DOY",
+        }
+    }
+}
+
+/// Year, month and quarter, the dimensions the transform has always added.
+pub fn default_time_dims() -> Vec<TimeDim> {
+    vec![TimeDim::Year, TimeDim::Month, TimeDim::Quarter]
+}
+
+/// Direction of the `--order-by` sort.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    pub(crate) fn sql(self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExplainMode {
+    /// The optimized plan, without running the query
+    Plan,
+    /// Runs the query to completion and reports per-operator timings
+    Analyze,
+}
+
+/// Whether and how duplicate rows are dropped between cleaning and transforming.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Dedup {
+    Off,
+    FullRow,
+    /// Keeps the first row, in input order, for each combination of these columns.
+    OnColumns(Vec<String>),
+}
+
+impl fmt::Display for Dedup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dedup::Off => write!(f, "off"),
+            Dedup::FullRow => write!(f, "full row"),
+            Dedup::OnColumns(columns) => write!(f, "on {}", columns.join(", ")),
+        }
+    }
+}
+
+impl Dedup {
+    pub(crate) fn columns(&self) -> &[String] {
+        match self {
+            Dedup::OnColumns(columns) => columns,
+            _ => &[],
+        }
+    }
+}
+
+/// The `--window` output column holding the moving average of revenue.
+pub(crate) const MOVING_AVERAGE_COLUMN: &str = "moving_avg_revenue";
+
+/// The period `--yoy` compares, added after the group-by keys.
+pub(crate) const YOY_PERIOD: &str = "year";
+
+/// The `--yoy` output columns after the keys: the year's revenue, the year
+/// before's and the change in percent.
+pub(crate) const YOY_COLUMNS: [&str; 3] = ["revenue", "prev_year_revenue", "yoy_pct"];
+
+/// Scalar functions `--revenue-expr` may call; anything else is rejected.
+const REVENUE_FUNCTIONS: [&str; 8] = [
+    "abs", "coalesce", "greatest", "least", "nullif", "round", "ceil", "floor",
+];
+
+/// The revenue computation in the transform step. Only column references,
+/// numbers, arithmetic and `REVENUE_FUNCTIONS` are allowed, so the SQL can
+/// be spliced into the view definition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevenueExpr {
+    pub(crate) sql: String,
+    /// Columns the expression reads, checked against the input schema.
+    pub(crate) columns: Vec<String>,
+}
+
+impl Default for RevenueExpr {
+    fn default() -> Self {
+        Self {
+            sql: "quantity * price".to_string(),
+            columns: vec!["quantity".to_string(), "price".to_string()],
+        }
+    }
+}
+
+/// The view a `--sql-file` has to create.
+pub(crate) const USER_TRANSFORM_VIEW: &str = "user_transformed";
+
+/// A `--sql-file`, read when the option is parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlFile {
+    pub path: PathBuf,
+    pub(crate) sql: String,
+}
+
+pub fn parse_sql_file(value: &str) -> std::result::Result<SqlFile, String> {
+    let path = PathBuf::from(value);
+    let sql = std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", value, e))?;
+    if !sql.contains(USER_TRANSFORM_VIEW) {
+        return Err(format!(
+            "{} never mentions {}; it must create that view from cleaned_data",
+            value, USER_TRANSFORM_VIEW
+        ));
+    }
+    Ok(SqlFile { path, sql })
+}
+
+pub fn parse_revenue_expr(value: &str) -> std::result::Result<RevenueExpr, String> {
+    let chars: Vec<char> = value.chars().collect();
+    let mut columns: Vec<String> = Vec::new();
+    let mut depth = 0i32;
+    // Two operands in a row (`SELECT 1`, `price AS x`) mean keywords are in play.
+    let mut after_operand = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let starts_operand = c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        if starts_operand && after_operand {
+            return Err(format!(
+                "expected an operator before '{}'",
+                chars[i..]
+                    .iter()
+                    .take_while(|c| !c.is_whitespace())
+                    .collect::<String>()
+            ));
+        }
+        if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            after_operand = true;
+            continue;
+        }
+        if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let rest = chars[i..].iter().find(|c| !c.is_whitespace());
+            if rest == Some(&'(') {
+                if !REVENUE_FUNCTIONS.contains(&word.to_lowercase().as_str()) {
+                    return Err(format!(
+                        "function '{}' is not allowed (allowed: {})",
+                        word,
+                        REVENUE_FUNCTIONS.join(", ")
+                    ));
+                }
+            } else {
+                if !columns.contains(&word) {
+                    columns.push(word);
+                }
+                after_operand = true;
+            }
+            continue;
+        }
+        match c {
+            '+' | '-' | '*' | '/' | '%' | ',' => after_operand = false,
+            c if c.is_whitespace() => {}
+            '(' => {
+                depth += 1;
+                after_operand = false;
+            }
+            ')' => {
+                depth -= 1;
+                if depth < 0 {
+                    return Err("unbalanced parentheses".to_string());
+                }
+                after_operand = true;
+            }
+            other => {
+                return Err(format!(
+                    "unexpected '{}'; only column names, numbers, arithmetic and {} are allowed",
+                    other,
+                    REVENUE_FUNCTIONS.join(", ")
+                ))
+            }
+        }
+        i += 1;
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_string());
+    }
+    if columns.is_empty() {
+        return Err("the expression must reference at least one column".to_string());
+    }
+    Ok(RevenueExpr {
+        sql: value.trim().to_string(),
+        columns,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_path_gets_default_extension() {
+        assert_eq!(
+            resolve_output_path("results/out", OutputFormat::Parquet),
+            PathBuf::from("results/out.parquet")
+        );
+        assert_eq!(
+            resolve_output_path("results/out.txt", OutputFormat::Json),
+            PathBuf::from("results/out.txt")
+        );
+    }
+
+    #[test]
+    fn memory_limit_requires_size_unit() {
+        assert_eq!(parse_memory_limit("4GB").unwrap(), "4GB");
+        assert_eq!(parse_memory_limit("1.5GiB").unwrap(), "1.5GiB");
+        assert!(parse_memory_limit("512").is_err());
+        assert!(parse_memory_limit("GB").is_err());
+        assert_eq!(memory_limit_bytes("1.5GiB"), Some(1.5 * 1024f64.powi(3)));
+        assert_eq!(memory_limit_bytes("512 MB"), Some(512e6));
+        assert!(parse_memory_limit("4GB'; DROP").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! `--expect`: a data contract the aggregated output is checked against
+//! before it's written, so a scheduled run fails rather than publishing
+//! results that are off.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+use log::info;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::failure::{failure, FailureKind};
+use crate::sql::build_expectations_sql;
+
+/// The constraints in an `--expect` file, TOML or, for a `.json` file, JSON:
+///
+/// ```toml
+/// min-rows = 2
+/// not-null = ["product_id"]
+/// sum.total_revenue = { min = 1000.0, max = 50000.0 }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Expectations {
+    #[serde(skip)]
+    pub path: PathBuf,
+    pub min_rows: Option<i64>,
+    pub max_rows: Option<i64>,
+    /// Output columns that mustn't hold a NULL.
+    #[serde(default)]
+    pub not_null: Vec<String>,
+    /// The range each output column's sum has to fall in.
+    #[serde(default)]
+    pub sum: BTreeMap<String, ValueRange>,
+}
+
+/// Inclusive bounds; either may be left out.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ValueRange {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Expectations {
+    /// The output columns the expectations name.
+    pub(crate) fn columns(&self) -> impl Iterator<Item = &String> {
+        self.not_null.iter().chain(self.sum.keys())
+    }
+
+    fn count(&self) -> usize {
+        usize::from(self.min_rows.is_some())
+            + usize::from(self.max_rows.is_some())
+            + self.not_null.len()
+            + self.sum.len()
+    }
+}
+
+pub fn parse_expectations(value: &str) -> std::result::Result<Expectations, String> {
+    let path = PathBuf::from(value);
+    let text =
+        std::fs::read_to_string(&path).map_err(|e| format!("can't read {}: {}", value, e))?;
+    let parsed = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str::<Expectations>(&text).map_err(|e| e.to_string())
+    } else {
+        toml::from_str::<Expectations>(&text).map_err(|e| e.to_string())
+    };
+    let expectations =
+        parsed.map_err(|e| format!("{} isn't a valid expectations file: {}", value, e))?;
+    let rows = (
+        "rows".to_string(),
+        expectations.min_rows.map(|n| n as f64),
+        expectations.max_rows.map(|n| n as f64),
+    );
+    let sums = expectations
+        .sum
+        .iter()
+        .map(|(column, range)| (format!("sum.{}", column), range.min, range.max));
+    let inverted = std::iter::once(rows)
+        .chain(sums)
+        .find(|(_, min, max)| matches!((min, max), (Some(min), Some(max)) if min > max));
+    if let Some((name, _, _)) = inverted {
+        return Err(format!(
+            "{} has a minimum above the maximum for {}",
+            value, name
+        ));
+    }
+    Ok(Expectations {
+        path,
+        ..expectations
+    })
+}
+
+/// Checks `source` against the expectations, listing every one it misses.
+/// `rows` leaves out a trailing `--with-totals` row, which isn't a group
+/// and would double the sums.
+pub(crate) fn check_expectations(
+    conn: &Connection,
+    expectations: &Expectations,
+    source: &str,
+    rows: i64,
+) -> Result<()> {
+    let sql = build_expectations_sql(expectations, source, rows);
+    let not_null = expectations.not_null.len();
+    let (count, nulls, sums): (i64, Vec<i64>, Vec<f64>) = conn
+        .query_row(&sql, [], |row| {
+            Ok((
+                row.get(0)?,
+                (1..=not_null)
+                    .map(|i| row.get(i))
+                    .collect::<Result<_, _>>()?,
+                (0..expectations.sum.len())
+                    .map(|i| row.get(1 + not_null + i))
+                    .collect::<Result<_, _>>()?,
+            ))
+        })
+        .context("Failed to check the --expect constraints")?;
+
+    let mut missed = Vec::new();
+    if let Some(min) = expectations.min_rows.filter(|&min| count < min) {
+        missed.push(format!(
+            "{} output rows, fewer than min-rows {}",
+            count, min
+        ));
+    }
+    if let Some(max) = expectations.max_rows.filter(|&max| count > max) {
+        missed.push(format!("{} output rows, more than max-rows {}", count, max));
+    }
+    for (column, nulls) in expectations.not_null.iter().zip(nulls) {
+        if nulls > 0 {
+            missed.push(format!("{} rows with a NULL {}", nulls, column));
+        }
+    }
+    for ((column, range), sum) in expectations.sum.iter().zip(sums) {
+        if let Some(min) = range.min.filter(|&min| sum < min) {
+            missed.push(format!("sum of {} is {}, below {}", column, sum, min));
+        }
+        if let Some(max) = range.max.filter(|&max| sum > max) {
+            missed.push(format!("sum of {} is {}, above {}", column, sum, max));
+        }
+    }
+    if !missed.is_empty() {
+        return Err(failure(
+            FailureKind::QualityGate,
+            format!(
+                "Output doesn't meet {}: {}",
+                expectations.path.display(),
+                missed.join("; ")
+            ),
+        ));
+    }
+    info!(
+        "Output meets the {} expectation(s) in {}",
+        expectations.count(),
+        expectations.path.display()
+    );
+    Ok(())
+}
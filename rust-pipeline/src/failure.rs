@@ -0,0 +1,49 @@
+//! Failure categories attached to pipeline errors, so the binary can exit
+//! with a distinct code for each.
+
+use std::fmt;
+
+/// Why a run failed, for the cases a wrapper script may want to tell apart.
+/// Any other error is a generic failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    /// The data directory or its input files are missing or unreadable.
+    Input,
+    /// The input doesn't have the columns the run needs.
+    Schema,
+    /// `--fail-on-removed-pct` tripped.
+    QualityGate,
+}
+
+/// An error message tagged with its `FailureKind`; shown as just the message.
+#[derive(Debug)]
+pub struct PipelineFailure {
+    pub kind: FailureKind,
+    message: String,
+}
+
+impl fmt::Display for PipelineFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for PipelineFailure {}
+
+/// `message` as an `anyhow::Error` of the given kind, for `return Err(..)`
+/// where a plain `bail!` would lose the category.
+pub(crate) fn failure(kind: FailureKind, message: impl Into<String>) -> anyhow::Error {
+    PipelineFailure {
+        kind,
+        message: message.into(),
+    }
+    .into()
+}
+
+/// The kind of the first tagged error in `err`'s chain, looking through any
+/// context added on the way up.
+pub fn failure_kind(err: &anyhow::Error) -> Option<FailureKind> {
+    err.chain()
+        .find_map(|e| e.downcast_ref::<PipelineFailure>())
+        .map(|f| f.kind)
+}
@@ -0,0 +1,111 @@
+//! `--histogram`: equal-width buckets of one numeric column of the cleaned
+//! rows, printed as a bar chart.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+
+use crate::config::HistogramSpec;
+use crate::sql::quote_ident;
+use crate::term::{rule, title};
+
+/// One bucket, `lower` inclusive and `upper` exclusive except for the last.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramBucket {
+    pub lower: f64,
+    pub upper: f64,
+    pub count: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub column: String,
+    /// Empty when the column has no non-NULL values.
+    pub buckets: Vec<HistogramBucket>,
+}
+
+/// Widest bar, for the bucket with the most values.
+const BAR_WIDTH: usize = 40;
+
+/// Every bucket is listed, empty ones included; a column holding a single
+/// value puts it all in the first.
+pub(crate) fn histogram_view(
+    conn: &Connection,
+    view: &str,
+    spec: &HistogramSpec,
+) -> Result<Histogram> {
+    let column = quote_ident(&spec.column);
+    let n = spec.buckets;
+    let sql = format!(
+        "WITH bounds AS (
+             SELECT MIN({column})::DOUBLE AS lo, MAX({column})::DOUBLE AS hi FROM {view}
+         ),
+         values AS (
+             SELECT LEAST(
+                 CAST(FLOOR(COALESCE(({column} - lo) / NULLIF(hi - lo, 0), 0) * {n}) AS BIGINT),
+                 {last}
+             ) AS bucket
+             FROM {view}, bounds
+             WHERE {column} IS NOT NULL
+         )
+         SELECT lo + s.bucket * (hi - lo) / {n}, lo + (s.bucket + 1) * (hi - lo) / {n},
+                COUNT(values.bucket)
+         FROM bounds, range({n}) s(bucket)
+         LEFT JOIN values ON values.bucket = s.bucket
+         WHERE lo IS NOT NULL
+         GROUP BY s.bucket, lo, hi
+         ORDER BY s.bucket",
+        last = n - 1
+    );
+    let buckets = conn
+        .prepare(&sql)
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(HistogramBucket {
+                    lower: row.get(0)?,
+                    upper: row.get(1)?,
+                    count: row.get(2)?,
+                })
+            })?
+            .collect::<std::result::Result<_, _>>()
+        })
+        .with_context(|| format!("Failed to compute the --histogram of {}", spec.column))?;
+    Ok(Histogram {
+        column: spec.column.clone(),
+        buckets,
+    })
+}
+
+pub fn print_histogram(histogram: &Histogram) {
+    println!("\n{}", rule(78));
+    println!(
+        "{}",
+        title(format!(
+            "Distribution of {} (cleaned rows)",
+            histogram.column
+        ))
+    );
+    println!("{}", rule(78));
+    if histogram.buckets.is_empty() {
+        println!("No values");
+    } else {
+        let most = histogram.buckets.iter().map(|b| b.count).max().unwrap_or(0);
+        println!("{:>12} {:>12} {:>10}", "from", "to", "count");
+        for bucket in &histogram.buckets {
+            let width = if most > 0 {
+                (bucket.count as f64 / most as f64 * BAR_WIDTH as f64).round() as usize
+            } else {
+                0
+            };
+            let line = format!(
+                "{:>12.2} {:>12.2} {:>10}  {}",
+                bucket.lower,
+                bucket.upper,
+                bucket.count,
+                "█".repeat(width)
+            );
+            println!("{}", line.trim_end());
+        }
+    }
+    println!("{}", rule(78));
+    println!();
+}
@@ -0,0 +1,361 @@
+//! Input discovery: which files to read and with which DuckDB reader.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+use std::io::Read;
+use std::path::Path;
+
+use crate::config::{ColumnType, InputFormat, PipelineConfig};
+use crate::failure::{failure, FailureKind};
+use crate::s3::is_s3;
+use crate::sql::{quote_ident, sql_string};
+
+/// The files a run reads: a concrete format and one glob per extension present.
+#[derive(Debug)]
+pub(crate) struct InputSource {
+    pub(crate) format: InputFormat,
+    pub(crate) patterns: Vec<String>,
+    pub(crate) file_count: usize,
+    /// The files the globs matched, for `--cache` to compare against.
+    pub(crate) files: Vec<String>,
+    /// `delim` and `quote` arguments for every `read_csv_auto` call.
+    pub(crate) csv_dialect: Vec<String>,
+    /// Extra named arguments for the typed `read_csv_auto`, e.g. `types={...}`.
+    pub(crate) csv_options: Vec<String>,
+}
+
+impl InputSource {
+    /// The globs as a SQL string, or a list literal when there are several.
+    pub(crate) fn source_sql(&self) -> String {
+        if self.patterns.len() == 1 {
+            sql_string(&self.patterns[0])
+        } else {
+            let quoted: Vec<String> = self.patterns.iter().map(|p| sql_string(p)).collect();
+            format!("[{}]", quoted.join(", "))
+        }
+    }
+
+    /// DuckDB table function call reading every input file.
+    pub(crate) fn reader_sql(&self) -> String {
+        self.reader_over(self.source_sql())
+    }
+
+    /// The same reader over `source`, a glob, a list of them or one file.
+    fn reader_over(&self, source: String) -> String {
+        match self.format {
+            InputFormat::Csv | InputFormat::Auto => {
+                let mut args = vec![source, "ignore_errors=true".to_string()];
+                args.extend(self.csv_dialect.iter().cloned());
+                args.extend(self.csv_options.iter().cloned());
+                format!("read_csv_auto({})", args.join(", "))
+            }
+            InputFormat::Parquet => format!("read_parquet({})", source),
+            InputFormat::Json => format!("read_json_auto({})", source),
+        }
+    }
+
+    /// Reads the CSVs with every column as text, before any type coercion.
+    pub(crate) fn text_reader_sql(&self) -> String {
+        let mut args = vec![self.source_sql(), "ignore_errors=true".to_string()];
+        args.extend(self.csv_dialect.iter().cloned());
+        args.push("all_varchar=true".to_string());
+        format!("read_csv_auto({})", args.join(", "))
+    }
+}
+
+/// Copies stdin into a file in a fresh temporary directory to use as the
+/// data directory. A pipe can only be read once, while the CSV sniffer and
+/// repeated `--runs` each need to read the input again.
+pub fn spool_stdin(input_format: InputFormat) -> Result<tempfile::TempDir> {
+    let dir = tempfile::tempdir().context("Failed to create a directory for stdin")?;
+    let extension = match input_format {
+        InputFormat::Auto => "csv",
+        format => format.extensions()[0],
+    };
+    let path = dir.path().join(format!("stdin.{}", extension));
+    let mut file = std::fs::File::create(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut file).context("Failed to read stdin")?;
+    Ok(dir)
+}
+
+/// Counts, per overridden column, the non-empty values that don't cast to
+/// the requested type. With `ignore_errors` DuckDB loads those values as
+/// NULL, so without this they'd vanish silently in the cleaning step.
+pub(crate) fn count_cast_failures(
+    conn: &Connection,
+    input: &InputSource,
+    column_types: &[ColumnType],
+) -> Result<Vec<i64>> {
+    if column_types.is_empty() {
+        return Ok(Vec::new());
+    }
+    let filters: Vec<String> = column_types
+        .iter()
+        .map(|t| {
+            // `sql_type` is limited to NAME or NAME(p,s) by parse_column_types
+            let column = quote_ident(&t.column);
+            format!(
+                "COUNT(*) FILTER (WHERE {0} IS NOT NULL AND TRY_CAST({0} AS {1}) IS NULL)",
+                column, t.sql_type
+            )
+        })
+        .collect();
+    let sql = format!(
+        "SELECT {} FROM {}",
+        filters.join(", "),
+        input.text_reader_sql()
+    );
+    conn.query_row(&sql, [], |row| {
+        (0..column_types.len()).map(|i| row.get(i)).collect()
+    })
+    .context("Failed to count values failing the column type overrides")
+}
+
+/// Reads every input file on its own to find the ones a failed load choked
+/// on: files that don't parse, with the first line of their error, and files
+/// whose columns differ from the first file's, which a multi-file read can't
+/// combine even though each file is fine alone.
+pub(crate) fn find_failing_files(
+    conn: &Connection,
+    input: &InputSource,
+) -> Result<Vec<(String, String)>> {
+    let first_line = |e: duckdb::Error| {
+        let message = e.to_string();
+        message.lines().next().unwrap_or_default().to_string()
+    };
+    let mut stmt = conn.prepare("SELECT file FROM glob(?)")?;
+    let mut expected: Option<Vec<String>> = None;
+    let mut failing = Vec::new();
+    for pattern in &input.patterns {
+        let files: Vec<String> = stmt
+            .query_map([pattern], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        for file in files {
+            let reader = input.reader_over(sql_string(&file));
+            let columns = conn
+                .prepare(&format!("DESCRIBE SELECT * FROM {}", reader))
+                .and_then(|mut describe| {
+                    describe
+                        .query_map([], |row| row.get::<_, String>(0))?
+                        .collect::<duckdb::Result<Vec<String>>>()
+                });
+            let columns = match columns {
+                Ok(columns) => columns,
+                Err(e) => {
+                    failing.push((file, first_line(e)));
+                    continue;
+                }
+            };
+            match &expected {
+                Some(expected) if *expected != columns => {
+                    failing.push((
+                        file,
+                        format!(
+                            "columns {} instead of {}",
+                            columns.join(", "),
+                            expected.join(", ")
+                        ),
+                    ));
+                    continue;
+                }
+                Some(_) => {}
+                None => expected = Some(columns),
+            }
+            let count = format!("SELECT COUNT(*) FROM {}", reader);
+            if let Err(e) = conn.query_row(&count, [], |row| row.get::<_, i64>(0)) {
+                failing.push((file, first_line(e)));
+            }
+        }
+    }
+    Ok(failing)
+}
+
+/// The encoding a UTF-16 or UTF-32 byte order mark at the start of `path`
+/// announces. DuckDB only reads UTF-8, whose BOM it strips itself.
+fn non_utf8_bom(path: &str) -> Option<&'static str> {
+    let mut head = [0u8; 4];
+    let read = std::fs::File::open(path)
+        .and_then(|mut file| file.read(&mut head))
+        .ok()?;
+    match &head[..read] {
+        [0xFF, 0xFE, 0, 0] | [0, 0, 0xFE, 0xFF] => Some("UTF-32"),
+        [0xFF, 0xFE, ..] | [0xFE, 0xFF, ..] => Some("UTF-16"),
+        _ => None,
+    }
+}
+
+/// Lists the data directories and resolves which reader and globs to use.
+/// With `--files` each file stands in for a directory listing only itself.
+pub(crate) fn discover_input(conn: &Connection, config: &PipelineConfig) -> Result<InputSource> {
+    let dirs = config.data_dirs();
+    let explicit = !config.files.is_empty();
+    let mut stmt = conn.prepare("SELECT file FROM glob(?)")?;
+    // Listed per directory, so each directory only gets globs that match
+    let mut files: Vec<Vec<String>> = Vec::new();
+    for dir in &dirs {
+        if explicit {
+            if !is_s3(dir) && !Path::new(dir).is_file() {
+                return Err(failure(
+                    FailureKind::Input,
+                    format!("Input file {} doesn't exist", dir),
+                ));
+            }
+            files.push(vec![dir.to_string()]);
+            continue;
+        }
+        if !is_s3(dir) && !Path::new(dir).is_dir() {
+            return Err(failure(
+                FailureKind::Input,
+                format!("Data directory {} doesn't exist", dir),
+            ));
+        }
+        files.push(
+            stmt.query_map([config.discovery_pattern(dir)], |row| row.get(0))?
+                .collect::<std::result::Result<_, _>>()?,
+        );
+    }
+
+    let count_in = |dir_files: &[String], ext: &str| {
+        let suffix = format!(".{}", ext);
+        dir_files.iter().filter(|f| f.ends_with(&suffix)).count()
+    };
+    let count_with = |ext: &str| files.iter().map(|f| count_in(f, ext)).sum::<usize>();
+    let where_searched = if config.recursive {
+        " (searched recursively)"
+    } else {
+        ""
+    };
+
+    let format = match config.input_format {
+        InputFormat::Auto => {
+            let present: Vec<InputFormat> = InputFormat::CONCRETE
+                .into_iter()
+                .filter(|f| f.extensions().iter().any(|ext| count_with(ext) > 0))
+                .collect();
+            match present.as_slice() {
+                [] => {
+                    return Err(failure(
+                        FailureKind::Input,
+                        format!(
+                            "No CSV, Parquet or JSON files found in {}{}",
+                            config.data_dir_description(),
+                            where_searched
+                        ),
+                    ))
+                }
+                [format] => *format,
+                _ => {
+                    let names: Vec<String> = present.iter().map(|f| f.to_string()).collect();
+                    anyhow::bail!(
+                        "{} mixes {} files; pass --input-format to choose one",
+                        config.data_dir_description(),
+                        names.join(" and ")
+                    )
+                }
+            }
+        }
+        format => format,
+    };
+
+    let mut patterns = Vec::new();
+    let mut file_count = 0;
+    let mut matched = Vec::new();
+    for ext in format.extensions() {
+        let suffix = format!(".{}", ext);
+        for (dir, dir_files) in dirs.iter().zip(&files) {
+            matched.extend(dir_files.iter().filter(|f| f.ends_with(&suffix)).cloned());
+            let count = count_in(dir_files, ext);
+            if count > 0 && explicit {
+                patterns.push(dir.to_string());
+                file_count += count;
+            } else if count > 0 {
+                patterns.push(config.input_pattern(dir, ext));
+                file_count += count;
+            }
+        }
+    }
+    // DuckDB would read a UTF-16 header as garbled column names, which only
+    // surfaces later as missing columns
+    if format != InputFormat::Parquet {
+        let local = dirs.iter().zip(&files).filter(|(dir, _)| !is_s3(dir));
+        for file in local.flat_map(|(_, dir_files)| dir_files) {
+            let read = format
+                .extensions()
+                .iter()
+                .any(|ext| file.ends_with(&format!(".{}", ext)));
+            if let Some(encoding) = non_utf8_bom(file).filter(|_| read) {
+                return Err(failure(
+                    FailureKind::Input,
+                    format!(
+                        "{} is {} encoded; convert it to UTF-8 first, e.g. with iconv -f {} -t UTF-8",
+                        file, encoding, encoding
+                    ),
+                ));
+            }
+        }
+    }
+    // An empty match would otherwise surface as an opaque DuckDB binder error
+    if file_count == 0 {
+        return Err(failure(
+            FailureKind::Input,
+            format!(
+                "No {} files found in {}{}",
+                format,
+                config.data_dir_description(),
+                where_searched
+            ),
+        ));
+    }
+
+    if format != InputFormat::Csv {
+        let csv_only = [
+            ("--column-types", !config.column_types.is_empty()),
+            ("--delimiter", config.csv_delimiter.is_some()),
+            ("--quote", config.csv_quote.is_some()),
+        ];
+        if let Some((option, _)) = csv_only.iter().find(|(_, set)| *set) {
+            anyhow::bail!("{} only applies to CSV input, not {}", option, format);
+        }
+    }
+    if format != InputFormat::Json && config.json_unnest.is_some() {
+        anyhow::bail!("--json-unnest only applies to JSON input, not {}", format);
+    }
+    let mut csv_dialect = Vec::new();
+    if let Some(delimiter) = config.csv_delimiter {
+        csv_dialect.push(format!("delim={}", sql_string(&delimiter.to_string())));
+    }
+    if let Some(quote) = config.csv_quote {
+        csv_dialect.push(format!("quote={}", sql_string(&quote.to_string())));
+    }
+    let mut types: Vec<(&str, &str)> = config
+        .column_types
+        .iter()
+        .map(|t| (t.column.as_str(), t.sql_type.as_str()))
+        .collect();
+    // Keep the sniffer from guessing a different date format than --date-format
+    let date_column = config.source_column(&config.date_column);
+    if format == InputFormat::Csv
+        && !config.date_formats.is_empty()
+        && !types.iter().any(|(column, _)| *column == date_column)
+    {
+        types.push((date_column, "VARCHAR"));
+    }
+    let mut csv_options = Vec::new();
+    if !types.is_empty() {
+        let entries: Vec<String> = types
+            .iter()
+            .map(|(column, sql_type)| format!("{}: {}", sql_string(column), sql_string(sql_type)))
+            .collect();
+        csv_options.push(format!("types={{{}}}", entries.join(", ")));
+    }
+
+    Ok(InputSource {
+        format,
+        patterns,
+        file_count,
+        files: matched,
+        csv_dialect,
+        csv_options,
+    })
+}
@@ -0,0 +1,66 @@
+//! Rust + DuckDB sales pipeline: load, clean, transform, aggregate and save,
+//! with timing and resource metrics for benchmarking.
+//!
+//! `run_pipeline` is the entry point; the `rust-pipeline` binary is a thin
+//! command-line wrapper around it, `generate_data` and `run_bench`.
+
+mod affinity;
+mod bench;
+mod cache;
+mod checksum;
+mod compare;
+mod config;
+mod expect;
+mod failure;
+mod formatted;
+mod generate;
+mod histogram;
+mod input;
+mod metrics;
+mod pipeline;
+mod profile;
+mod push;
+mod s3;
+mod sql;
+mod stats;
+mod stream;
+mod term;
+mod watermark;
+
+pub use affinity::pin_to_cpus;
+pub use bench::{
+    print_bench, run_bench, write_bench_json, BenchOptions, BenchReport, PipelineTimings,
+};
+pub use checksum::FileChecksum;
+pub use compare::{
+    check_baseline, compare_reports, compare_to_baseline, print_comparison, run_compare,
+    MetricDelta,
+};
+pub use config::{
+    default_aggregations, default_rules, default_time_dims, parse_aggregation, parse_column_types,
+    parse_cpu_set, parse_csv_char, parse_data_dir, parse_fraction, parse_histogram,
+    parse_json_unnest, parse_memory_limit, parse_output_destination, parse_percentage,
+    parse_positive_number, parse_quantile, parse_rename, parse_revenue_expr, parse_rule,
+    parse_sample_fraction, parse_since, parse_sql_file, parse_table_destination,
+    resolve_output_path, split_data_dirs, AggregateFunction, Aggregation, ColumnRename, ColumnType,
+    ColumnTypes, CpuSet, Dedup, ExplainMode, HistogramSpec, InputFormat, JsonUnnest, NullStrategy,
+    OutputDestination, OutputFormat, PipelineConfig, Predicate, RevenueExpr, Since, SortOrder,
+    SqlFile, TableDestination, TimeDim, ValidationRule,
+};
+pub use expect::{parse_expectations, Expectations, ValueRange};
+pub use failure::{failure_kind, FailureKind, PipelineFailure};
+pub use generate::{generate_data, print_generated, GenerateOptions, GeneratedData};
+pub use histogram::{print_histogram, Histogram, HistogramBucket};
+pub use input::spool_stdin;
+pub use metrics::{
+    read_metrics_json, write_metrics_json, CastFailure, CurrencyRows, DateFormatMatch, Degradation,
+    DumpedFile, ImputedValues, MetricsReport, PartialMetrics, PipelineMetrics, QuarterRevenue,
+    RowCounts, RuleRemoval, SharedPartialMetrics, StepTiming, UnnestedJson, WrittenOutput,
+    WrittenTable, MEMORY_SAMPLE_INTERVAL,
+};
+pub use pipeline::{dry_run_statements, explain_schema, run_pipeline, PipelineResult, QueryPlan};
+pub use profile::{print_profile, print_schema, ColumnProfile, ColumnSchema};
+pub use push::{format_metrics, push_metrics};
+pub use stats::{print_run_statistics, Stats};
+pub use term::summary_rule;
+pub use watermark::read_watermark;
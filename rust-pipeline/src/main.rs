@@ -1,192 +1,1527 @@
-use anyhow::{Context, Result};
-use duckdb::{Connection, params};
-use std::time::Instant;
-use sysinfo::{System, SystemExt, ProcessExt};
-use std::path::Path;
-use chrono::Local;
-
-struct PipelineMetrics {
-    start_time: Instant,
-    peak_memory_mb: f64,
-    system: System,
-}
-
-impl PipelineMetrics {
-    fn new() -> Self {
-        Self {
-            start_time: Instant::now(),
-            peak_memory_mb: 0.0,
-            system: System::new_all(),
-        }
-    }
-
-    fn update_memory(&mut self) {
-        self.system.refresh_all();
-        if let Some(process) = self.system.process(sysinfo::get_current_pid().unwrap()) {
-            let memory_mb = process.memory() as f64 / 1024.0 / 1024.0;
-            if memory_mb > self.peak_memory_mb {
-                self.peak_memory_mb = memory_mb;
-            }
-        }
-    }
-
-    fn print_summary(&self) {
-        let duration = self.start_time.elapsed();
-        let duration_secs = duration.as_secs_f64();
-        
-        println!("\n{}", "=".repeat(60));
-        println!("Pipeline Execution Summary (Rust + DuckDB)");
-        println!("{}", "=".repeat(60));
-        println!("Duration: {:.2} seconds ({:.2} minutes)", duration_secs, duration_secs / 60.0);
-        println!("Peak Memory: {:.2} MB ({:.2} GB)", self.peak_memory_mb, self.peak_memory_mb / 1024.0);
-        println!("{}", "=".repeat(60));
-        println!();
-    }
-}
-
-fn run_pipeline(data_dir: &str, output_path: &str) -> Result<()> {
-    let mut metrics = PipelineMetrics::new();
-    
-    println!("\n{}", "=".repeat(60));
-    println!("Starting Rust + DuckDB Pipeline");
-    println!("Timestamp: {}", Local::now().format("%Y-%m-%d %H:%M:%S"));
-    println!("{}", "=".repeat(60));
-    println!();
-
-    // Connect to DuckDB (in-memory)
-    println!("Initializing DuckDB...");
-    let conn = Connection::open_in_memory()
-        .context("Failed to create DuckDB connection")?;
-    
-    metrics.update_memory();
-
-    // Step 1: Load CSV files
-    println!("\nLoading CSV files from {}...", data_dir);
-    let csv_pattern = format!("{}/*.csv", data_dir);
-    
-    conn.execute(
-        "CREATE VIEW raw_data AS SELECT * FROM read_csv_auto(?, ignore_errors=true)",
-        params![csv_pattern],
-    ).context("Failed to load CSV files")?;
-    
-    metrics.update_memory();
-
-    // Count total rows
-    let row_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM raw_data",
-        [],
-        |row| row.get(0),
-    )?;
-    println!("Total rows loaded: {}", row_count);
-
-    // Step 2: Clean data
-    println!("\nCleaning data...");
-    conn.execute(
-        "CREATE VIEW cleaned_data AS 
-         SELECT * FROM raw_data 
-         WHERE product_id IS NOT NULL 
-           AND quantity > 0 
-           AND price > 0
-           AND TRY_CAST(date AS DATE) IS NOT NULL",
-        [],
-    ).context("Failed to clean data")?;
-    
-    let cleaned_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM cleaned_data",
-        [],
-        |row| row.get(0),
-    )?;
-    
-    let removed = row_count - cleaned_count;
-    let removed_pct = (removed as f64 / row_count as f64) * 100.0;
-    println!("Removed {} invalid rows ({:.2}%)", removed, removed_pct);
-    println!("Remaining rows: {}", cleaned_count);
-    
-    metrics.update_memory();
-
-    // Step 3: Transform data
-    println!("\nTransforming data...");
-    conn.execute(
-        "CREATE VIEW transformed_data AS 
-         SELECT 
-             *,
-             quantity * price AS revenue,
-             EXTRACT(YEAR FROM CAST(date AS DATE)) AS year,
-             EXTRACT(MONTH FROM CAST(date AS DATE)) AS month,
-             EXTRACT(QUARTER FROM CAST(date AS DATE)) AS quarter
-         FROM cleaned_data",
-        [],
-    ).context("Failed to transform data")?;
-    
-    println!("Transformations complete");
-    metrics.update_memory();
-
-    // Step 4: Aggregate data
-    println!("\nAggregating data...");
-    conn.execute(
-        "CREATE VIEW aggregated_data AS 
-         SELECT 
-             product_id,
-             SUM(quantity) AS total_quantity,
-             SUM(revenue) AS total_revenue,
-             AVG(price) AS avg_price
-         FROM transformed_data
-         GROUP BY product_id
-         ORDER BY total_revenue DESC",
-        [],
-    ).context("Failed to aggregate data")?;
-    
-    let agg_count: i64 = conn.query_row(
-        "SELECT COUNT(*) FROM aggregated_data",
-        [],
-        |row| row.get(0),
-    )?;
-    println!("Aggregated to {} products", agg_count);
-    
-    metrics.update_memory();
-
-    // Step 5: Save results
-    println!("\nSaving results to {}...", output_path);
-    
-    // Create output directory if it doesn't exist
-    if let Some(parent) = Path::new(output_path).parent() {
-        std::fs::create_dir_all(parent)
-            .context("Failed to create output directory")?;
-    }
-    
-    conn.execute(
-        &format!("COPY aggregated_data TO '{}' (HEADER, DELIMITER ',')", output_path),
-        [],
-    ).context("Failed to save results")?;
-    
-    let file_size = std::fs::metadata(output_path)?.len() as f64 / 1024.0 / 1024.0;
-    println!("Results saved ({:.2} MB)", file_size);
-    
-    metrics.update_memory();
-    metrics.print_summary();
-
-    Ok(())
+use anyhow::Context;
+use chrono::NaiveDate;
+use clap::builder::BoolishValueParser;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use log::{error, info, warn};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use rust_pipeline::{
+    check_baseline, default_rules, dry_run_statements, explain_schema, failure_kind, generate_data,
+    parse_aggregation, parse_column_types, parse_cpu_set, parse_csv_char, parse_data_dir,
+    parse_expectations, parse_fraction, parse_histogram, parse_json_unnest, parse_memory_limit,
+    parse_output_destination, parse_percentage, parse_positive_number, parse_quantile,
+    parse_rename, parse_revenue_expr, parse_rule, parse_sample_fraction, parse_since,
+    parse_sql_file, parse_table_destination, pin_to_cpus, print_bench, print_comparison,
+    print_generated, print_histogram, print_profile, print_run_statistics, print_schema,
+    push_metrics, read_watermark, run_bench, run_compare, run_pipeline, split_data_dirs,
+    spool_stdin, summary_rule, write_bench_json, write_metrics_json, Aggregation, BenchOptions,
+    ColumnRename, ColumnTypes, CpuSet, Dedup, Expectations, ExplainMode, FailureKind,
+    GenerateOptions, HistogramSpec, InputFormat, JsonUnnest, MetricsReport, NullStrategy,
+    OutputDestination, OutputFormat, PipelineConfig, PipelineMetrics, RevenueExpr,
+    SharedPartialMetrics, Since, SortOrder, SqlFile, TableDestination, TimeDim, ValidationRule,
+    MEMORY_SAMPLE_INTERVAL,
+};
+
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Rust + DuckDB data pipeline benchmark",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Directory containing the input files, a comma-separated list of them to
+    /// read as one input, or - to read them from stdin. An s3://bucket/prefix
+    /// location or glob such as s3://bucket/prefix/*.csv is read through
+    /// DuckDB's httpfs extension with the AWS_* credentials
+    #[arg(env = "PIPELINE_DATA_DIR", default_value = "data")]
+    data_dir: String,
+
+    /// Output file path; the format's extension is added if it has none
+    #[arg(env = "PIPELINE_OUTPUT", default_value = "results/rust_output")]
+    output_path: String,
+
+    /// Output file format
+    #[arg(long, env = "PIPELINE_FORMAT", value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+
+    /// Output file, with the format taken from its extension; repeat it to
+    /// write the same results to several files. Takes precedence over
+    /// OUTPUT_PATH and --format
+    #[arg(
+        long = "output",
+        env = "PIPELINE_OUTPUTS",
+        value_name = "PATH",
+        value_delimiter = ',',
+        value_parser = parse_output_destination
+    )]
+    outputs: Vec<OutputDestination>,
+
+    /// Write machine-readable run metrics to this JSON file
+    #[arg(long, env = "PIPELINE_METRICS_JSON", value_name = "PATH")]
+    metrics_json: Option<PathBuf>,
+
+    /// Compare the last run's duration and peak memory with this
+    /// --metrics-json file and exit 6 if either grew by more than --tolerance
+    #[arg(long, env = "PIPELINE_BASELINE", value_name = "PATH")]
+    baseline: Option<PathBuf>,
+
+    /// Percentage increase over --baseline that counts as a regression
+    #[arg(
+        long,
+        env = "PIPELINE_TOLERANCE",
+        value_name = "PCT",
+        default_value_t = 10.0,
+        requires = "baseline"
+    )]
+    tolerance: f64,
+
+    /// Overwrite --baseline with this run's metrics instead of checking it
+    #[arg(
+        long,
+        env = "PIPELINE_UPDATE_BASELINE",
+        requires = "baseline",
+        value_parser = BoolishValueParser::new()
+    )]
+    update_baseline: bool,
+
+    /// Print nothing on stdout but the run's metrics report, as written by
+    /// --metrics-json, or a JSON error object on failure; logs stay on stderr
+    #[arg(
+        long,
+        env = "PIPELINE_JSON_ONLY",
+        value_parser = BoolishValueParser::new(),
+        conflicts_with = "dry_run"
+    )]
+    json_only: bool,
+
+    /// Colour in the summary tables; auto colours a terminal unless NO_COLOR
+    /// is set
+    #[arg(long, env = "PIPELINE_COLOR", value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Highlight the summary's peak memory when it's above MB
+    #[arg(
+        long,
+        env = "PIPELINE_MEMORY_WARN_MB",
+        value_name = "MB",
+        value_parser = parse_positive_number
+    )]
+    memory_warn_mb: Option<f64>,
+
+    /// Also discover input files in subdirectories of DATA_DIR
+    #[arg(long, env = "PIPELINE_RECURSIVE", value_parser = BoolishValueParser::new())]
+    recursive: bool,
+
+    /// Read exactly these input files, comma-separated, instead of the files
+    /// in DATA_DIR
+    #[arg(
+        long,
+        env = "PIPELINE_FILES",
+        value_name = "FILE",
+        value_delimiter = ',',
+        conflicts_with = "data_dir"
+    )]
+    files: Vec<String>,
+
+    /// Input file format
+    #[arg(long, env = "PIPELINE_INPUT_FORMAT", value_enum, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+
+    /// Dotted path to an array in each JSON document, e.g. order.items;
+    /// every element becomes an input row, with the structs on the path
+    /// spread into columns next to the element's fields
+    #[arg(
+        long,
+        env = "PIPELINE_JSON_UNNEST",
+        value_name = "PATH",
+        value_parser = parse_json_unnest
+    )]
+    json_unnest: Option<JsonUnnest>,
+
+    /// DuckDB memory limit, e.g. 4GB or 512MB
+    #[arg(
+        long,
+        env = "PIPELINE_MEMORY_LIMIT",
+        value_name = "SIZE",
+        value_parser = parse_memory_limit
+    )]
+    memory_limit: Option<String>,
+
+    /// Directory for DuckDB's spill files when a query outgrows the memory
+    /// limit, e.g. on a large, fast volume [default: DuckDB's .tmp]
+    #[arg(long, env = "PIPELINE_TEMP_DIR", value_name = "DIR")]
+    temp_dir: Option<PathBuf>,
+
+    /// Number of DuckDB worker threads
+    #[arg(
+        long,
+        env = "PIPELINE_THREADS",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    threads: Option<u32>,
+
+    /// Pin the process to these cores, e.g. 0-3 or 0,2,4-5, for steadier
+    /// timings. Linux only; elsewhere it warns and runs unpinned
+    #[arg(
+        long,
+        env = "PIPELINE_PIN_CPU",
+        value_name = "CORES",
+        value_parser = parse_cpu_set
+    )]
+    pin_cpu: Option<CpuSet>,
+
+    /// After a DuckDB out-of-memory error, rerun the pipeline once with one
+    /// thread and half the memory limit (of 80% of RAM by default), and note
+    /// it in the summary
+    #[arg(long, env = "PIPELINE_AUTO_DEGRADE", value_parser = BoolishValueParser::new())]
+    auto_degrade: bool,
+
+    /// Use a DuckDB database file instead of an in-memory database
+    #[arg(long, env = "PIPELINE_DB_PATH", value_name = "FILE")]
+    db_path: Option<PathBuf>,
+
+    /// Cleaning rule as COLUMN:CHECK (not-null, positive, valid-date);
+    /// repeatable, replaces the default sales-schema rules
+    #[arg(
+        long = "rule",
+        env = "PIPELINE_RULE",
+        value_name = "COLUMN:CHECK",
+        value_parser = parse_rule
+    )]
+    rules: Vec<ValidationRule>,
+
+    /// Number of measured runs; statistics are printed when more than one
+    #[arg(
+        long,
+        env = "PIPELINE_RUNS",
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    runs: u32,
+
+    /// Extra runs before the measured ones whose results are discarded
+    #[arg(long, env = "PIPELINE_WARMUP", value_name = "K", default_value_t = 0)]
+    warmup: u32,
+
+    /// Before timing starts, run a trivial query and a zero-row read of the
+    /// input on the fresh connection, so DuckDB's first-query set-up and the
+    /// first open of the files stay out of the measured duration
+    #[arg(long, env = "PIPELINE_WARMUP_DB", value_parser = BoolishValueParser::new())]
+    warmup_db: bool,
+
+    /// Force CSV column types, e.g. price=DOUBLE,quantity=BIGINT
+    #[arg(
+        long,
+        env = "PIPELINE_COLUMN_TYPES",
+        value_name = "COL=TYPE,...",
+        value_parser = parse_column_types
+    )]
+    column_types: Option<ColumnTypes>,
+
+    /// Write an (empty) output even when no input rows were loaded
+    #[arg(long, env = "PIPELINE_ALLOW_EMPTY", value_parser = BoolishValueParser::new())]
+    allow_empty: bool,
+
+    /// Print the SQL the pipeline would run and exit without processing data
+    #[arg(long, env = "PIPELINE_DRY_RUN", value_parser = BoolishValueParser::new())]
+    dry_run: bool,
+
+    /// Print the input's columns, inferred types and sample values, and exit
+    /// without processing data
+    #[arg(
+        long,
+        env = "PIPELINE_EXPLAIN_SCHEMA",
+        value_parser = BoolishValueParser::new(),
+        conflicts_with_all = ["dry_run", "json_only"]
+    )]
+    explain_schema: bool,
+
+    /// Comma-separated columns to aggregate by
+    #[arg(
+        long,
+        env = "PIPELINE_GROUP_BY",
+        value_name = "COLS",
+        value_delimiter = ',',
+        default_value = "product_id"
+    )]
+    group_by: Vec<String>,
+
+    /// Comma-separated FUNCTION:COLUMN measures per group, FUNCTION being
+    /// sum, avg, min, max, count or median; count:* counts rows. Named like
+    /// total_revenue, avg_price, max_price and row_count
+    #[arg(
+        long,
+        env = "PIPELINE_AGGREGATIONS",
+        value_name = "FUNC:COL,...",
+        value_delimiter = ',',
+        default_value = "sum:quantity,sum:revenue,avg:price",
+        value_parser = parse_aggregation
+    )]
+    aggregations: Vec<Aggregation>,
+
+    /// Comma-separated calendar fields of the date column to add as columns
+    /// for --group-by; the columns use underscores, e.g. day_of_week
+    #[arg(
+        long,
+        env = "PIPELINE_TIME_DIMS",
+        value_enum,
+        value_name = "DIMS",
+        value_delimiter = ',',
+        default_value = "year,month,quarter"
+    )]
+    time_dims: Vec<TimeDim>,
+
+    /// SQL expression for the revenue column, e.g. "quantity * price * (1 - discount)"
+    #[arg(
+        long,
+        env = "PIPELINE_REVENUE_EXPR",
+        value_name = "SQL",
+        value_parser = parse_revenue_expr
+    )]
+    revenue_expr: Option<RevenueExpr>,
+
+    /// Drop duplicate rows after cleaning
+    #[arg(long, env = "PIPELINE_DEDUP", value_parser = BoolishValueParser::new())]
+    dedup: bool,
+
+    /// Comma-separated key columns for --dedup; the first row per key is kept
+    #[arg(
+        long,
+        env = "PIPELINE_DEDUP_ON",
+        value_name = "COLS",
+        value_delimiter = ','
+    )]
+    dedup_on: Vec<String>,
+
+    /// Column holding the order date
+    #[arg(
+        long,
+        env = "PIPELINE_DATE_COLUMN",
+        value_name = "NAME",
+        default_value = "date"
+    )]
+    date_column: String,
+
+    /// strptime format for date values, e.g. %m/%d/%Y; by default dates are cast
+    #[arg(long, env = "PIPELINE_DATE_FORMAT", value_name = "FMT")]
+    date_format: Option<String>,
+
+    /// Comma-separated strptime formats tried in order, for inputs mixing
+    /// date formats; rows matched per format are reported
+    #[arg(
+        long,
+        env = "PIPELINE_DATE_FORMATS",
+        value_name = "FMT,...",
+        value_delimiter = ',',
+        conflicts_with = "date_format"
+    )]
+    date_formats: Vec<String>,
+
+    /// Keep only the first K groups in --order-by order, by default the K
+    /// highest-revenue ones
+    #[arg(
+        long,
+        env = "PIPELINE_TOP_N",
+        value_name = "K",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    top_n: Option<u64>,
+
+    /// Output a row per group and --window-order period instead of per
+    /// group, adding moving_avg_revenue: the group's revenue averaged over
+    /// the last N periods, including this one. Rows are sorted by group,
+    /// then period, so --order-by doesn't apply
+    #[arg(
+        long,
+        env = "PIPELINE_WINDOW",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    window: Option<u32>,
+
+    /// Comma-separated period columns --window orders by, oldest first, e.g.
+    /// year,week with --time-dims
+    #[arg(
+        long,
+        env = "PIPELINE_WINDOW_ORDER",
+        value_name = "COLS",
+        value_delimiter = ',',
+        default_value = "year,month",
+        requires = "window"
+    )]
+    window_order: Vec<String>,
+
+    /// Output revenue per group and year, with prev_year_revenue and yoy_pct,
+    /// the change against the year before (NULL in a group's first year),
+    /// instead of the --aggregations measures. Rows are sorted by group, then
+    /// year, so --order-by doesn't apply
+    #[arg(long, env = "PIPELINE_YOY", value_parser = BoolishValueParser::new())]
+    yoy: bool,
+
+    /// Output column to sort the aggregate by: a --group-by, --aggregations
+    /// or --quantiles column
+    #[arg(
+        long,
+        env = "PIPELINE_ORDER_BY",
+        value_name = "COL",
+        default_value = "total_revenue"
+    )]
+    order_by: String,
+
+    /// Sort direction for --order-by
+    #[arg(long, env = "PIPELINE_ORDER", value_enum, default_value_t = SortOrder::Desc)]
+    order: SortOrder,
+
+    /// Add a grand-total row, with empty group-by columns, after the groups;
+    /// it isn't counted against --top-n
+    #[arg(long, env = "PIPELINE_WITH_TOTALS", value_parser = BoolishValueParser::new())]
+    with_totals: bool,
+
+    /// Only sample memory between steps instead of also every 100ms in the background
+    #[arg(long, env = "PIPELINE_NO_MEMORY_SAMPLER", value_parser = BoolishValueParser::new())]
+    no_memory_sampler: bool,
+
+    /// Milliseconds between the background sampler's memory readings; longer
+    /// intervals cost less but can miss short spikes
+    #[arg(
+        long,
+        env = "PIPELINE_SAMPLE_INTERVAL",
+        value_name = "MS",
+        default_value_t = MEMORY_SAMPLE_INTERVAL.as_millis() as u64,
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "no_memory_sampler"
+    )]
+    sample_interval: u64,
+
+    /// Retries for loading the input after an I/O error, with exponential backoff
+    #[arg(
+        long,
+        env = "PIPELINE_MAX_RETRIES",
+        value_name = "N",
+        default_value_t = 3
+    )]
+    max_retries: u32,
+
+    /// Comma-separated price quantiles to add per group, e.g. 0.25,0.5,0.95,
+    /// as price_p25 etc.; uses DuckDB's approx_quantile for speed
+    #[arg(
+        long,
+        env = "PIPELINE_QUANTILES",
+        value_name = "Q,...",
+        value_delimiter = ',',
+        value_parser = parse_quantile
+    )]
+    quantiles: Vec<f64>,
+
+    /// Write Parquet output as a directory partitioned by this --group-by column
+    #[arg(long, env = "PIPELINE_PARTITION_BY", value_name = "COL")]
+    partition_by: Option<String>,
+
+    /// Order the output by year and quarter, then --order-by, and list each
+    /// quarter's revenue in the summary; --group-by must include year and
+    /// quarter
+    #[arg(long, env = "PIPELINE_QUARTERLY", value_parser = BoolishValueParser::new())]
+    quarterly: bool,
+
+    /// With --quarterly, write the Parquet output as a directory with one
+    /// year=/quarter= partition per quarter
+    #[arg(
+        long,
+        env = "PIPELINE_SPLIT_QUARTERS",
+        value_parser = BoolishValueParser::new(),
+        requires = "quarterly"
+    )]
+    split_quarters: bool,
+
+    /// Print DuckDB's plan for each step's view; --explain=analyze runs the
+    /// queries and adds per-operator timings
+    #[arg(
+        long,
+        env = "PIPELINE_EXPLAIN",
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "plan"
+    )]
+    explain: Option<ExplainMode>,
+
+    /// CSV field delimiter, e.g. ';' or '\t'; detected automatically by default
+    #[arg(long, env = "PIPELINE_DELIMITER", value_name = "CHAR", value_parser = parse_csv_char)]
+    delimiter: Option<char>,
+
+    /// CSV quote character; detected automatically by default
+    #[arg(long, env = "PIPELINE_QUOTE", value_name = "CHAR", value_parser = parse_csv_char)]
+    quote: Option<char>,
+
+    /// Print per-column null, distinct and min/max counts of the raw data
+    #[arg(long, env = "PIPELINE_PROFILE", value_parser = BoolishValueParser::new())]
+    profile: bool,
+
+    /// Print a bar chart of COLUMN's values over the cleaned rows in BUCKETS
+    /// equal-width buckets, e.g. price:10 or revenue:20
+    #[arg(
+        long,
+        env = "PIPELINE_HISTOGRAM",
+        value_name = "COLUMN:BUCKETS",
+        value_parser = parse_histogram
+    )]
+    histogram: Option<HistogramSpec>,
+
+    /// Log the row count of every view (raw, cleaned, transformed,
+    /// aggregated) as it's created. Counting transformed_data costs another
+    /// pass over the input, kept out of the step timings
+    #[arg(long, env = "PIPELINE_VERBOSE", value_parser = BoolishValueParser::new())]
+    verbose: bool,
+
+    /// Field delimiter for CSV output, e.g. '\t' for TSV
+    #[arg(
+        long,
+        env = "PIPELINE_OUTPUT_DELIMITER",
+        value_name = "CHAR",
+        default_value = ",",
+        value_parser = parse_csv_char
+    )]
+    output_delimiter: char,
+
+    /// What to do with rows whose price or quantity is missing or invalid
+    #[arg(long, env = "PIPELINE_NULL_STRATEGY", value_enum, default_value_t = NullStrategy::Drop)]
+    null_strategy: NullStrategy,
+
+    /// Also write cleaned_data and transformed_data as Parquet files to this
+    /// directory for debugging; the extra I/O skews the benchmark
+    #[arg(long, env = "PIPELINE_DUMP_INTERMEDIATE", value_name = "DIR")]
+    dump_intermediate: Option<PathBuf>,
+
+    /// Keep the cleaned data in this Parquet file and, while it's newer than
+    /// the input, load it instead of reading and cleaning the input again
+    #[arg(long, env = "PIPELINE_CACHE", value_name = "PATH")]
+    cache: Option<PathBuf>,
+
+    /// Drop rows whose product ID matches this glob, e.g. 'TEST_*'
+    #[arg(long, env = "PIPELINE_EXCLUDE_PRODUCTS", value_name = "GLOB")]
+    exclude_products: Option<String>,
+
+    /// Drop rows priced above this, e.g. to catch data entry errors
+    #[arg(
+        long,
+        env = "PIPELINE_MAX_PRICE",
+        value_name = "N",
+        value_parser = parse_positive_number
+    )]
+    max_price: Option<f64>,
+
+    /// Drop rows with a quantity above this
+    #[arg(
+        long,
+        env = "PIPELINE_MAX_QUANTITY",
+        value_name = "N",
+        value_parser = parse_positive_number
+    )]
+    max_quantity: Option<f64>,
+
+    /// Write JSON Lines output row by row, flushing as it goes, instead of
+    /// through DuckDB's COPY; slower, but readers can start right away
+    #[arg(long, env = "PIPELINE_STREAM_JSONL", value_parser = BoolishValueParser::new())]
+    stream_jsonl: bool,
+
+    /// Write CSV output from Rust with thousands separators (1,234.50)
+    /// instead of through DuckDB's COPY; slower
+    #[arg(long, env = "PIPELINE_FORMAT_NUMBERS", value_parser = BoolishValueParser::new())]
+    format_numbers: bool,
+
+    /// Print a SHA-256 of the output, per file and combined for partitioned
+    /// output, and add it to --metrics-json
+    #[arg(long, env = "PIPELINE_CHECKSUM", value_parser = BoolishValueParser::new())]
+    checksum: bool,
+
+    /// Run on a random fraction of the input rows, e.g. 0.01 for a smoke test
+    #[arg(
+        long,
+        env = "PIPELINE_SAMPLE",
+        value_name = "FRACTION",
+        value_parser = parse_sample_fraction
+    )]
+    sample: Option<f64>,
+
+    /// Seed for --sample; the same seed samples the same rows
+    #[arg(long, env = "PIPELINE_SEED", default_value_t = 42)]
+    seed: u64,
+
+    /// Read only the first N input rows, in file order, e.g. to measure how
+    /// the run scales with input size
+    #[arg(
+        long,
+        env = "PIPELINE_LIMIT_ROWS",
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    limit_rows: Option<u64>,
+
+    /// Count the rows each cleaning rule removes; costs an extra pass over the
+    /// input
+    #[arg(long, env = "PIPELINE_DIAGNOSE", value_parser = BoolishValueParser::new())]
+    diagnose: bool,
+
+    /// Show up to N (default 20) of the rows cleaning removed, each with the
+    /// rules and filters it failed; on stderr unless --explain-removed-to
+    #[arg(
+        long,
+        env = "PIPELINE_EXPLAIN_REMOVED",
+        value_name = "N",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "20",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    explain_removed: Option<u64>,
+
+    /// CSV file to write the --explain-removed rows to instead of stderr
+    #[arg(
+        long,
+        env = "PIPELINE_EXPLAIN_REMOVED_TO",
+        value_name = "PATH",
+        requires = "explain_removed"
+    )]
+    explain_removed_to: Option<PathBuf>,
+
+    /// Add the results to an existing output instead of overwriting it: CSV
+    /// rows are appended without a header, other formats are rewritten with
+    /// the old and new rows, and --to-table rows are inserted
+    #[arg(long, env = "PIPELINE_APPEND", value_parser = BoolishValueParser::new())]
+    append: bool,
+
+    /// Write the results to TABLE in a DuckDB database file instead of an
+    /// output file, or in a SQLite one for a .sqlite or .sqlite3 file. An
+    /// existing table is an error unless --replace or --append is given
+    #[arg(
+        long,
+        env = "PIPELINE_TO_TABLE",
+        value_name = "DB_PATH:TABLE",
+        value_parser = parse_table_destination,
+        conflicts_with_all = [
+            "outputs",
+            "partition_by",
+            "split_quarters",
+            "stream_jsonl",
+            "format_numbers",
+            "checksum"
+        ]
+    )]
+    to_table: Option<TableDestination>,
+
+    /// Replace the --to-table table if it already exists
+    #[arg(
+        long,
+        env = "PIPELINE_REPLACE",
+        value_parser = BoolishValueParser::new(),
+        requires = "to_table",
+        conflicts_with = "append"
+    )]
+    replace: bool,
+
+    /// Round the revenue and price columns of the output to N decimal places
+    #[arg(
+        long,
+        env = "PIPELINE_ROUND",
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(0..=15)
+    )]
+    round: Option<u32>,
+
+    /// Comma-separated raw columns to keep instead of all of them; the
+    /// columns the rules, revenue, dedup and group-by need are always kept
+    #[arg(
+        long,
+        env = "PIPELINE_COLUMNS",
+        value_name = "COLS",
+        value_delimiter = ','
+    )]
+    columns: Vec<String>,
+
+    /// Comma-separated FROM=TO renames applied as the input is read, e.g.
+    /// qty=quantity,unit_price=price, so the pipeline finds its columns
+    #[arg(
+        long,
+        env = "PIPELINE_RENAME",
+        value_name = "FROM=TO,...",
+        value_delimiter = ',',
+        value_parser = parse_rename
+    )]
+    rename: Vec<ColumnRename>,
+
+    /// CSV with currency and rate columns; revenue is converted with the rate
+    /// of each row's currency column, and rows with an unknown currency are
+    /// counted and get no revenue
+    #[arg(long, env = "PIPELINE_RATES", value_name = "FILE")]
+    rates: Option<PathBuf>,
+
+    /// SQL file run instead of the built-in transform. It must create a view
+    /// named user_transformed from cleaned_data (deduped_data with --dedup),
+    /// with the date column and the columns --group-by and --aggregations
+    /// use; revenue and the --time-dims columns are only there if it adds them
+    #[arg(
+        long,
+        env = "PIPELINE_SQL_FILE",
+        value_name = "FILE",
+        value_parser = parse_sql_file
+    )]
+    sql_file: Option<SqlFile>,
+
+    /// Only process rows dated on or after YYYY-MM-DD, or with "auto" on or
+    /// after the date --state-file recorded; the rest are counted as before
+    /// the watermark
+    #[arg(long, env = "PIPELINE_SINCE", value_name = "DATE", value_parser = parse_since)]
+    since: Option<Since>,
+
+    /// File a successful run records the latest processed date in, for the
+    /// next run's --since auto
+    #[arg(long, env = "PIPELINE_STATE_FILE", value_name = "FILE")]
+    state_file: Option<PathBuf>,
+
+    /// Fail when cleaning removes more than PCT percent of the input rows,
+    /// which usually means a broken upstream export
+    #[arg(
+        long,
+        env = "PIPELINE_FAIL_ON_REMOVED_PCT",
+        value_name = "PCT",
+        value_parser = parse_percentage
+    )]
+    fail_on_removed_pct: Option<f64>,
+
+    /// TOML (or .json) file of constraints the aggregated output must meet
+    /// before it's written: min-rows, max-rows, not-null columns and sum
+    /// ranges. A miss fails the run with exit code 4
+    #[arg(
+        long,
+        env = "PIPELINE_EXPECT",
+        value_name = "FILE",
+        value_parser = parse_expectations
+    )]
+    expect: Option<Expectations>,
+
+    /// Only load and clean the input and report what the rules removed; fails
+    /// if that's more than --fail-on-removed-pct, writes no output
+    #[arg(
+        long,
+        env = "PIPELINE_VALIDATE_ONLY",
+        value_parser = BoolishValueParser::new(),
+        requires = "fail_on_removed_pct",
+        conflicts_with = "dry_run"
+    )]
+    validate_only: bool,
+
+    /// AWS region for s3:// input [default: AWS_REGION or AWS_DEFAULT_REGION]
+    #[arg(long, env = "PIPELINE_S3_REGION", value_name = "REGION")]
+    s3_region: Option<String>,
+
+    /// S3-compatible endpoint for s3:// input, e.g. http://localhost:9000
+    /// for MinIO; path-style URLs are used
+    #[arg(long, env = "PIPELINE_S3_ENDPOINT", value_name = "URL")]
+    s3_endpoint: Option<String>,
+
+    /// Prometheus pushgateway URL to push each successful run's metrics to;
+    /// a failed push only warns
+    #[arg(long, env = "PIPELINE_PUSH_GATEWAY", value_name = "URL")]
+    push_gateway: Option<String>,
+
+    /// Abort with exit code 5 if the dry run, warmup and measured runs
+    /// together take longer than this many seconds
+    #[arg(
+        long,
+        env = "PIPELINE_TIMEOUT",
+        value_name = "SECS",
+        value_parser = parse_positive_number
+    )]
+    timeout: Option<f64>,
+
+    /// TOML file of options keyed by their long names, e.g. group-by =
+    /// ["region"]; command-line arguments and environment variables win over
+    /// it
+    #[arg(long, env = "PIPELINE_CONFIG", value_name = "FILE")]
+    config: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare two --metrics-json files and fail if the candidate regressed
+    Compare {
+        baseline: PathBuf,
+        candidate: PathBuf,
+        /// Percentage increase beyond which a metric counts as a regression
+        #[arg(
+            long,
+            env = "PIPELINE_THRESHOLD",
+            value_name = "PCT",
+            default_value_t = 10.0
+        )]
+        threshold: f64,
+    },
+    /// Write reproducible synthetic sales CSVs for benchmarking
+    Generate {
+        rows: u64,
+        out_dir: PathBuf,
+        /// RNG seed; the same seed writes byte-identical files
+        #[arg(long, default_value_t = 42)]
+        seed: u64,
+        /// Number of files to split the rows across
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        files: u32,
+        /// Share of rows with one defect the default cleaning rules remove
+        #[arg(
+            long,
+            value_name = "FRACTION",
+            default_value_t = 0.05,
+            value_parser = parse_fraction
+        )]
+        invalid_fraction: f64,
+    },
+    /// Run this pipeline and the Python one on the same data and compare
+    /// their duration and peak memory
+    Bench {
+        #[arg(default_value = "data")]
+        data_dir: PathBuf,
+        /// Python pipeline command; {data_dir} and {output} are filled in
+        #[arg(
+            long,
+            env = "PIPELINE_PYTHON_CMD",
+            value_name = "CMD",
+            default_value = "python3 ../python-pipeline/pipeline.py --data-dir {data_dir} --output {output}"
+        )]
+        python_cmd: String,
+        /// Runs per pipeline; the medians are compared
+        #[arg(
+            long,
+            value_name = "N",
+            default_value_t = 1,
+            value_parser = clap::value_parser!(u32).range(1..)
+        )]
+        runs: u32,
+        /// Directory for both pipelines' outputs and logs
+        #[arg(long, value_name = "DIR", default_value = "results/bench")]
+        out_dir: PathBuf,
+        /// Where the combined result is written
+        #[arg(long, value_name = "PATH", default_value = "results/bench.json")]
+        json: PathBuf,
+    },
+}
+
+impl Cli {
+    fn pipeline_config(&self) -> PipelineConfig {
+        let mut data_dirs = split_data_dirs(&self.data_dir).into_iter();
+        PipelineConfig {
+            data_dir: data_dirs.next().unwrap_or_default(),
+            extra_data_dirs: data_dirs.collect(),
+            output_path: match self.outputs.first() {
+                Some(output) => output.path.to_string_lossy().into_owned(),
+                None => self.output_path.clone(),
+            },
+            format: self.outputs.first().map_or(self.format, |o| o.format),
+            recursive: self.recursive,
+            files: self.files.clone(),
+            json_unnest: self.json_unnest.clone(),
+            expect: self.expect.clone(),
+            input_format: self.input_format,
+            memory_limit: self.memory_limit.clone(),
+            temp_dir: self.temp_dir.clone(),
+            warmup_db: self.warmup_db,
+            threads: self.threads,
+            auto_degrade: self.auto_degrade,
+            db_path: self.db_path.clone(),
+            rules: if self.rules.is_empty() {
+                default_rules(&self.date_column)
+            } else {
+                self.rules.clone()
+            },
+            group_by: self.group_by.clone(),
+            aggregations: self.aggregations.clone(),
+            time_dims: self.time_dims.clone(),
+            allow_empty: self.allow_empty,
+            column_types: self.column_types.clone().map(|t| t.0).unwrap_or_default(),
+            revenue_expr: self.revenue_expr.clone().unwrap_or_default(),
+            dedup: if !self.dedup_on.is_empty() {
+                Dedup::OnColumns(self.dedup_on.clone())
+            } else if self.dedup {
+                Dedup::FullRow
+            } else {
+                Dedup::Off
+            },
+            date_column: self.date_column.clone(),
+            date_formats: match &self.date_format {
+                Some(format) => vec![format.clone()],
+                None => self.date_formats.clone(),
+            },
+            top_n: self.top_n,
+            window: self.window,
+            window_order: self.window_order.clone(),
+            yoy: self.yoy,
+            order_by: self.order_by.clone(),
+            order: self.order,
+            with_totals: self.with_totals,
+            max_retries: self.max_retries,
+            quantiles: self.quantiles.clone(),
+            partition_by: self.partition_by.clone(),
+            quarterly: self.quarterly,
+            split_quarters: self.split_quarters,
+            explain: self.explain,
+            csv_delimiter: self.delimiter,
+            csv_quote: self.quote,
+            profile: self.profile,
+            histogram: self.histogram.clone(),
+            verbose: self.verbose,
+            output_delimiter: self.output_delimiter,
+            null_strategy: self.null_strategy,
+            stdin_input: self.data_dir == "-",
+            dump_intermediate: self.dump_intermediate.clone(),
+            cache: self.cache.clone(),
+            extra_outputs: self.outputs.iter().skip(1).cloned().collect(),
+            exclude_products: self.exclude_products.clone(),
+            max_price: self.max_price,
+            max_quantity: self.max_quantity,
+            stream_jsonl: self.stream_jsonl,
+            format_numbers: self.format_numbers,
+            checksum: self.checksum,
+            sample: self.sample,
+            limit_rows: self.limit_rows,
+            seed: self.seed,
+            diagnose: self.diagnose,
+            explain_removed: self.explain_removed,
+            explain_removed_to: self.explain_removed_to.clone(),
+            append: self.append,
+            to_table: self.to_table.clone(),
+            replace_table: self.replace,
+            round: self.round,
+            memory_warn_mb: self.memory_warn_mb,
+            columns: self.columns.clone(),
+            renames: self.rename.clone(),
+            rates: self.rates.clone(),
+            sql_file: self.sql_file.clone(),
+            since: match self.since {
+                Some(Since::Date(date)) => Some(date),
+                // Filled in from the state file by `resolve_since`
+                Some(Since::Auto) | None => None,
+            },
+            state_file: self.state_file.clone(),
+            fail_on_removed_pct: self.fail_on_removed_pct,
+            validate_only: self.validate_only,
+            s3_region: self.s3_region.clone().or_else(|| {
+                ["AWS_REGION", "AWS_DEFAULT_REGION"]
+                    .into_iter()
+                    .find_map(|name| std::env::var(name).ok().filter(|v| !v.is_empty()))
+            }),
+            s3_endpoint: self.s3_endpoint.clone(),
+        }
+    }
+}
+
+/// The watermark `--since auto` starts from; `None` on the first run, before
+/// the state file exists, so everything is processed.
+fn resolve_since(state_file: Option<&Path>) -> anyhow::Result<Option<NaiveDate>> {
+    let path = state_file.ok_or_else(|| anyhow::anyhow!("--since auto requires --state-file"))?;
+    let since = read_watermark(path)?;
+    match since {
+        Some(date) => info!("Watermark from {}: {}", path.display(), date),
+        None => info!(
+            "No watermark in {} yet; processing all rows",
+            path.display()
+        ),
+    }
+    Ok(since)
+}
+
+/// A `--config` value as argument values; lists give one value per item.
+fn toml_arg_values(key: &str, value: &toml::Value) -> anyhow::Result<Vec<String>> {
+    let scalar = |value: &toml::Value| match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(n) => Ok(n.to_string()),
+        toml::Value::Float(n) => Ok(n.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        toml::Value::Datetime(d) => Ok(d.to_string()),
+        _ => Err(anyhow::anyhow!(
+            "'{}' must be a value or a list of values",
+            key
+        )),
+    };
+    match value {
+        toml::Value::Array(items) => items.iter().map(scalar).collect(),
+        value => Ok(vec![scalar(value)?]),
+    }
+}
+
+/// The `--config` file's entries as extra arguments for the options neither
+/// the command line nor the environment set: `--name=value` flags to go
+/// first, and the positional arguments to go last. Keys are long option
+/// names, with dashes or underscores; unknown keys are an error.
+fn config_file_args(
+    path: &Path,
+    matches: &ArgMatches,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("{} is not valid TOML", path.display()))?;
+    let command = Cli::command();
+    let overridden = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    let mut flags = Vec::new();
+    let mut data_dir = None;
+    let mut output_path = None;
+    let mut unknown = Vec::new();
+    for (key, value) in &table {
+        let name = key.replace('_', "-");
+        let arg = command.get_arguments().find(|a| match a.get_long() {
+            Some(long) => long == name && !["config", "help", "version"].contains(&long),
+            None => a.get_id().as_str().replace('_', "-") == name,
+        });
+        let Some(arg) = arg else {
+            unknown.push(key.as_str());
+            continue;
+        };
+        let id = arg.get_id().as_str();
+        if overridden(id) {
+            continue;
+        }
+        let values = toml_arg_values(key, value)?;
+        match (id, arg.get_long()) {
+            ("data_dir", _) => data_dir = values.into_iter().next(),
+            ("output_path", _) => output_path = values.into_iter().next(),
+            (_, Some(long)) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+                match value.as_bool() {
+                    Some(true) => flags.push(format!("--{}", long)),
+                    Some(false) => {}
+                    None => anyhow::bail!("'{}' must be true or false", key),
+                }
+            }
+            (_, Some(long)) => flags.extend(values.iter().map(|v| format!("--{}={}", long, v))),
+            (_, None) => unknown.push(key.as_str()),
+        }
+    }
+    if !unknown.is_empty() {
+        anyhow::bail!(
+            "{} has unknown option(s): {}",
+            path.display(),
+            unknown.join(", ")
+        );
+    }
+    // An output path needs the data directory before it, from wherever that came
+    let mut positionals = Vec::new();
+    if matches.value_source("data_dir") != Some(ValueSource::CommandLine)
+        && (data_dir.is_some() || output_path.is_some())
+    {
+        positionals.push(data_dir.unwrap_or_else(|| {
+            matches
+                .get_one::<String>("data_dir")
+                .cloned()
+                .unwrap_or_default()
+        }));
+    }
+    positionals.extend(output_path);
+    Ok((flags, positionals))
+}
+
+/// Parses the command line, adding the `--config` file's options if given.
+/// Subcommands don't read the file.
+fn parse_cli() -> Cli {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let matches = Cli::command().get_matches_from(&args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let (Some(path), None) = (&cli.config, &cli.command) else {
+        return cli;
+    };
+    let (flags, positionals) = config_file_args(path, &matches).unwrap_or_else(|e| {
+        Cli::command()
+            .error(clap::error::ErrorKind::ValueValidation, format!("{:#}", e))
+            .exit()
+    });
+    let mut args_with_file = vec![args[0].clone()];
+    args_with_file.extend(flags.into_iter().map(OsString::from));
+    args_with_file.extend(args[1..].iter().cloned());
+    if !positionals.is_empty() {
+        if !args.iter().any(|a| a == "--") {
+            args_with_file.push("--".into());
+        }
+        args_with_file.extend(positionals.into_iter().map(OsString::from));
+    }
+    Cli::parse_from(args_with_file)
+}
+
+/// Exit code after Ctrl-C, as a shell reports a process killed by SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// How a pipeline run failed, as its exit code, so a wrapper script can tell
+/// the causes apart. The subcommands exit 1 on any failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExitCode {
+    Generic = 1,
+    /// The data directory or its input files are missing or unreadable; also
+    /// clap's code for a usage error.
+    BadInput = 2,
+    /// The input lacks the columns the run needs.
+    Schema = 3,
+    /// `--fail-on-removed-pct` tripped.
+    QualityGate = 4,
+    /// `--timeout` elapsed.
+    Timeout = 5,
+    /// The run was slower or used more memory than `--baseline` allows.
+    Regression = 6,
+}
+
+impl ExitCode {
+    fn of(err: &anyhow::Error) -> Self {
+        match failure_kind(err) {
+            Some(FailureKind::Input) => ExitCode::BadInput,
+            Some(FailureKind::Schema) => ExitCode::Schema,
+            Some(FailureKind::QualityGate) => ExitCode::QualityGate,
+            None => ExitCode::Generic,
+        }
+    }
+}
+
+/// Set by the Ctrl-C handler, so a second Ctrl-C can't print twice.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// `--json-only` output: one pretty-printed JSON document on stdout.
+fn print_json(value: &impl serde::Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => error!("❌ Failed to serialize the JSON output: {}", e),
+    }
+}
+
+/// With `--json-only`, what's printed in place of a report when the run
+/// failed before it could produce one.
+fn print_json_error(message: &str) {
+    print_json(&serde_json::json!({ "success": false, "error": message }));
+}
+
+/// On Ctrl-C, prints the measured run's partial metrics and exits. The main
+/// thread may be stuck in a DuckDB query, so the handler can't wait for it.
+/// `stdin_dir` is the spooled stdin, removed since exiting skips destructors.
+fn install_interrupt_handler(
+    partial: SharedPartialMetrics,
+    stdin_dir: Option<PathBuf>,
+    json_only: bool,
+) {
+    let result = ctrlc::set_handler(move || {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if json_only {
+            print_json_error("Interrupted");
+        } else {
+            match partial.lock().unwrap().as_ref() {
+                Some(partial) => partial.print(),
+                None => warn!("Interrupted before the first measured run"),
+            }
+        }
+        if let Some(dir) = &stdin_dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        error!("❌ Interrupted");
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    });
+    if let Err(e) = result {
+        warn!("Failed to install the Ctrl-C handler: {}", e);
+    }
 }
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    
-    let data_dir = args.get(1)
-        .map(|s| s.as_str())
-        .unwrap_or("data");
-    
-    let output_path = args.get(2)
-        .map(|s| s.as_str())
-        .unwrap_or("results/rust_output.csv");
-
-    match run_pipeline(data_dir, output_path) {
-        Ok(_) => {
-            println!("✅ Pipeline completed successfully");
-            std::process::exit(0);
+    // Progress goes through the logger so RUST_LOG=warn gives a quiet run;
+    // the summary tables stay on stdout
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let cli = parse_cli();
+    // --json-only output is for machines, whatever the terminal
+    match cli.color {
+        _ if cli.json_only => console::set_colors_enabled(false),
+        ColorMode::Always => console::set_colors_enabled(true),
+        ColorMode::Never => console::set_colors_enabled(false),
+        ColorMode::Auto => {}
+    }
+
+    match &cli.command {
+        Some(Command::Compare {
+            baseline,
+            candidate,
+            threshold,
+        }) => match run_compare(baseline, candidate, *threshold) {
+            Ok(false) => std::process::exit(0),
+            Ok(true) => {
+                error!("❌ Candidate regressed by more than {}%", threshold);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                error!("❌ Comparison failed: {:#}", e);
+                std::process::exit(1);
+            }
+        },
+        Some(Command::Generate {
+            rows,
+            out_dir,
+            seed,
+            files,
+            invalid_fraction,
+        }) => {
+            let options = GenerateOptions {
+                rows: *rows,
+                out_dir: out_dir.clone(),
+                seed: *seed,
+                files: *files,
+                invalid_fraction: *invalid_fraction,
+            };
+            match generate_data(&options) {
+                Ok(generated) => {
+                    print_generated(&generated, &options);
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    error!("❌ Generation failed: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(Command::Bench {
+            data_dir,
+            python_cmd,
+            runs,
+            out_dir,
+            json,
+        }) => {
+            // The Rust side is this binary, run as a child like the Python
+            // one so both are measured the same way
+            let rust_cmd = match std::env::current_exe() {
+                Ok(exe) => vec![
+                    exe.to_string_lossy().into_owned(),
+                    "{data_dir}".to_string(),
+                    "{output}".to_string(),
+                ],
+                Err(e) => {
+                    error!("❌ Can't locate the rust-pipeline binary: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let options = BenchOptions {
+                data_dir: data_dir.clone(),
+                out_dir: out_dir.clone(),
+                rust_cmd,
+                python_cmd: python_cmd.split_whitespace().map(String::from).collect(),
+                runs: *runs,
+            };
+            let result = run_bench(&options).and_then(|report| {
+                print_bench(&report);
+                write_bench_json(json, &report)?;
+                info!("Benchmark result written to {}", json.display());
+                Ok(())
+            });
+            match result {
+                Ok(()) => std::process::exit(0),
+                Err(e) => {
+                    error!("❌ Benchmark failed: {:#}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => {}
+    }
+
+    // Checked here rather than by clap, which would also validate the
+    // default and so break subcommands run outside the repo. --files
+    // replaces the directory
+    let checked_dir = if cli.files.is_empty() {
+        parse_data_dir(&cli.data_dir)
+    } else {
+        Ok(String::new())
+    };
+    if let Err(e) = checked_dir {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ValueValidation,
+                format!("invalid value for '[DATA_DIR]': {}", e),
+            )
+            .exit();
+    }
+    let mut config = cli.pipeline_config();
+    // Before any DuckDB thread starts, so they all inherit it
+    if let Some(cpus) = &cli.pin_cpu {
+        if let Err(e) = pin_to_cpus(cpus) {
+            error!("❌ {:#}", e);
+            if cli.json_only {
+                print_json_error(&format!("{:#}", e));
+            }
+            std::process::exit(ExitCode::of(&e) as i32);
+        }
+    }
+    if cli.since == Some(Since::Auto) {
+        match resolve_since(cli.state_file.as_deref()) {
+            Ok(since) => config.since = since,
+            Err(e) => {
+                error!("❌ {:#}", e);
+                if cli.json_only {
+                    print_json_error(&format!("{:#}", e));
+                }
+                std::process::exit(ExitCode::of(&e) as i32);
+            }
+        }
+    }
+    let stdin_dir = if config.stdin_input {
+        match spool_stdin(config.input_format) {
+            Ok(dir) => {
+                config.data_dir = dir.path().to_string_lossy().into_owned();
+                Some(dir)
+            }
+            Err(e) => {
+                error!("❌ {:#}", e);
+                if cli.json_only {
+                    print_json_error(&format!("{:#}", e));
+                }
+                std::process::exit(ExitCode::of(&e) as i32);
+            }
+        }
+    } else {
+        None
+    };
+
+    let partial = SharedPartialMetrics::default();
+    install_interrupt_handler(
+        Arc::clone(&partial),
+        stdin_dir.as_ref().map(|dir| dir.path().to_path_buf()),
+        cli.json_only,
+    );
+    let code = match cli.timeout {
+        Some(secs) => run_with_timeout(
+            &cli,
+            &config,
+            &partial,
+            Duration::from_secs_f64(secs),
+            stdin_dir.as_ref().map(|dir| dir.path()),
+        ),
+        None => run(&cli, &config, &partial),
+    };
+    // process::exit skips destructors, so the spooled stdin is removed first
+    drop(stdin_dir);
+    std::process::exit(code);
+}
+
+/// `run` on a worker thread, exiting with `ExitCode::Timeout` if it's still
+/// going after `timeout`. duckdb-rs 0.10 has no way to interrupt a running
+/// query, so exiting the process is what stops it. `stdin_dir` is removed
+/// first, as in the Ctrl-C handler.
+fn run_with_timeout(
+    cli: &Cli,
+    config: &PipelineConfig,
+    partial: &SharedPartialMetrics,
+    timeout: Duration,
+    stdin_dir: Option<&Path>,
+) -> i32 {
+    let (done, finished) = mpsc::channel();
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let _ = done.send(run(cli, config, partial));
+        });
+        match finished.recv_timeout(timeout) {
+            Ok(code) => code,
+            // The worker panicked; leaving the scope re-raises the panic
+            Err(RecvTimeoutError::Disconnected) => ExitCode::Generic as i32,
+            // Leaving the scope would wait for the worker, so exit from here
+            Err(RecvTimeoutError::Timeout) => {
+                let message = format!(
+                    "Pipeline exceeded the --timeout of {}s",
+                    timeout.as_secs_f64()
+                );
+                if cli.json_only {
+                    print_json_error(&message);
+                } else if let Some(partial) = partial.lock().unwrap().as_ref() {
+                    partial.print();
+                }
+                if let Some(dir) = stdin_dir {
+                    let _ = std::fs::remove_dir_all(dir);
+                }
+                error!("❌ {}", message);
+                std::process::exit(ExitCode::Timeout as i32);
+            }
+        }
+    })
+}
+
+/// Dry run or schema, warmup and measured runs; returns the exit code. Measured runs
+/// publish their progress to `partial` for the Ctrl-C handler.
+fn run(cli: &Cli, config: &PipelineConfig, partial: &SharedPartialMetrics) -> i32 {
+    if cli.dry_run {
+        match dry_run_statements(config) {
+            Ok(statements) => {
+                for statement in statements {
+                    println!("{};\n", statement);
+                }
+                return 0;
+            }
+            Err(e) => {
+                error!("❌ Dry run failed: {:#}", e);
+                return ExitCode::of(&e) as i32;
+            }
+        }
+    }
+    if cli.explain_schema {
+        match explain_schema(config) {
+            Ok(columns) => {
+                print_schema(&columns);
+                return 0;
+            }
+            Err(e) => {
+                error!("❌ Explaining the schema failed: {:#}", e);
+                return ExitCode::of(&e) as i32;
+            }
+        }
+    }
+
+    info!("Runs: {} (plus {} warmup)", cli.runs, cli.warmup);
+    for i in 1..=cli.warmup {
+        info!("Warmup run {}/{} (results discarded)", i, cli.warmup);
+        if let Err(e) = run_pipeline(config, &mut PipelineMetrics::new()) {
+            error!("❌ Pipeline failed: {:#}", e);
+            if cli.json_only {
+                print_json_error(&format!("{:#}", e));
+            }
+            return ExitCode::of(&e) as i32;
+        }
+    }
+
+    let mut durations = Vec::new();
+    let mut peak_memory = Vec::new();
+    let mut rows_per_sec = Vec::new();
+    // Only the last run's report is printed with --json-only, as with
+    // --metrics-json
+    let mut last_report = None;
+    for i in 1..=cli.runs {
+        if cli.runs > 1 {
+            info!("Run {}/{}", i, cli.runs);
+        }
+        // Each run gets fresh metrics and, inside run_pipeline, a fresh connection
+        let run_timestamp = chrono::Utc::now().timestamp();
+        let mut metrics = PipelineMetrics::new();
+        if !cli.no_memory_sampler {
+            metrics.start_sampler(Duration::from_millis(cli.sample_interval));
+        }
+        metrics.share_partial(Arc::clone(partial));
+        let result = run_pipeline(config, &mut metrics);
+
+        // Written even when the run fails so partial timings aren't lost;
+        // with several runs the file holds the last one
+        if let Some(path) = &cli.metrics_json {
+            let error = result.as_ref().err().map(|e| format!("{:#}", e));
+            if let Err(e) = write_metrics_json(path, &metrics.report(error)) {
+                warn!("{:#}", e);
+            }
+        }
+
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                error!("❌ Pipeline failed: {:#}", e);
+                if cli.json_only {
+                    print_json(&metrics.report(Some(format!("{:#}", e))));
+                }
+                return ExitCode::of(&e) as i32;
+            }
+        };
+        last_report = Some(metrics.report(None));
+        if !cli.json_only {
+            if !result.profile.is_empty() {
+                print_profile(&result.profile);
+            }
+            if let Some(histogram) = &result.histogram {
+                print_histogram(histogram);
+            }
+            for plan in &result.plans {
+                println!("\n{}", summary_rule());
+                println!("Query Plan: {} ({})", plan.step, plan.view);
+                println!("{}", summary_rule());
+                println!("{}", plan.plan);
+            }
+            metrics.print_summary(config);
+        }
+        if let Some(url) = &cli.push_gateway {
+            match push_metrics(url, &metrics.report(None), run_timestamp) {
+                Ok(()) => info!("Pushed metrics to {}", url),
+                Err(e) => warn!("{:#}", e),
+            }
+        }
+        durations.push(result.duration.as_secs_f64());
+        peak_memory.extend(result.peak_memory_mb);
+        rows_per_sec.push(result.raw_rows as f64 / result.duration.as_secs_f64());
+    }
+
+    match &last_report {
+        Some(report) if cli.json_only => print_json(report),
+        _ if cli.runs > 1 => print_run_statistics(&durations, &peak_memory, &rows_per_sec),
+        _ => {}
+    }
+    if let (Some(path), Some(report)) = (&cli.baseline, &last_report) {
+        let code = check_against_baseline(cli, path, report);
+        if code != 0 {
+            return code;
+        }
+    }
+    info!("✅ Pipeline completed successfully");
+    0
+}
+
+/// `--baseline`: rewritten with `--update-baseline`, otherwise a regression
+/// past `--tolerance` fails the run.
+fn check_against_baseline(cli: &Cli, path: &Path, report: &MetricsReport) -> i32 {
+    if cli.update_baseline {
+        if let Err(e) = write_metrics_json(path, report) {
+            error!("❌ {:#}", e);
+            return ExitCode::Generic as i32;
+        }
+        info!("Updated baseline {}", path.display());
+        return 0;
+    }
+    match check_baseline(path, report, cli.tolerance) {
+        Ok(deltas) => {
+            if !cli.json_only {
+                print_comparison(&deltas, cli.tolerance);
+            }
+            if deltas.iter().any(|d| d.regressed) {
+                error!(
+                    "❌ Regressed by more than {}% against baseline {}",
+                    cli.tolerance,
+                    path.display()
+                );
+                return ExitCode::Regression as i32;
+            }
+            0
         }
         Err(e) => {
-            eprintln!("❌ Pipeline failed: {}", e);
-            std::process::exit(1);
+            error!("❌ {:#}", e);
+            ExitCode::Generic as i32
         }
     }
 }
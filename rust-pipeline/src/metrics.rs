@@ -0,0 +1,969 @@
+//! Timing and resource sampling for a run, and the `--metrics-json` report.
+
+use anyhow::{Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io::IsTerminal;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use sysinfo::{Pid, System};
+
+use crate::checksum::FileChecksum;
+use crate::config::PipelineConfig;
+use crate::term::{summary_rule, title, warning};
+
+/// Row counts at each stage; `None` for stages the run didn't reach.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RowCounts {
+    pub raw: Option<i64>,
+    pub cleaned: Option<i64>,
+    pub removed: Option<i64>,
+    /// The part of `removed` that passed the validity rules but not the
+    /// business filters.
+    #[serde(default)]
+    pub filtered: Option<i64>,
+    /// The part of `filtered` dated before `--since`.
+    #[serde(default)]
+    pub before_watermark: Option<i64>,
+    pub duplicates: Option<i64>,
+    /// Only counted with `--verbose`.
+    #[serde(default)]
+    pub transformed: Option<i64>,
+    pub aggregated: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleRemoval {
+    pub rule: String,
+    pub rows: i64,
+}
+
+/// Values `--null-strategy` replaced in a column instead of dropping the row.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImputedValues {
+    pub column: String,
+    pub rows: i64,
+}
+
+/// Raw rows whose date only parsed with this `--date-formats` entry or a
+/// later one; each row counts for the first format that matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DateFormatMatch {
+    pub format: String,
+    pub rows: i64,
+}
+
+/// Rows in one currency of a `--rates` run. Rows whose currency has no
+/// rate keep their quantities but get no revenue.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CurrencyRows {
+    pub currency: Option<String>,
+    pub rows: i64,
+    pub has_rate: bool,
+}
+
+/// One quarter's revenue in a `--quarterly` run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarterRevenue {
+    pub year: i64,
+    pub quarter: i64,
+    pub revenue: f64,
+}
+
+/// JSON documents read by a `--json-unnest` run, and the array elements in
+/// them that became rows.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnnestedJson {
+    pub documents: i64,
+    pub elements: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CastFailure {
+    pub column: String,
+    pub sql_type: String,
+    pub rows: i64,
+}
+
+/// One output destination of a run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrittenOutput {
+    pub path: String,
+    pub format: String,
+    /// Summed over the files of a partitioned output.
+    pub bytes: u64,
+    /// With `--checksum`: the file's SHA-256, or for a partitioned output a
+    /// hash combining its `files`.
+    #[serde(default)]
+    pub sha256: Option<String>,
+    #[serde(default)]
+    pub files: Vec<FileChecksum>,
+}
+
+/// The `--to-table` table a run wrote.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WrittenTable {
+    pub database: String,
+    pub table: String,
+    /// Rows inserted by this run; with `--append` the table may hold more.
+    pub rows: i64,
+}
+
+/// How `--auto-degrade` reran a run that ran out of memory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Degradation {
+    /// The first attempt's out-of-memory error.
+    pub error: String,
+    pub threads: u32,
+    pub memory_limit: String,
+}
+
+/// An intermediate view written by `--dump-intermediate`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpedFile {
+    pub view: String,
+    pub path: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepTiming {
+    pub name: String,
+    pub secs: f64,
+}
+
+/// Snapshot of a run written by `--metrics-json`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetricsReport {
+    pub success: bool,
+    pub error: Option<String>,
+    pub duration_secs: f64,
+    /// Peak resident set size (RSS); `None` when memory tracking was
+    /// unavailable.
+    pub peak_memory_mb: Option<f64>,
+    /// Peak virtual memory size: the address space mapped, resident or not.
+    /// DuckDB reserves far more than it touches, so this runs well above RSS.
+    #[serde(default)]
+    pub peak_virtual_memory_mb: Option<f64>,
+    pub peak_cpu_pct: Option<f32>,
+    pub avg_cpu_pct: Option<f32>,
+    pub steps: Vec<StepTiming>,
+    pub rows: RowCounts,
+    /// `rows.raw` and `rows.cleaned` over `duration_secs`.
+    #[serde(default)]
+    pub rows_per_sec: Option<f64>,
+    #[serde(default)]
+    pub cleaned_rows_per_sec: Option<f64>,
+    pub removed_by_rule: Vec<RuleRemoval>,
+    /// Valid rows removed by each business filter.
+    #[serde(default)]
+    pub removed_by_filter: Vec<RuleRemoval>,
+    #[serde(default)]
+    pub imputed: Vec<ImputedValues>,
+    #[serde(default)]
+    pub date_formats: Vec<DateFormatMatch>,
+    #[serde(default)]
+    pub currencies: Vec<CurrencyRows>,
+    #[serde(default)]
+    pub unnested: Option<UnnestedJson>,
+    #[serde(default)]
+    pub quarters: Vec<QuarterRevenue>,
+    pub cast_failures: Vec<CastFailure>,
+    pub output_size_bytes: Option<u64>,
+    /// Files written by a `--partition-by` run.
+    pub output_files: Option<u64>,
+    /// `--sample` fraction and seed; the row counts cover only the sample.
+    #[serde(default)]
+    pub sample_fraction: Option<f64>,
+    #[serde(default)]
+    pub sample_seed: Option<u64>,
+    /// `--limit-rows` cap on the rows read.
+    #[serde(default)]
+    pub limit_rows: Option<u64>,
+    /// `--warmup-db` time, left out of `duration_secs` and the steps.
+    #[serde(default)]
+    pub warmup_secs: Option<f64>,
+    #[serde(default)]
+    pub outputs: Vec<WrittenOutput>,
+    #[serde(default)]
+    pub table: Option<WrittenTable>,
+    #[serde(default)]
+    pub dumped: Vec<DumpedFile>,
+    /// Whether `--cache` stood in for loading and cleaning; `None` without
+    /// `--cache`.
+    #[serde(default)]
+    pub cache_hit: Option<bool>,
+    /// `SELECT version()` of the DuckDB that ran the queries.
+    #[serde(default)]
+    pub duckdb_version: Option<String>,
+    pub disk_read_bytes: Option<u64>,
+    pub disk_written_bytes: Option<u64>,
+    /// Size of DuckDB's spill files at the end of the run; 0 when everything
+    /// fit in memory.
+    #[serde(default)]
+    pub spilled_bytes: Option<u64>,
+    /// Set when `--auto-degrade` reran the pipeline; the failed attempt's
+    /// time is part of `duration_secs`.
+    #[serde(default)]
+    pub degraded: Option<Degradation>,
+}
+
+/// What a run has got through so far, republished at every sample so a
+/// Ctrl-C handler can report it from its own thread while the main thread is
+/// blocked inside a DuckDB query.
+#[derive(Debug, Clone)]
+pub struct PartialMetrics {
+    start_time: Instant,
+    steps: Vec<(String, Duration)>,
+    step_peak_memory_mb: f64,
+    /// The background sampler's live peak, read when the report is printed.
+    sampled_peak_bytes: Option<Arc<AtomicU64>>,
+    memory_tracked: bool,
+}
+
+/// Where a run publishes its `PartialMetrics`; `None` until one starts.
+pub type SharedPartialMetrics = Arc<Mutex<Option<PartialMetrics>>>;
+
+impl PartialMetrics {
+    pub fn peak_memory_mb(&self) -> Option<f64> {
+        let sampled = self
+            .sampled_peak_bytes
+            .as_ref()
+            .map_or(0.0, |peak| bytes_to_mb(peak.load(Ordering::Relaxed)));
+        self.memory_tracked
+            .then(|| self.step_peak_memory_mb.max(sampled))
+    }
+
+    pub fn print(&self) {
+        println!("\n{}", summary_rule());
+        println!("{}", title("Pipeline Interrupted (partial metrics)"));
+        println!("{}", summary_rule());
+        println!(
+            "Elapsed: {:.2} seconds",
+            self.start_time.elapsed().as_secs_f64()
+        );
+        println!("Completed steps:");
+        if self.steps.is_empty() {
+            println!("  (none)");
+        }
+        for (name, step) in &self.steps {
+            println!("  {:<12} {:>10.3} s", name, step.as_secs_f64());
+        }
+        match self.peak_memory_mb() {
+            Some(peak) => println!("Peak Memory: {:.2} MB", peak),
+            None => println!("Peak Memory: {}", MEMORY_UNAVAILABLE),
+        }
+        println!("{}", summary_rule());
+        println!();
+    }
+}
+
+/// Timings, row counts and resource samples collected over one run.
+pub struct PipelineMetrics {
+    pub(crate) start_time: Instant,
+    step_start: Instant,
+    steps: Vec<(String, Duration)>,
+    /// Peak resident memory seen by `update_memory` at step boundaries.
+    step_peak_memory_mb: f64,
+    /// The same for virtual memory.
+    step_peak_virtual_mb: f64,
+    sampler: Option<MemorySampler>,
+    cpu_samples: Vec<f32>,
+    last_refresh: Instant,
+    pub(crate) rows: RowCounts,
+    pub(crate) removed_by_rule: Vec<RuleRemoval>,
+    pub(crate) removed_by_filter: Vec<RuleRemoval>,
+    pub(crate) imputed: Vec<ImputedValues>,
+    pub(crate) date_formats: Vec<DateFormatMatch>,
+    pub(crate) currencies: Vec<CurrencyRows>,
+    pub(crate) unnested: Option<UnnestedJson>,
+    pub(crate) quarters: Vec<QuarterRevenue>,
+    pub(crate) cast_failures: Vec<CastFailure>,
+    pub(crate) output_size_bytes: Option<u64>,
+    pub(crate) output_files: Option<u64>,
+    pub(crate) sample: Option<(f64, u64)>,
+    pub(crate) limit_rows: Option<u64>,
+    warmup: Option<Duration>,
+    pub(crate) outputs: Vec<WrittenOutput>,
+    pub(crate) table: Option<WrittenTable>,
+    pub(crate) dumped: Vec<DumpedFile>,
+    pub(crate) cache_hit: Option<bool>,
+    pub(crate) duckdb_version: Option<String>,
+    pub(crate) spilled_bytes: Option<u64>,
+    degraded: Option<Degradation>,
+    /// Process-wide (all threads, including DuckDB's workers) cumulative
+    /// disk I/O when the metrics were created, and at the latest sample.
+    disk_baseline: Option<DiskCounters>,
+    disk_latest: Option<DiskCounters>,
+    progress: Option<ProgressBar>,
+    partial: Option<SharedPartialMetrics>,
+    system: System,
+    /// `None` when sysinfo can't see this process; nothing is sampled then.
+    pid: Option<Pid>,
+}
+
+impl Default for PipelineMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How often the background sampler reads the process's memory, unless
+/// `--sample-interval` says otherwise.
+pub const MEMORY_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shown in place of a peak when sysinfo couldn't see this process.
+const MEMORY_UNAVAILABLE: &str = "unavailable (process not visible to sysinfo)";
+
+/// Set once the warning about an untracked process has been logged, so that
+/// repeated `--runs` don't repeat it.
+static UNTRACKED_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// This process's PID, when sysinfo can find the process. Some containers
+/// and platforms hide it; memory, CPU and disk I/O then go untracked rather
+/// than failing the run.
+fn tracked_pid(system: &mut System) -> Option<Pid> {
+    let pid = sysinfo::get_current_pid()
+        .ok()
+        .filter(|pid| system.refresh_process(*pid));
+    if pid.is_none() && !UNTRACKED_WARNED.swap(true, Ordering::Relaxed) {
+        warn!("Can't find this process through sysinfo; memory and CPU usage won't be tracked");
+    }
+    pid
+}
+
+fn progress_message(peak_memory_mb: f64) -> String {
+    format!("(peak memory {:.0} MB)", peak_memory_mb)
+}
+
+fn bytes_to_mb(bytes: u64) -> f64 {
+    bytes as f64 / 1024.0 / 1024.0
+}
+
+/// Polls the process's resident and virtual memory on its own thread so that
+/// spikes inside a single long step still reach the peaks, and shows the
+/// resident peak on the attached spinner as samples come in. Stopped and
+/// joined on drop.
+struct MemorySampler {
+    peak_bytes: Arc<AtomicU64>,
+    peak_virtual_bytes: Arc<AtomicU64>,
+    progress: Arc<Mutex<Option<ProgressBar>>>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MemorySampler {
+    fn spawn(pid: Pid, interval: Duration) -> Self {
+        let peak_bytes = Arc::new(AtomicU64::new(0));
+        let peak_virtual_bytes = Arc::new(AtomicU64::new(0));
+        let progress: Arc<Mutex<Option<ProgressBar>>> = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let peak_bytes = Arc::clone(&peak_bytes);
+            let peak_virtual_bytes = Arc::clone(&peak_virtual_bytes);
+            let progress = Arc::clone(&progress);
+            let stop = Arc::clone(&stop);
+            std::thread::spawn(move || {
+                let mut system = System::new();
+                while !stop.load(Ordering::Relaxed) {
+                    system.refresh_process(pid);
+                    if let Some(process) = system.process(pid) {
+                        let peak = peak_bytes
+                            .fetch_max(process.memory(), Ordering::Relaxed)
+                            .max(process.memory());
+                        peak_virtual_bytes.fetch_max(process.virtual_memory(), Ordering::Relaxed);
+                        if let Some(bar) = progress.lock().unwrap().as_ref() {
+                            bar.set_message(progress_message(bytes_to_mb(peak)));
+                        }
+                    }
+                    // Woken early by `drop` so stopping doesn't wait out the interval
+                    std::thread::park_timeout(interval);
+                }
+            })
+        };
+        Self {
+            peak_bytes,
+            peak_virtual_bytes,
+            progress,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    fn peak_mb(&self) -> f64 {
+        bytes_to_mb(self.peak_bytes.load(Ordering::Relaxed))
+    }
+
+    fn peak_virtual_mb(&self) -> f64 {
+        bytes_to_mb(self.peak_virtual_bytes.load(Ordering::Relaxed))
+    }
+
+    /// Updates `bar` from now on, or stops updating any spinner with `None`.
+    fn attach_progress(&self, bar: Option<ProgressBar>) {
+        *self.progress.lock().unwrap() = bar;
+    }
+}
+
+impl Drop for MemorySampler {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DiskCounters {
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+fn process_disk_counters(system: &System, pid: Option<Pid>) -> Option<DiskCounters> {
+    let usage = system.process(pid?)?.disk_usage();
+    Some(DiskCounters {
+        read_bytes: usage.total_read_bytes,
+        written_bytes: usage.total_written_bytes,
+    })
+}
+
+impl PipelineMetrics {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        // Only this process is ever refreshed; loading every process would
+        // slow runs down and isn't needed for its own memory, CPU and disk I/O
+        let mut system = System::new();
+        let pid = tracked_pid(&mut system);
+        let disk_baseline = process_disk_counters(&system, pid);
+        Self {
+            start_time: now,
+            step_start: now,
+            steps: Vec::new(),
+            step_peak_memory_mb: 0.0,
+            step_peak_virtual_mb: 0.0,
+            sampler: None,
+            cpu_samples: Vec::new(),
+            last_refresh: now,
+            rows: RowCounts::default(),
+            removed_by_rule: Vec::new(),
+            removed_by_filter: Vec::new(),
+            imputed: Vec::new(),
+            date_formats: Vec::new(),
+            currencies: Vec::new(),
+            unnested: None,
+            quarters: Vec::new(),
+            cast_failures: Vec::new(),
+            output_size_bytes: None,
+            output_files: None,
+            sample: None,
+            limit_rows: None,
+            warmup: None,
+            outputs: Vec::new(),
+            table: None,
+            dumped: Vec::new(),
+            cache_hit: None,
+            duckdb_version: None,
+            spilled_bytes: None,
+            degraded: None,
+            disk_baseline,
+            disk_latest: disk_baseline,
+            progress: None,
+            partial: None,
+            system,
+            pid,
+        }
+    }
+
+    /// Starts the next step's clock without recording anything, so setup
+    /// work before the first step lands in the "other" bucket.
+    pub(crate) fn reset_step_clock(&mut self) {
+        self.step_start = Instant::now();
+    }
+
+    /// Drops what a failed attempt recorded before `--auto-degrade` reruns
+    /// it. The clock and the memory and CPU samples carry on, so the totals
+    /// cover both attempts.
+    pub(crate) fn restart_degraded(&mut self, degradation: Degradation) {
+        self.finish_progress();
+        self.steps.clear();
+        self.rows = RowCounts::default();
+        self.removed_by_rule.clear();
+        self.removed_by_filter.clear();
+        self.imputed.clear();
+        self.date_formats.clear();
+        self.currencies.clear();
+        self.unnested = None;
+        self.quarters.clear();
+        self.cast_failures.clear();
+        self.output_size_bytes = None;
+        self.output_files = None;
+        self.outputs.clear();
+        self.table = None;
+        self.dumped.clear();
+        self.cache_hit = None;
+        self.spilled_bytes = None;
+        self.degraded = Some(degradation);
+        self.reset_step_clock();
+        self.publish_partial();
+    }
+
+    /// Takes the `--warmup-db` time out of the run's duration, as if the run
+    /// had started once the warmup was done.
+    pub(crate) fn exclude_warmup(&mut self, warmup: Duration) {
+        self.start_time += warmup;
+        self.warmup = Some(warmup);
+    }
+
+    /// Records the time elapsed since the previous step boundary under `name`.
+    pub(crate) fn record_step(&mut self, name: &str) {
+        let now = Instant::now();
+        self.steps.push((name.to_string(), now - self.step_start));
+        self.step_start = now;
+        self.publish_partial();
+    }
+
+    /// Keeps `slot` up to date with this run's progress from now on.
+    pub fn share_partial(&mut self, slot: SharedPartialMetrics) {
+        self.partial = Some(slot);
+        self.publish_partial();
+    }
+
+    fn publish_partial(&self) {
+        if let Some(slot) = &self.partial {
+            *slot.lock().unwrap() = Some(PartialMetrics {
+                start_time: self.start_time,
+                steps: self.steps.clone(),
+                step_peak_memory_mb: self.step_peak_memory_mb,
+                sampled_peak_bytes: self.sampler.as_ref().map(|s| Arc::clone(&s.peak_bytes)),
+                memory_tracked: self.pid.is_some(),
+            });
+        }
+    }
+
+    /// Samples memory and CPU usage of the current process.
+    ///
+    /// sysinfo computes CPU usage as the delta between two refreshes, so a
+    /// sample taken too soon after the previous refresh (including the first
+    /// one in `new`) reads as ~0%. Such samples are skipped rather than
+    /// dragging the average down.
+    pub(crate) fn update_memory(&mut self) {
+        let Some(pid) = self.pid else {
+            return;
+        };
+        self.system.refresh_process(pid);
+        let since_refresh = self.last_refresh.elapsed();
+        self.last_refresh = Instant::now();
+        if let Some(process) = self.system.process(pid) {
+            let memory_mb = process.memory() as f64 / 1024.0 / 1024.0;
+            if memory_mb > self.step_peak_memory_mb {
+                self.step_peak_memory_mb = memory_mb;
+            }
+            let virtual_mb = bytes_to_mb(process.virtual_memory());
+            self.step_peak_virtual_mb = self.step_peak_virtual_mb.max(virtual_mb);
+            if since_refresh >= sysinfo::MINIMUM_CPU_UPDATE_INTERVAL {
+                self.cpu_samples.push(process.cpu_usage());
+            }
+        }
+        self.disk_latest = process_disk_counters(&self.system, self.pid);
+        if let (Some(progress), Some(peak)) = (&self.progress, self.peak_memory_mb()) {
+            progress.set_message(progress_message(peak));
+        }
+        self.publish_partial();
+    }
+
+    /// Starts sampling memory every `interval` on a background thread, on
+    /// top of the samples taken between steps.
+    pub fn start_sampler(&mut self, interval: Duration) {
+        self.sampler = self.pid.map(|pid| MemorySampler::spawn(pid, interval));
+        self.publish_partial();
+    }
+
+    /// Peak resident memory in MB across step-boundary and background
+    /// samples; `None` when the process couldn't be tracked.
+    pub fn peak_memory_mb(&self) -> Option<f64> {
+        let sampled = self.sampler.as_ref().map_or(0.0, |s| s.peak_mb());
+        self.pid.map(|_| self.step_peak_memory_mb.max(sampled))
+    }
+
+    /// Peak virtual memory in MB, sampled like `peak_memory_mb`.
+    pub fn peak_virtual_memory_mb(&self) -> Option<f64> {
+        let sampled = self.sampler.as_ref().map_or(0.0, |s| s.peak_virtual_mb());
+        self.pid.map(|_| self.step_peak_virtual_mb.max(sampled))
+    }
+
+    /// Shows a spinner for a long-running step. Nothing is drawn when stdout
+    /// isn't a terminal so piped output stays clean. The background sampler,
+    /// when running, keeps its peak-memory message current; without it the
+    /// message only has the step-boundary peak.
+    pub(crate) fn start_progress(&mut self, label: &str) {
+        if !std::io::stdout().is_terminal() {
+            return;
+        }
+        let progress = ProgressBar::new_spinner();
+        progress.set_style(
+            ProgressStyle::with_template("{spinner} {prefix} {elapsed} {msg}")
+                .expect("valid progress template"),
+        );
+        progress.set_prefix(label.to_string());
+        progress.enable_steady_tick(Duration::from_millis(100));
+        if let Some(sampler) = &self.sampler {
+            sampler.attach_progress(Some(progress.clone()));
+        }
+        self.progress = Some(progress);
+        self.update_memory();
+    }
+
+    /// Clears the spinner so it never overlaps log lines or the summary.
+    pub(crate) fn finish_progress(&mut self) {
+        if let Some(sampler) = &self.sampler {
+            sampler.attach_progress(None);
+        }
+        if let Some(progress) = self.progress.take() {
+            progress.finish_and_clear();
+        }
+    }
+
+    /// Bytes read from and written to storage by the whole process since the
+    /// metrics were created. Reads served from the page cache don't count.
+    fn disk_io(&self) -> Option<DiskCounters> {
+        let (start, end) = (self.disk_baseline?, self.disk_latest?);
+        Some(DiskCounters {
+            read_bytes: end.read_bytes.saturating_sub(start.read_bytes),
+            written_bytes: end.written_bytes.saturating_sub(start.written_bytes),
+        })
+    }
+
+    /// Peak and average CPU usage in percent of one core (can exceed 100%).
+    fn cpu_stats(&self) -> Option<(f32, f32)> {
+        if self.cpu_samples.is_empty() {
+            return None;
+        }
+        let peak = self.cpu_samples.iter().cloned().fold(0.0, f32::max);
+        let avg = self.cpu_samples.iter().sum::<f32>() / self.cpu_samples.len() as f32;
+        Some((peak, avg))
+    }
+
+    /// Builds the JSON report; `error` is set when the run did not complete.
+    pub fn report(&self, error: Option<String>) -> MetricsReport {
+        let cpu = self.cpu_stats();
+        let duration_secs = self.start_time.elapsed().as_secs_f64();
+        MetricsReport {
+            success: error.is_none(),
+            error,
+            duration_secs,
+            peak_memory_mb: self.peak_memory_mb(),
+            peak_virtual_memory_mb: self.peak_virtual_memory_mb(),
+            peak_cpu_pct: cpu.map(|(peak, _)| peak),
+            avg_cpu_pct: cpu.map(|(_, avg)| avg),
+            steps: self
+                .steps
+                .iter()
+                .map(|(name, d)| StepTiming {
+                    name: name.clone(),
+                    secs: d.as_secs_f64(),
+                })
+                .collect(),
+            rows: self.rows.clone(),
+            rows_per_sec: rows_per_sec(self.rows.raw, duration_secs),
+            cleaned_rows_per_sec: rows_per_sec(self.rows.cleaned, duration_secs),
+            removed_by_rule: self.removed_by_rule.clone(),
+            removed_by_filter: self.removed_by_filter.clone(),
+            imputed: self.imputed.clone(),
+            date_formats: self.date_formats.clone(),
+            currencies: self.currencies.clone(),
+            unnested: self.unnested.clone(),
+            quarters: self.quarters.clone(),
+            cast_failures: self.cast_failures.clone(),
+            output_size_bytes: self.output_size_bytes,
+            output_files: self.output_files,
+            sample_fraction: self.sample.map(|(fraction, _)| fraction),
+            sample_seed: self.sample.map(|(_, seed)| seed),
+            limit_rows: self.limit_rows,
+            warmup_secs: self.warmup.map(|w| w.as_secs_f64()),
+            outputs: self.outputs.clone(),
+            table: self.table.clone(),
+            dumped: self.dumped.clone(),
+            cache_hit: self.cache_hit,
+            duckdb_version: self.duckdb_version.clone(),
+            disk_read_bytes: self.disk_io().map(|io| io.read_bytes),
+            disk_written_bytes: self.disk_io().map(|io| io.written_bytes),
+            spilled_bytes: self.spilled_bytes,
+            degraded: self.degraded.clone(),
+        }
+    }
+
+    pub fn print_summary(&self, config: &PipelineConfig) {
+        let duration = self.start_time.elapsed();
+        let duration_secs = duration.as_secs_f64();
+
+        println!("\n{}", summary_rule());
+        println!("{}", title("Pipeline Execution Summary (Rust + DuckDB)"));
+        println!("{}", summary_rule());
+        if let Some(version) = &self.duckdb_version {
+            println!("DuckDB: {}", version);
+        }
+        println!(
+            "Duration: {:.2} seconds ({:.2} minutes)",
+            duration_secs,
+            duration_secs / 60.0
+        );
+        let stepped: Duration = self.steps.iter().map(|(_, d)| *d).sum();
+        for (name, step) in &self.steps {
+            println!("  {:<12} {:>10.3} s", name, step.as_secs_f64());
+        }
+        println!(
+            "  {:<12} {:>10.3} s",
+            "Other",
+            duration.saturating_sub(stepped).as_secs_f64()
+        );
+        if let Some(warmup) = self.warmup {
+            println!("Warmup: {:.3} s, not included above", warmup.as_secs_f64());
+        }
+        if let Some(degraded) = &self.degraded {
+            println!(
+                "{}",
+                warning(format!(
+                    "Degraded: rerun after running out of memory, with {} thread(s) and a {} memory limit; the failed attempt is in Other",
+                    degraded.threads, degraded.memory_limit
+                ))
+            );
+        }
+        if let Some(raw) = rows_per_sec(self.rows.raw, duration_secs) {
+            match rows_per_sec(self.rows.cleaned, duration_secs) {
+                Some(cleaned) => println!(
+                    "Throughput: {:.0} rows/sec ({:.0} cleaned rows/sec)",
+                    raw, cleaned
+                ),
+                None => println!("Throughput: {:.0} rows/sec", raw),
+            }
+        }
+        match self.peak_memory_mb() {
+            Some(peak) => {
+                let figure = format!("{:.2} MB ({:.2} GB) resident (RSS)", peak, peak / 1024.0);
+                if config.memory_warn_mb.is_some_and(|limit| peak > limit) {
+                    println!("Peak Memory: {}", warning(figure));
+                } else {
+                    println!("Peak Memory: {}", figure);
+                }
+            }
+            None => println!("Peak Memory: {}", MEMORY_UNAVAILABLE),
+        }
+        if let Some(peak) = self.peak_virtual_memory_mb() {
+            println!(
+                "Peak Virtual Memory: {:.2} MB ({:.2} GB) address space, not all resident",
+                peak,
+                peak / 1024.0
+            );
+        }
+        match self.cpu_stats() {
+            Some((peak, avg)) => {
+                println!("Peak CPU: {:.1}%", peak);
+                println!("Avg CPU: {:.1}%", avg);
+            }
+            None => println!("CPU: not sampled (run too short)"),
+        }
+        if let Some(io) = self.disk_io() {
+            println!(
+                "Disk I/O: {:.2} MB read, {:.2} MB written",
+                io.read_bytes as f64 / 1024.0 / 1024.0,
+                io.written_bytes as f64 / 1024.0 / 1024.0
+            );
+        }
+        match self.spilled_bytes {
+            Some(0) => println!("Spilled to Disk: none"),
+            Some(bytes) => println!("Spilled to Disk: {:.2} MB", bytes_to_mb(bytes)),
+            None => {}
+        }
+        if config.stdin_input {
+            println!("Input: stdin");
+        }
+        if !config.files.is_empty() {
+            println!("Input files: {}", config.files.join(", "));
+        }
+        match (&config.cache, self.cache_hit) {
+            (Some(path), Some(true)) => println!(
+                "Cache: loaded cleaned data from {}; load and clean skipped",
+                path.display()
+            ),
+            (Some(path), Some(false)) => println!("Cache: written to {}", path.display()),
+            _ => {}
+        }
+        if let (Some(unnest), Some(counts)) = (&config.json_unnest, &self.unnested) {
+            println!(
+                "JSON Unnest: {} documents expanded into {} {} elements",
+                counts.documents, counts.elements, unnest
+            );
+        }
+        if let Some(sample) = config.sample_description() {
+            println!(
+                "⚠️  SAMPLED RUN: {}; counts are not full-dataset results",
+                sample
+            );
+        }
+        if let Some(n) = self.limit_rows {
+            println!(
+                "⚠️  ROW CAP: first {} input rows; counts are not full-dataset results",
+                n
+            );
+        }
+        if config.validate_only {
+            println!("Validate Only: no output written");
+        } else if let Some(table) = &self.table {
+            println!(
+                "Output Table: {} in {} ({} rows inserted)",
+                table.table, table.database, table.rows
+            );
+        } else if self.outputs.len() > 1 {
+            println!("Outputs:");
+            for output in &self.outputs {
+                println!(
+                    "  {} ({}, {:.2} MB)",
+                    output.path,
+                    output.format,
+                    bytes_to_mb(output.bytes)
+                );
+            }
+        } else {
+            println!("Output Format: {}", config.format);
+        }
+        for output in &self.outputs {
+            if let Some(sha256) = &output.sha256 {
+                println!("SHA-256: {}  {}", sha256, output.path);
+            }
+            for file in &output.files {
+                println!("  {}  {}", file.sha256, file.path);
+            }
+        }
+        if let (Some(files), Some(bytes)) = (self.output_files, self.output_size_bytes) {
+            println!(
+                "Output Files: {} partitions, {:.2} MB total",
+                files,
+                bytes as f64 / 1024.0 / 1024.0
+            );
+        }
+        if let (Some(since), Some(skipped)) = (config.since, self.rows.before_watermark) {
+            println!("Since: {} ({} rows before watermark)", since, skipped);
+        }
+        if !self.currencies.is_empty() {
+            println!("Currencies:");
+            for currency in &self.currencies {
+                println!(
+                    "  {:<8} {} rows{}",
+                    currency.currency.as_deref().unwrap_or("(null)"),
+                    currency.rows,
+                    if currency.has_rate {
+                        ""
+                    } else {
+                        "  NO RATE, revenue left out"
+                    }
+                );
+            }
+        }
+        if !self.quarters.is_empty() {
+            println!("Quarters:");
+            for quarter in &self.quarters {
+                println!(
+                    "  {} Q{}  {:>14.2} revenue",
+                    quarter.year, quarter.quarter, quarter.revenue
+                );
+            }
+        }
+        if !self.dumped.is_empty() {
+            println!("Intermediate Dumps:");
+            for dump in &self.dumped {
+                println!(
+                    "  {:<16} {} ({:.2} MB)",
+                    dump.view,
+                    dump.path,
+                    bytes_to_mb(dump.bytes)
+                );
+            }
+        }
+        println!("Storage: {}", config.storage_description());
+        println!("{}", summary_rule());
+        println!();
+    }
+}
+
+/// Rows handled per second of the whole run, so runs over datasets of
+/// different sizes compare; `None` until the rows are counted.
+fn rows_per_sec(rows: Option<i64>, secs: f64) -> Option<f64> {
+    rows.filter(|_| secs > 0.0).map(|rows| rows as f64 / secs)
+}
+
+pub fn write_metrics_json(path: &Path, report: &MetricsReport) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create metrics directory")?;
+    }
+    let json = serde_json::to_string_pretty(report)?;
+    std::fs::write(path, json)
+        .with_context(|| format!("Failed to write metrics to {}", path.display()))
+}
+
+pub fn read_metrics_json(path: &Path) -> Result<MetricsReport> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read metrics from {}", path.display()))?;
+    serde_json::from_str(&json)
+        .with_context(|| format!("{} is not a --metrics-json file", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn background_sampler_reports_peak_memory() {
+        let mut metrics = PipelineMetrics::new();
+        assert_eq!(metrics.peak_memory_mb(), Some(0.0));
+        metrics.start_sampler(Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(metrics.peak_memory_mb().unwrap() > 0.0);
+        // Everything resident is mapped, so virtual memory is never smaller
+        let report = metrics.report(None);
+        assert!(report.peak_virtual_memory_mb >= report.peak_memory_mb);
+        drop(metrics);
+    }
+
+    #[test]
+    fn untracked_process_reports_no_memory() {
+        let mut metrics = PipelineMetrics::new();
+        metrics.pid = None;
+        metrics.start_sampler(Duration::from_millis(10));
+        metrics.update_memory();
+        assert!(metrics.sampler.is_none());
+        assert_eq!(metrics.peak_memory_mb(), None);
+        assert_eq!(metrics.report(None).peak_memory_mb, None);
+        assert_eq!(metrics.report(None).peak_virtual_memory_mb, None);
+    }
+
+    #[test]
+    fn shared_partial_metrics_follow_the_run() {
+        let slot = SharedPartialMetrics::default();
+        let mut metrics = PipelineMetrics::new();
+        metrics.share_partial(Arc::clone(&slot));
+        assert!(slot.lock().unwrap().as_ref().unwrap().steps.is_empty());
+        metrics.record_step("Load");
+        let partial = slot.lock().unwrap().clone().unwrap();
+        assert_eq!(partial.steps[0].0, "Load");
+    }
+
+    #[test]
+    fn sampler_updates_attached_spinner() {
+        let pid = sysinfo::get_current_pid().unwrap();
+        let sampler = MemorySampler::spawn(pid, Duration::from_millis(10));
+        let bar = ProgressBar::hidden();
+        sampler.attach_progress(Some(bar.clone()));
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(
+            bar.message().starts_with("(peak memory "),
+            "{}",
+            bar.message()
+        );
+        sampler.attach_progress(None);
+    }
+}
@@ -0,0 +1,3712 @@
+//! The load, clean, transform, aggregate and save steps.
+
+use anyhow::{Context, Result};
+use chrono::{Local, NaiveDate};
+use duckdb::{Connection, OptionalExt};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use sysinfo::System;
+
+use crate::cache::{cache_key_sql, read_cache, write_cache, CachedMetrics};
+use crate::checksum::{checksum_dir, checksum_file};
+use crate::config::{
+    memory_limit_bytes, Dedup, ExplainMode, OutputDestination, OutputFormat, PipelineConfig,
+    RevenueExpr, SqlFile, ValidationRule, USER_TRANSFORM_VIEW, YOY_PERIOD,
+};
+use crate::expect::check_expectations;
+use crate::failure::{failure, FailureKind};
+use crate::formatted::{format_number, write_formatted_csv};
+use crate::histogram::{histogram_view, Histogram};
+use crate::input::{count_cast_failures, discover_input, find_failing_files, InputSource};
+use crate::metrics::{
+    CastFailure, CurrencyRows, DateFormatMatch, Degradation, DumpedFile, ImputedValues,
+    PipelineMetrics, QuarterRevenue, RuleRemoval, UnnestedJson, WrittenOutput, WrittenTable,
+};
+use crate::profile::{describe_view, profile_view, ColumnProfile, ColumnSchema};
+use crate::s3::{is_s3, setup_s3};
+use crate::sql::{
+    append_staging_path, build_cached_clean_sql, build_currency_count_sql,
+    build_date_format_count_sql, build_filter_count_sql, build_group_count_sql,
+    build_imputed_count_sql, build_max_date_sql, build_quarter_revenue_sql, build_read_output_sql,
+    build_removed_copy_sql, build_removed_sample_sql, build_text_sql, build_unnest_count_sql,
+    build_unnest_type_sql, build_user_transform_exists_sql, dump_path, output_source,
+    pending_output_path, source_reader_sql, transform_source, PipelineSql, DUPLICATE_RATES_SQL,
+    SPILLED_BYTES_SQL,
+};
+use crate::stream::stream_jsonl;
+use crate::watermark::write_watermark;
+
+/// Counts the raw rows failing each rule; a row failing several rules is
+/// counted once per rule. It costs a pass over the input, hence `--diagnose`.
+fn count_rule_failures(
+    conn: &Connection,
+    rules: &[ValidationRule],
+    date_formats: &[String],
+) -> Result<Vec<i64>> {
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+    let filters: Vec<String> = rules
+        .iter()
+        .map(|r| {
+            format!(
+                "COUNT(*) FILTER (WHERE NOT COALESCE({}, false))",
+                r.condition(date_formats)
+            )
+        })
+        .collect();
+    let sql = format!("SELECT {} FROM raw_data", filters.join(", "));
+    conn.query_row(&sql, [], |row| {
+        (0..rules.len()).map(|i| row.get(i)).collect()
+    })
+    .context("Failed to count rows removed per rule")
+}
+
+/// `--explain-removed`: writes a sample of the removed rows and why each was
+/// removed to the `--explain-removed-to` CSV, or lists it on stderr.
+fn explain_removed(conn: &Connection, config: &PipelineConfig, limit: u64) -> Result<()> {
+    let sample = build_removed_sample_sql(config, limit);
+    if let Some(path) = &config.explain_removed_to {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)
+                .context("Failed to create the --explain-removed-to directory")?;
+        }
+        conn.execute_batch(&build_removed_copy_sql(&sample, path))
+            .with_context(|| format!("Failed to write removed rows to {}", path.display()))?;
+        info!("Sample of removed rows written to {}", path.display());
+        return Ok(());
+    }
+    let columns = view_columns(conn, &format!("SELECT * FROM ({})", sample))?;
+    let mut stmt = conn.prepare(&build_text_sql(&sample, &columns))?;
+    let rows: Vec<Vec<Option<String>>> = stmt
+        .query_map([], |row| (0..columns.len()).map(|i| row.get(i)).collect())?
+        .collect::<std::result::Result<_, _>>()
+        .context("Failed to sample the removed rows")?;
+    eprintln!("Removed rows (first {}):", rows.len());
+    for row in rows {
+        let values: Vec<String> = columns[1..]
+            .iter()
+            .zip(&row[1..])
+            .map(|(column, value)| format!("{}={}", column, value.as_deref().unwrap_or("NULL")))
+            .collect();
+        eprintln!(
+            "  {}: {}",
+            row[0].as_deref().unwrap_or_default(),
+            values.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Column names of a view or table, in order.
+fn view_columns(conn: &Connection, view: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {}", view))?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(columns)
+}
+
+/// Runs the `--sql-file` statements and checks they created
+/// `user_transformed`. DuckDB binds a view when it's created, so an unknown
+/// column fails here; the error lists the columns that are available.
+fn run_user_transform(
+    conn: &Connection,
+    config: &PipelineConfig,
+    file: &SqlFile,
+    user_sql: &str,
+) -> Result<()> {
+    if let Err(e) = conn.execute_batch(user_sql) {
+        let source = transform_source(&config.dedup);
+        let available = view_columns(conn, source)?;
+        return Err(anyhow::Error::new(e).context(format!(
+            "--sql-file {} failed ({} has: {})",
+            file.path.display(),
+            source,
+            available.join(", ")
+        )));
+    }
+    let created: i64 = conn.query_row(&build_user_transform_exists_sql(), [], |row| row.get(0))?;
+    if created == 0 {
+        anyhow::bail!(
+            "--sql-file {} ran but didn't create a view named {}",
+            file.path.display(),
+            USER_TRANSFORM_VIEW
+        );
+    }
+    Ok(())
+}
+
+/// Refuses to `--append` results whose columns differ from the existing
+/// output's, e.g. after `--group-by` changed, rather than mixing schemas.
+fn check_append_columns(
+    conn: &Connection,
+    config: &PipelineConfig,
+    destination: &OutputDestination,
+) -> Result<()> {
+    let existing = view_columns(conn, &build_read_output_sql(config, destination))
+        .with_context(|| format!("Failed to read {}", destination.path.display()))?;
+    let results = view_columns(conn, output_source(config))?;
+    if existing != results {
+        return Err(failure(
+            FailureKind::Schema,
+            format!(
+                "Can't append to {}: its columns ({}) don't match the results' ({})",
+                destination.path.display(),
+                existing.join(", "),
+                results.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Writes a CSV output followed by the header-less rows `--append` staged to
+/// `pending`, leaving the output itself untouched until it's renamed over.
+fn append_staged_csv(path: &Path, pending: &Path) -> Result<()> {
+    let staging = append_staging_path(path);
+    std::fs::copy(path, pending).with_context(|| format!("Failed to copy {}", path.display()))?;
+    let mut rows = std::fs::File::open(&staging)
+        .with_context(|| format!("Failed to open {}", staging.display()))?;
+    let mut output = std::fs::OpenOptions::new()
+        .append(true)
+        .open(pending)
+        .with_context(|| format!("Failed to open {}", pending.display()))?;
+    std::io::copy(&mut rows, &mut output)
+        .with_context(|| format!("Failed to append to {}", pending.display()))?;
+    std::fs::remove_file(&staging)
+        .with_context(|| format!("Failed to remove {}", staging.display()))?;
+    Ok(())
+}
+
+/// Fails with the list of missing columns, `--columns` ones included,
+/// instead of letting DuckDB error deep inside the cleaning step.
+fn validate_schema(conn: &Connection, config: &PipelineConfig) -> Result<()> {
+    let available = view_columns(conn, "raw_data")?;
+    let missing: Vec<String> = config
+        .projected_columns()
+        .unwrap_or_else(|| config.required_columns())
+        .into_iter()
+        .filter(|c| !available.contains(c))
+        .collect();
+    if !missing.is_empty() {
+        return Err(failure(
+            FailureKind::Schema,
+            format!(
+                "Input is missing required column(s): {} (found: {})",
+                missing.join(", "),
+                available.join(", ")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that the `--json-unnest` path leads through objects to an array of
+/// objects, whose fields the load spreads into columns. DuckDB's own error
+/// for a wrong path would only name the last field.
+fn check_json_unnest(
+    conn: &Connection,
+    config: &PipelineConfig,
+    input: &InputSource,
+) -> Result<()> {
+    let Some(unnest) = &config.json_unnest else {
+        return Ok(());
+    };
+    let sql_type: Option<String> = conn
+        .query_row(&build_unnest_type_sql(unnest, input), [], |row| row.get(0))
+        .optional()
+        .map_err(|e| {
+            failure(
+                FailureKind::Schema,
+                format!(
+                    "--json-unnest {} isn't in the JSON documents: {}",
+                    unnest, e
+                ),
+            )
+        })?;
+    match sql_type {
+        Some(t) if !(t.starts_with("STRUCT(") && t.ends_with(")[]")) => Err(failure(
+            FailureKind::Schema,
+            format!("--json-unnest {} is {}, not an array of objects", unnest, t),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Checks the `--rename` mapping against the input's columns before
+/// `raw_data` is built on it: every source has to exist, and no target may
+/// clash with another column.
+fn check_renames(conn: &Connection, config: &PipelineConfig, input: &InputSource) -> Result<()> {
+    if config.renames.is_empty() {
+        return Ok(());
+    }
+    let available = view_columns(
+        conn,
+        &format!("SELECT * FROM {}", source_reader_sql(config, input)),
+    )
+    .context("Failed to read the input columns to rename")?;
+    let missing: Vec<&str> = config
+        .renames
+        .iter()
+        .filter(|r| !available.contains(&r.from))
+        .map(|r| r.from.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(failure(
+            FailureKind::Schema,
+            format!(
+                "--rename source column(s) not in the input: {} (found: {})",
+                missing.join(", "),
+                available.join(", ")
+            ),
+        ));
+    }
+    for rename in &config.renames {
+        let sources = config.renames.iter().filter(|r| r.from == rename.from);
+        if sources.count() > 1 {
+            anyhow::bail!("--rename renames '{}' more than once", rename.from);
+        }
+        let targets = config.renames.iter().filter(|r| r.to == rename.to);
+        let kept =
+            available.contains(&rename.to) && !config.renames.iter().any(|r| r.from == rename.to);
+        if kept || targets.count() > 1 {
+            anyhow::bail!(
+                "--rename target '{}' would clash with another column",
+                rename.to
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Runs the `--to-table` statements, installing the sqlite extension on
+/// first use the way `setup_s3` does httpfs.
+fn write_table(conn: &Connection, config: &PipelineConfig, statements: &[String]) -> Result<()> {
+    let Some(target) = &config.to_table else {
+        return Ok(());
+    };
+    info!("Saving results to table {}...", target);
+    if let Some(dir) = target.database.parent() {
+        std::fs::create_dir_all(dir).context("Failed to create the database directory")?;
+    }
+    let mut statements = statements.iter();
+    if target.is_sqlite() {
+        let load = statements.next().map_or("LOAD sqlite", |s| s.as_str());
+        conn.execute_batch(load)
+            .or_else(|_| conn.execute_batch("INSTALL sqlite; LOAD sqlite"))
+            .context(
+                "A SQLite --to-table database needs DuckDB's sqlite extension, which couldn't \
+                 be loaded or installed; allow access to extensions.duckdb.org or install it \
+                 beforehand",
+            )?;
+    }
+    for statement in statements {
+        conn.execute_batch(statement).with_context(|| {
+            if config.append || config.replace_table {
+                format!("Failed to write the results to table {}", target)
+            } else {
+                format!(
+                    "Failed to create table {}; pass --replace or --append to write to an \
+                     existing one",
+                    target
+                )
+            }
+        })?;
+    }
+    Ok(())
+}
+
+/// Records how much DuckDB spilled to its temp directory over the run.
+fn record_spill(conn: &Connection, metrics: &mut PipelineMetrics) -> Result<()> {
+    let bytes: u64 = conn
+        .query_row(SPILLED_BYTES_SQL, [], |row| row.get(0))
+        .context("Failed to check DuckDB's spill files")?;
+    if bytes > 0 {
+        warn!(
+            "DuckDB spilled {:.2} MB to disk; the memory limit was a bottleneck",
+            bytes as f64 / 1024.0 / 1024.0
+        );
+    }
+    metrics.spilled_bytes = Some(bytes);
+    Ok(())
+}
+
+/// Applies the optional DuckDB PRAGMAs; unset options keep DuckDB's defaults.
+fn apply_engine_settings(conn: &Connection, settings: &[String]) -> Result<()> {
+    for statement in settings {
+        conn.execute_batch(statement)
+            .with_context(|| format!("Failed to apply `{}`", statement))?;
+    }
+    Ok(())
+}
+
+/// `--verbose`: logs the rows in a view just created.
+fn log_view_rows(config: &PipelineConfig, view: &str, rows: i64) {
+    if config.verbose {
+        info!("{}: {} rows", view, rows);
+    }
+}
+
+/// Whether `--top-n` left out any groups, so `agg_count` isn't the true
+/// number of groups. Exactly k groups fill the cap without being capped,
+/// hence the look for a (k+1)th.
+fn top_n_dropped_groups(
+    conn: &Connection,
+    config: &PipelineConfig,
+    agg_count: i64,
+) -> Result<bool> {
+    match config.top_n {
+        Some(k) if agg_count as u64 == k => {
+            let groups: u64 = conn
+                .query_row(&build_group_count_sql(config, k + 1), [], |row| row.get(0))
+                .context("Failed to count groups")?;
+            Ok(groups > k)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Delay before the first load retry; doubled for each further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// DuckDB prefixes messages with the error category. Only I/O failures, such
+/// as a flaky network mount, are worth retrying; SQL and schema errors aren't.
+fn is_io_error(err: &duckdb::Error) -> bool {
+    err.to_string().starts_with("IO Error")
+}
+
+/// Runs `op`, retrying up to `max_retries` times on I/O errors.
+fn retry_io<T>(
+    max_retries: u32,
+    base_delay: Duration,
+    mut op: impl FnMut() -> duckdb::Result<T>,
+) -> duckdb::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < max_retries && is_io_error(&e) => {
+                let delay = base_delay * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "{}; retrying in {} ms (attempt {}/{})",
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    max_retries
+                );
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Failing files named in a load error; the rest are only counted.
+const MAX_FAILING_FILES: usize = 5;
+
+/// Adds the input files that also fail when read on their own to a load
+/// error, so one bad file in a large drop doesn't have to be bisected by
+/// hand. If none fail alone, the error is returned as it was.
+fn name_failing_files(conn: &Connection, input: &InputSource, err: anyhow::Error) -> anyhow::Error {
+    let failing = match find_failing_files(conn, input) {
+        Ok(failing) if !failing.is_empty() => failing,
+        _ => return err,
+    };
+    let mut named: Vec<String> = failing
+        .iter()
+        .take(MAX_FAILING_FILES)
+        .map(|(file, error)| format!("{} ({})", file, error))
+        .collect();
+    if failing.len() > MAX_FAILING_FILES {
+        named.push(format!("and {} more", failing.len() - MAX_FAILING_FILES));
+    }
+    err.context(format!(
+        "{} of {} {} files failed to load: {}",
+        failing.len(),
+        input.file_count,
+        input.format,
+        named.join("; ")
+    ))
+}
+
+/// The statements `run_pipeline` would execute for `config`, in order.
+/// Only the data directory listing is read; no input data is touched.
+pub fn dry_run_statements(config: &PipelineConfig) -> Result<Vec<String>> {
+    check_output_options(config)?;
+    let conn = Connection::open_in_memory().context("Failed to create DuckDB connection")?;
+    setup_s3(&conn, config)?;
+    let input = discover_input(&conn, config)?;
+    Ok(PipelineSql::new(config, &input).statements())
+}
+
+/// Loads `raw_data` as `run_pipeline` would and describes its columns,
+/// without cleaning or writing anything.
+pub fn explain_schema(config: &PipelineConfig) -> Result<Vec<ColumnSchema>> {
+    check_output_options(config)?;
+    let conn = Connection::open_in_memory().context("Failed to create DuckDB connection")?;
+    setup_s3(&conn, config)?;
+    let input = discover_input(&conn, config)?;
+    check_json_unnest(&conn, config, &input)?;
+    check_renames(&conn, config, &input)?;
+    conn.execute(&PipelineSql::new(config, &input).raw, [])
+        .with_context(|| format!("Failed to load {} files", input.format))?;
+    describe_view(&conn, "raw_data")
+}
+
+/// `--output-delimiter` only applies to CSV outputs and `--stream-jsonl` to
+/// JSON Lines ones. `--partition-by` only works for Parquet, and the column
+/// has to be a group-by key or it won't exist in `aggregated_data`.
+/// `--append` needs a single file written through `COPY`, and `--order-by`
+/// has to name an output column. `--to-table` writes no files, so the file
+/// output options don't apply to it.
+fn check_output_options(config: &PipelineConfig) -> Result<()> {
+    let output_columns = config.output_columns();
+    if let Some(expectations) = &config.expect {
+        if let Some(column) = expectations.columns().find(|c| !output_columns.contains(c)) {
+            anyhow::bail!(
+                "{} names column '{}', which isn't in the output (columns: {})",
+                expectations.path.display(),
+                column,
+                output_columns.join(", ")
+            );
+        }
+    }
+    if config.cache.is_some() {
+        if config.data_dirs().iter().any(|d| is_s3(d)) {
+            anyhow::bail!("--cache needs local input; S3 files aren't checked for changes");
+        }
+        let raw_only = [
+            ("--profile", config.profile),
+            ("--diagnose", config.diagnose),
+            ("--explain-removed", config.explain_removed.is_some()),
+            ("--validate-only", config.validate_only),
+        ];
+        if let Some((option, _)) = raw_only.iter().find(|(_, set)| *set) {
+            anyhow::bail!(
+                "{} reads the raw input, which --cache skips; drop one of them",
+                option
+            );
+        }
+    }
+    if !config.files.is_empty() && (config.stdin_input || !config.extra_data_dirs.is_empty()) {
+        anyhow::bail!("--files replaces the data directory; don't pass directories or - as well");
+    }
+    if config.aggregations.is_empty() {
+        anyhow::bail!("--aggregations needs at least one measure");
+    }
+    for (i, dim) in config.time_dims.iter().enumerate() {
+        if config.time_dims[..i].contains(dim) {
+            anyhow::bail!("--time-dims lists '{}' more than once", dim.column());
+        }
+    }
+    if config.yoy && config.group_by.iter().any(|c| c == YOY_PERIOD) {
+        anyhow::bail!(
+            "--yoy adds {0} itself; drop {0} from --group-by",
+            YOY_PERIOD
+        );
+    }
+    for (i, column) in output_columns.iter().enumerate() {
+        if output_columns[..i].contains(column) {
+            anyhow::bail!(
+                "Output column '{}' appears twice; check --group-by, --window-order and --aggregations",
+                column
+            );
+        }
+    }
+    if config.sql_file.is_some() {
+        if config.rates.is_some() {
+            anyhow::bail!("--rates can't be combined with --sql-file");
+        }
+        if config.revenue_expr != RevenueExpr::default() {
+            anyhow::bail!("--revenue-expr can't be combined with --sql-file");
+        }
+    }
+    if config.quarterly {
+        let missing: Vec<&str> = ["year", "quarter"]
+            .into_iter()
+            .filter(|c| !config.group_by.iter().any(|g| g == c))
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "--quarterly needs {} in --group-by (has: {})",
+                missing.join(" and "),
+                config.group_by.join(", ")
+            );
+        }
+        if config.window.is_some() {
+            anyhow::bail!("--quarterly can't be combined with --window");
+        }
+        if config.top_n.is_some() {
+            anyhow::bail!("--top-n can't be combined with --quarterly");
+        }
+    } else if config.split_quarters {
+        anyhow::bail!("--split-quarters requires --quarterly");
+    }
+    if config.yoy {
+        let conflicts = [
+            ("--window", config.window.is_some()),
+            ("--quarterly", config.quarterly),
+            ("--top-n", config.top_n.is_some()),
+            ("--with-totals", config.with_totals),
+            ("--quantiles", !config.quantiles.is_empty()),
+        ];
+        if let Some((option, _)) = conflicts.iter().find(|(_, set)| *set) {
+            anyhow::bail!("{} can't be combined with --yoy", option);
+        }
+    } else if config.window.is_some() {
+        if config.window_order.is_empty() {
+            anyhow::bail!("--window needs at least one --window-order column");
+        }
+        if config.top_n.is_some() {
+            anyhow::bail!("--top-n can't be combined with --window");
+        }
+        if config.with_totals {
+            anyhow::bail!("--with-totals can't be combined with --window");
+        }
+    } else if !output_columns.contains(&config.order_by) {
+        anyhow::bail!(
+            "--order-by column '{}' must be one of the output columns ({})",
+            config.order_by,
+            output_columns.join(", ")
+        );
+    }
+    if config.replace_table && config.to_table.is_none() {
+        anyhow::bail!("--replace requires --to-table");
+    }
+    if config.replace_table && config.append {
+        anyhow::bail!("--replace can't be combined with --append");
+    }
+    if config.to_table.is_some() {
+        let file_only = [
+            ("--output", !config.extra_outputs.is_empty()),
+            ("--partition-by", config.partition_by.is_some()),
+            ("--split-quarters", config.split_quarters),
+            ("--stream-jsonl", config.stream_jsonl),
+            ("--format-numbers", config.format_numbers),
+            ("--checksum", config.checksum),
+        ];
+        if let Some((option, _)) = file_only.iter().find(|(_, set)| *set) {
+            anyhow::bail!("{} can't be combined with --to-table", option);
+        }
+    }
+    let destinations = config.destinations();
+    if config.stream_jsonl && !destinations.iter().any(|d| config.streams(d)) {
+        anyhow::bail!("--stream-jsonl requires a JSON Lines output (--format jsonl)");
+    }
+    if config.append && config.stream_jsonl {
+        anyhow::bail!("--append can't be combined with --stream-jsonl");
+    }
+    if config.format_numbers && !destinations.iter().any(|d| config.formats_numbers(d)) {
+        anyhow::bail!("--format-numbers requires a CSV output (--format csv)");
+    }
+    if config.format_numbers && !config.output_delimiter.is_ascii() {
+        anyhow::bail!("--format-numbers needs an ASCII --output-delimiter");
+    }
+    if config.append && config.format_numbers {
+        anyhow::bail!("--append can't be combined with --format-numbers");
+    }
+    if let Some(option) = config.partition_option() {
+        if config.append {
+            anyhow::bail!("--append can't be combined with {}", option);
+        }
+        if config.with_totals {
+            anyhow::bail!("--with-totals can't be combined with {}", option);
+        }
+    }
+    if config.output_delimiter != ',' && !destinations.iter().any(|d| d.format == OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--output-delimiter requires --format csv, not {}",
+            config.format
+        );
+    }
+    if config.split_quarters && config.partition_by.is_some() {
+        anyhow::bail!("--split-quarters can't be combined with --partition-by");
+    }
+    let Some(option) = config.partition_option() else {
+        return Ok(());
+    };
+    if destinations.len() > 1 {
+        anyhow::bail!("{} can't be combined with several outputs", option);
+    }
+    if config.format != OutputFormat::Parquet {
+        anyhow::bail!(
+            "{} requires --format parquet, not {}",
+            option,
+            config.format
+        );
+    }
+    let Some(column) = &config.partition_by else {
+        return Ok(());
+    };
+    if !config.group_by.contains(column) {
+        anyhow::bail!(
+            "--partition-by column '{}' must be one of the --group-by columns ({})",
+            column,
+            config.group_by.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Creates the directory each output goes in and writes a probe file there,
+/// so an unwritable output fails before the input is loaded rather than at
+/// the final `COPY`. Existing output files are opened for writing too.
+fn check_outputs_writable(config: &PipelineConfig) -> Result<()> {
+    for destination in config.destinations() {
+        let path = &destination.path;
+        let dir = if config.partition_option().is_some() && path.is_dir() {
+            path.as_path()
+        } else {
+            match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            }
+        };
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create output directory {}", dir.display()))?;
+        tempfile::Builder::new()
+            .prefix(".rust-pipeline-")
+            .tempfile_in(dir)
+            .with_context(|| format!("Output directory {} is not writable", dir.display()))?;
+        if path.is_file() {
+            std::fs::OpenOptions::new()
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Output file {} is not writable", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates an empty `pending` directory for the partitions, removing one a
+/// killed run left. The output directory an earlier run wrote is replaced
+/// wholesale, so stale partitions don't survive; anything in it that isn't
+/// a `column=value` partition directory fails the run instead.
+fn prepare_partition_dir(dir: &Path, pending: &Path, column: &str) -> Result<()> {
+    if dir.exists() {
+        let prefix = format!("{}=", column);
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?
+        {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !entry.file_type()?.is_dir() || !name.starts_with(&prefix) {
+                anyhow::bail!(
+                    "Refusing to write partitions into {}: it holds {}, which isn't a {}... partition",
+                    dir.display(),
+                    name,
+                    prefix
+                );
+            }
+        }
+    }
+    if pending.exists() {
+        std::fs::remove_dir_all(pending)
+            .with_context(|| format!("Failed to remove {}", pending.display()))?;
+    }
+    std::fs::create_dir_all(pending).context("Failed to create output directory")
+}
+
+/// Renames a finished output from `pending` over `path`, so a reader sees
+/// the previous output or the complete new one, never a partial write. A
+/// directory can't be renamed over a non-empty one, so a partitioned output
+/// is moved aside first and removed once the new one is in place.
+fn move_into_place(pending: &Path, path: &Path, partitioned: bool) -> Result<()> {
+    let replaced = (partitioned && path.exists()).then(|| {
+        let mut old = path.as_os_str().to_owned();
+        old.push(".old");
+        PathBuf::from(old)
+    });
+    if let Some(old) = &replaced {
+        if old.exists() {
+            std::fs::remove_dir_all(old)
+                .with_context(|| format!("Failed to remove {}", old.display()))?;
+        }
+        std::fs::rename(path, old)
+            .with_context(|| format!("Failed to move {} aside", path.display()))?;
+    }
+    std::fs::rename(pending, path).with_context(|| {
+        format!(
+            "Failed to rename {} to {}",
+            pending.display(),
+            path.display()
+        )
+    })?;
+    if let Some(old) = replaced {
+        std::fs::remove_dir_all(&old)
+            .with_context(|| format!("Failed to remove {}", old.display()))?;
+    }
+    Ok(())
+}
+
+/// Runs a `--dump-intermediate` COPY of `view` and records the file. Kept out
+/// of the step timings, since the extra write would skew them.
+fn dump_view(
+    conn: &Connection,
+    config: &PipelineConfig,
+    metrics: &mut PipelineMetrics,
+    view: &str,
+    sql: &Option<String>,
+) -> Result<()> {
+    let (Some(dir), Some(sql)) = (&config.dump_intermediate, sql) else {
+        return Ok(());
+    };
+    info!("Dumping {}...", view);
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    conn.execute(sql, [])
+        .with_context(|| format!("Failed to dump {}", view))?;
+    let path = dump_path(dir, view);
+    let bytes = std::fs::metadata(&path)
+        .with_context(|| format!("Failed to stat {}", path.display()))?
+        .len();
+    metrics.dumped.push(DumpedFile {
+        view: view.to_string(),
+        path: path.to_string_lossy().into_owned(),
+        bytes,
+    });
+    metrics.reset_step_clock();
+    Ok(())
+}
+
+/// Number and total size of the files under a partitioned output directory.
+fn partition_files(dir: &Path) -> Result<(u64, u64)> {
+    let mut files = 0;
+    let mut bytes = 0;
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to list {}", dir.display()))?
+    {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            let (sub_files, sub_bytes) = partition_files(&entry.path())?;
+            files += sub_files;
+            bytes += sub_bytes;
+        } else {
+            files += 1;
+            bytes += metadata.len();
+        }
+    }
+    Ok((files, bytes))
+}
+
+/// DuckDB's plan for the query behind one step's view.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryPlan {
+    pub step: String,
+    pub view: String,
+    pub plan: String,
+}
+
+/// Explains `SELECT * FROM view` for each step's view. Each plan includes the
+/// views below it, since DuckDB inlines them. `EXPLAIN ANALYZE` executes the
+/// whole query, so its timings are for real work.
+fn explain_steps(
+    conn: &Connection,
+    config: &PipelineConfig,
+    mode: ExplainMode,
+) -> Result<Vec<QueryPlan>> {
+    let mut views = vec![("Load", "raw_data"), ("Clean", "cleaned_data")];
+    if config.dedup != Dedup::Off {
+        views.push(("Dedup", "deduped_data"));
+    }
+    views.extend([
+        ("Transform", "transformed_data"),
+        ("Aggregate", "aggregated_data"),
+    ]);
+
+    let explain = match mode {
+        ExplainMode::Plan => "EXPLAIN",
+        ExplainMode::Analyze => "EXPLAIN ANALYZE",
+    };
+    let mut plans = Vec::new();
+    for (step, view) in views {
+        let mut stmt = conn.prepare(&format!("{} SELECT * FROM {}", explain, view))?;
+        let rows: Vec<String> = stmt
+            .query_map([], |row| row.get::<_, String>(1))?
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("Failed to explain {}", view))?;
+        plans.push(QueryPlan {
+            step: step.to_string(),
+            view: view.to_string(),
+            plan: rows.join("\n"),
+        });
+    }
+    Ok(plans)
+}
+
+/// What a successful run produced. Counts for stages a run skipped (no input
+/// rows without `--allow-empty`) are zero.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineResult {
+    pub raw_rows: i64,
+    pub cleaned_rows: i64,
+    pub removed_rows: i64,
+    pub aggregated_rows: i64,
+    pub duration: Duration,
+    /// `None` when memory tracking was unavailable.
+    pub peak_memory_mb: Option<f64>,
+    /// `None` when no output file was written. Summed over the files of a
+    /// partitioned output.
+    pub output_size_bytes: Option<u64>,
+    pub output_files: u64,
+    /// Filled in when `config.explain` is set.
+    pub plans: Vec<QueryPlan>,
+    /// Filled in when `config.profile` is set.
+    pub profile: Vec<ColumnProfile>,
+    /// Filled in when `config.histogram` is set and the run got that far.
+    pub histogram: Option<Histogram>,
+}
+
+/// Runs the pipeline; with `config.auto_degrade`, an out-of-memory failure is
+/// retried once on `degraded_config`.
+pub fn run_pipeline(
+    config: &PipelineConfig,
+    metrics: &mut PipelineMetrics,
+) -> Result<PipelineResult> {
+    let err = match run_attempt(config, metrics) {
+        Err(err) if config.auto_degrade && is_out_of_memory(&err) => err,
+        result => return result,
+    };
+    let degraded = degraded_config(config);
+    let memory_limit = degraded.memory_limit.clone().unwrap_or_default();
+    warn!(
+        "Out of memory; rerunning with 1 thread and a {} memory limit (--auto-degrade)",
+        memory_limit
+    );
+    metrics.restart_degraded(Degradation {
+        error: format!("{:#}", err),
+        threads: 1,
+        memory_limit,
+    });
+    run_attempt(&degraded, metrics)
+}
+
+/// DuckDB's error category for a query that outgrew the memory limit.
+fn is_out_of_memory(err: &anyhow::Error) -> bool {
+    err.chain().any(|e| {
+        e.downcast_ref::<duckdb::Error>()
+            .is_some_and(|e| e.to_string().starts_with("Out of Memory Error"))
+    })
+}
+
+/// `config` on one thread with half its memory limit, or half of DuckDB's
+/// default of 80% of RAM. Fewer threads hold fewer buffers at once, and the
+/// lower limit makes DuckDB spill to disk sooner.
+fn degraded_config(config: &PipelineConfig) -> PipelineConfig {
+    let limit_bytes = config
+        .memory_limit
+        .as_deref()
+        .and_then(memory_limit_bytes)
+        .unwrap_or_else(|| {
+            let mut system = System::new();
+            system.refresh_memory();
+            system.total_memory() as f64 * 0.8
+        });
+    let limit_mib = (limit_bytes / 2.0 / 1024.0 / 1024.0).floor().max(1.0);
+    PipelineConfig {
+        threads: Some(1),
+        memory_limit: Some(format!("{:.0}MiB", limit_mib)),
+        ..config.clone()
+    }
+}
+
+/// What loading and cleaning left in `cleaned_data`.
+struct CleanedRows {
+    raw_rows: i64,
+    cleaned_rows: i64,
+    removed_rows: i64,
+    profile: Vec<ColumnProfile>,
+}
+
+/// How `load_and_clean` ended: with rows to carry on with, or with the
+/// whole run's result, for empty input and `--validate-only`.
+enum CleanOutcome {
+    Cleaned(CleanedRows),
+    Finished(PipelineResult),
+}
+
+/// `--fail-on-removed-pct`: fails the run when cleaning removed too much.
+fn check_removed_pct(
+    config: &PipelineConfig,
+    row_count: i64,
+    cleaned_count: i64,
+    removed_pct: f64,
+) -> Result<()> {
+    if let Some(max_pct) = config.fail_on_removed_pct {
+        if removed_pct > max_pct {
+            return Err(failure(
+                FailureKind::QualityGate,
+                format!(
+                    "Cleaning removed {:.2}% of rows ({} raw, {} cleaned), more than --fail-on-removed-pct {}",
+                    removed_pct, row_count, cleaned_count, max_pct
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Steps 1 and 2 on a `--cache` hit: points `cleaned_data` at the cache and
+/// restores the counts the run that wrote it recorded while cleaning.
+fn load_cached(
+    conn: &Connection,
+    config: &PipelineConfig,
+    sql: &PipelineSql,
+    metrics: &mut PipelineMetrics,
+    path: &Path,
+    cached: CachedMetrics,
+) -> Result<CleanedRows> {
+    info!(
+        "Loading cleaned data from cache {} (skipping load and clean)...",
+        path.display()
+    );
+    conn.execute(&build_cached_clean_sql(path), [])
+        .with_context(|| format!("Failed to read cache {}", path.display()))?;
+    let cleaned_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM cleaned_data", [], |row| row.get(0))?;
+    log_view_rows(config, "cleaned_data", cleaned_count);
+    cached.restore(metrics);
+    let row_count = metrics.rows.raw.unwrap_or(cleaned_count);
+    let removed = row_count - cleaned_count;
+    let removed_pct = if row_count > 0 {
+        (removed as f64 / row_count as f64) * 100.0
+    } else {
+        0.0
+    };
+    info!(
+        "Cached rows: {} of {} raw rows; {} ({:.2}%) were removed when the cache was built",
+        cleaned_count, row_count, removed, removed_pct
+    );
+    check_removed_pct(config, row_count, cleaned_count, removed_pct)?;
+    metrics.update_memory();
+    metrics.record_step("Load");
+    dump_view(conn, config, metrics, "cleaned_data", &sql.dump_clean)?;
+    Ok(CleanedRows {
+        raw_rows: row_count,
+        cleaned_rows: cleaned_count,
+        removed_rows: removed,
+        profile: Vec::new(),
+    })
+}
+
+/// Steps 1 and 2: loads the input into `raw_data` and cleans it into
+/// `cleaned_data`, recording the counts along the way.
+fn load_and_clean(
+    conn: &Connection,
+    config: &PipelineConfig,
+    input: &InputSource,
+    sql: &PipelineSql,
+    metrics: &mut PipelineMetrics,
+) -> Result<CleanOutcome> {
+    // Step 1: Load input files
+    info!(
+        "Loading {} files from {}...",
+        input.format,
+        config.data_dir_description()
+    );
+    info!("Found {} {} files", input.file_count, input.format);
+    check_json_unnest(conn, config, input)?;
+    check_renames(conn, config, input)?;
+
+    retry_io(config.max_retries, RETRY_BASE_DELAY, || {
+        conn.execute(&sql.raw, [])
+    })
+    .map_err(|e| {
+        let context = if is_io_error(&e) && config.max_retries > 0 {
+            format!(
+                "Failed to load {} files after {} retries",
+                input.format, config.max_retries
+            )
+        } else {
+            format!("Failed to load {} files", input.format)
+        };
+        name_failing_files(conn, input, anyhow::Error::new(e).context(context))
+    })?;
+    validate_schema(conn, config)?;
+
+    let cast_failures = count_cast_failures(conn, input, &config.column_types)?;
+    for (column_type, failed) in config.column_types.iter().zip(&cast_failures) {
+        if *failed > 0 {
+            warn!(
+                "{} value(s) in column {} failed to cast to {} and were loaded as NULL",
+                failed, column_type.column, column_type.sql_type
+            );
+        }
+    }
+    metrics.cast_failures = config
+        .column_types
+        .iter()
+        .zip(cast_failures)
+        .map(|(t, rows)| CastFailure {
+            column: t.column.clone(),
+            sql_type: t.sql_type.clone(),
+            rows,
+        })
+        .collect();
+
+    metrics.update_memory();
+
+    // Count total rows; views are lazy, so this is where the files get read
+    metrics.start_progress("Loading data");
+    let row_count: Result<i64, _> =
+        conn.query_row("SELECT COUNT(*) FROM raw_data", [], |row| row.get(0));
+    metrics.finish_progress();
+    let row_count = row_count.map_err(|e| {
+        name_failing_files(
+            conn,
+            input,
+            anyhow::Error::new(e).context(format!("Failed to read {} files", input.format)),
+        )
+    })?;
+    info!("Total rows loaded: {}", row_count);
+    log_view_rows(config, "raw_data", row_count);
+    metrics.rows.raw = Some(row_count);
+    metrics.record_step("Load");
+    if let Some(unnest) = &config.json_unnest {
+        let (documents, elements) = conn
+            .query_row(&build_unnest_count_sql(unnest, input), [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .context("Failed to count the unnested JSON elements")?;
+        info!(
+            "Expanded {} JSON documents into {} {} elements",
+            documents, elements, unnest
+        );
+        metrics.unnested = Some(UnnestedJson {
+            documents,
+            elements,
+        });
+        metrics.reset_step_clock();
+    }
+
+    if row_count == 0 {
+        warn!("No rows loaded");
+        let Some(empty_raw) = &sql.empty_raw else {
+            info!("Nothing to process; pass --allow-empty to write an empty output anyway");
+            return Ok(CleanOutcome::Finished(PipelineResult {
+                raw_rows: 0,
+                cleaned_rows: 0,
+                removed_rows: 0,
+                aggregated_rows: 0,
+                duration: metrics.start_time.elapsed(),
+                peak_memory_mb: metrics.peak_memory_mb(),
+                output_size_bytes: None,
+                output_files: 0,
+                plans: Vec::new(),
+                profile: Vec::new(),
+                histogram: None,
+            }));
+        };
+        conn.execute(empty_raw, [])
+            .context("Failed to prepare empty input")?;
+    }
+
+    let profile = if config.profile {
+        info!("Profiling raw data...");
+        let profile = profile_view(conn, "raw_data")?;
+        // Like --explain, kept out of the step timings
+        metrics.reset_step_clock();
+        profile
+    } else {
+        Vec::new()
+    };
+
+    // Step 2: Clean data
+    info!("Cleaning data...");
+    conn.execute(&sql.clean, [])
+        .context("Failed to clean data")?;
+
+    let cleaned_count: i64 =
+        conn.query_row("SELECT COUNT(*) FROM cleaned_data", [], |row| row.get(0))?;
+    log_view_rows(config, "cleaned_data", cleaned_count);
+
+    let removed = row_count - cleaned_count;
+    let removed_pct = if row_count > 0 {
+        (removed as f64 / row_count as f64) * 100.0
+    } else {
+        0.0
+    };
+    info!("Removed {} invalid rows ({:.2}%)", removed, removed_pct);
+    info!("Remaining rows: {}", cleaned_count);
+    if let Some(limit) = config.explain_removed.filter(|_| removed > 0) {
+        explain_removed(conn, config, limit)?;
+    }
+    if config.diagnose || config.validate_only {
+        let rules = config.cleaning_rules();
+        let failures = count_rule_failures(conn, &rules, &config.date_formats)?;
+        let breakdown: Vec<String> = rules
+            .iter()
+            .zip(&failures)
+            .map(|(rule, failed)| format!("{}: {}", rule.describe_failure(), failed))
+            .collect();
+        info!(
+            "Removed by rule: {} (a row may fail several)",
+            breakdown.join(", ")
+        );
+        for (rule, failed) in rules.iter().zip(&failures) {
+            info!("  {:<24} {} rows", rule.to_string(), failed);
+        }
+        metrics.removed_by_rule = rules
+            .iter()
+            .zip(failures)
+            .map(|(rule, rows)| RuleRemoval {
+                rule: rule.to_string(),
+                rows,
+            })
+            .collect();
+    }
+    if let Some(count_sql) = build_filter_count_sql(config) {
+        let filters = config.business_filters();
+        let counts: Vec<i64> = conn
+            .query_row(&count_sql, [], |row| {
+                (0..=filters.len()).map(|i| row.get(i)).collect()
+            })
+            .context("Failed to count rows removed by business filters")?;
+        let filtered = counts[filters.len()];
+        info!(
+            "Removed {} valid rows with business filters; {} failed the validity rules",
+            filtered,
+            removed - filtered
+        );
+        for (filter, rows) in filters.iter().zip(&counts) {
+            info!("  {:<24} {} rows", filter.name, rows);
+        }
+        if let Some(since) = config.since {
+            // The watermark is the last filter
+            let skipped = counts[filters.len() - 1];
+            info!("Skipped {} rows before watermark {}", skipped, since);
+            metrics.rows.before_watermark = Some(skipped);
+        }
+        metrics.removed_by_filter = filters
+            .iter()
+            .zip(counts)
+            .map(|(filter, rows)| RuleRemoval {
+                rule: filter.name.clone(),
+                rows,
+            })
+            .collect();
+        metrics.rows.filtered = Some(filtered);
+    }
+    if let Some(count_sql) = build_imputed_count_sql(config) {
+        let columns = config.imputed_columns();
+        let counts: Vec<i64> = conn
+            .query_row(&count_sql, [], |row| {
+                (0..columns.len()).map(|i| row.get(i)).collect()
+            })
+            .context("Failed to count imputed values")?;
+        for (column, rows) in columns.iter().zip(&counts) {
+            info!("Imputed {} missing or invalid {} values", rows, column);
+        }
+        metrics.imputed = columns
+            .iter()
+            .zip(counts)
+            .map(|(column, rows)| ImputedValues {
+                column: column.to_string(),
+                rows,
+            })
+            .collect();
+    }
+    if let Some(count_sql) = build_date_format_count_sql(config) {
+        let counts: Vec<i64> = conn
+            .query_row(&count_sql, [], |row| {
+                (0..config.date_formats.len()).map(|i| row.get(i)).collect()
+            })
+            .context("Failed to count rows per date format")?;
+        for (format, rows) in config.date_formats.iter().zip(&counts) {
+            info!("  Date format {:<16} {} rows", format, rows);
+        }
+        metrics.date_formats = config
+            .date_formats
+            .iter()
+            .zip(counts)
+            .map(|(format, rows)| DateFormatMatch {
+                format: format.clone(),
+                rows,
+            })
+            .collect();
+    }
+    metrics.rows.cleaned = Some(cleaned_count);
+    metrics.rows.removed = Some(removed);
+    check_removed_pct(config, row_count, cleaned_count, removed_pct)?;
+
+    metrics.update_memory();
+    metrics.record_step("Clean");
+    dump_view(conn, config, metrics, "cleaned_data", &sql.dump_clean)?;
+
+    if config.validate_only {
+        record_spill(conn, metrics)?;
+        info!(
+            "✅ Validation passed: {:.2}% of rows removed, within --fail-on-removed-pct {}",
+            removed_pct,
+            config.fail_on_removed_pct.unwrap_or(100.0)
+        );
+        return Ok(CleanOutcome::Finished(PipelineResult {
+            raw_rows: row_count,
+            cleaned_rows: cleaned_count,
+            removed_rows: removed,
+            aggregated_rows: 0,
+            duration: metrics.start_time.elapsed(),
+            peak_memory_mb: metrics.peak_memory_mb(),
+            output_size_bytes: None,
+            output_files: 0,
+            plans: Vec::new(),
+            profile,
+            histogram: None,
+        }));
+    }
+    Ok(CleanOutcome::Cleaned(CleanedRows {
+        raw_rows: row_count,
+        cleaned_rows: cleaned_count,
+        removed_rows: removed,
+        profile,
+    }))
+}
+
+fn run_attempt(config: &PipelineConfig, metrics: &mut PipelineMetrics) -> Result<PipelineResult> {
+    check_output_options(config)?;
+    if !config.validate_only {
+        check_outputs_writable(config)?;
+    }
+
+    // Connect to DuckDB (in-memory unless a database file was requested)
+    let conn = match &config.db_path {
+        Some(path) => Connection::open(path)
+            .with_context(|| format!("Failed to open DuckDB database {}", path.display()))?,
+        None => Connection::open_in_memory().context("Failed to create DuckDB connection")?,
+    };
+    let version: String = conn
+        .query_row("SELECT version()", [], |row| row.get(0))
+        .context("Failed to query the DuckDB version")?;
+    metrics.duckdb_version = Some(version.clone());
+    // Discovery only lists files, so it can run before the settings apply
+    setup_s3(&conn, config)?;
+    let input = discover_input(&conn, config)?;
+    let sql = PipelineSql::new(config, &input);
+    apply_engine_settings(&conn, &sql.settings)?;
+    let (memory_limit, threads, temp_dir): (String, i64, String) = conn.query_row(
+        "SELECT current_setting('memory_limit'), current_setting('threads'),
+                current_setting('temp_directory')",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+
+    info!("Starting Rust + DuckDB Pipeline");
+    info!("Timestamp: {}", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    info!("DuckDB: {}", version);
+    info!("Memory limit: {}", memory_limit);
+    info!("Threads: {}", threads);
+    info!("Temp directory: {}", temp_dir);
+    info!("Storage: {}", config.storage_description());
+    if config.stdin_input {
+        info!("Input: stdin");
+    } else {
+        info!("Input: {}", config.data_dir_description());
+    }
+    if let Some(target) = &config.to_table {
+        info!("Output: table {}", target);
+    }
+    for destination in config.destinations() {
+        info!(
+            "Output: {} ({})",
+            destination.path.display(),
+            destination.format
+        );
+    }
+    info!("Group by: {}", config.group_by.join(", "));
+    let rules: Vec<String> = config.rules.iter().map(|r| r.to_string()).collect();
+    info!(
+        "Rules: {}",
+        if rules.is_empty() {
+            "none".to_string()
+        } else {
+            rules.join(", ")
+        }
+    );
+    info!("Dedup: {}", config.dedup);
+    info!(
+        "Date column: {} (format {})",
+        config.date_column,
+        if config.date_formats.is_empty() {
+            "auto".to_string()
+        } else {
+            config.date_formats.join(", ")
+        }
+    );
+    match config.top_n {
+        Some(n) => info!("Top N: {}", n),
+        None => info!("Top N: all groups"),
+    }
+    let time_dims: Vec<&str> = config.time_dims.iter().map(|d| d.column()).collect();
+    if let Some(file) = &config.sql_file {
+        info!("Transform: {}", file.path.display());
+    } else if time_dims.is_empty() {
+        info!("Time dimensions: none");
+    } else {
+        info!("Time dimensions: {}", time_dims.join(", "));
+    }
+    let measures: Vec<String> = config.aggregations.iter().map(|a| a.to_string()).collect();
+    info!("Aggregations: {}", measures.join(", "));
+    match config.window {
+        Some(periods) => info!(
+            "Window: revenue averaged over {} period(s) of {}",
+            periods,
+            config.window_order.join(", ")
+        ),
+        None if config.yoy => info!("Year over year: revenue per year against the year before"),
+        None => info!("Order by: {} {}", config.order_by, config.order.sql()),
+    }
+    if !config.renames.is_empty() {
+        let mapping: Vec<String> = config
+            .renames
+            .iter()
+            .map(|r| format!("{} → {}", r.from, r.to))
+            .collect();
+        info!("Renamed columns: {}", mapping.join(", "));
+    }
+    if let Some(sample) = config.sample_description() {
+        warn!("Sampling {}", sample);
+        metrics.sample = config.sample.map(|fraction| (fraction, config.seed));
+    }
+    if let Some(n) = config.limit_rows {
+        warn!("Reading only the first {} input rows", n);
+        metrics.limit_rows = Some(n);
+    }
+
+    if !sql.warmup.is_empty() {
+        let started = Instant::now();
+        for statement in &sql.warmup {
+            conn.execute_batch(statement)
+                .context("Failed to warm up DuckDB on the input")?;
+        }
+        let warmup = started.elapsed();
+        info!(
+            "Warmed up DuckDB in {:.3} s (untimed)",
+            warmup.as_secs_f64()
+        );
+        metrics.exclude_warmup(warmup);
+    }
+
+    metrics.update_memory();
+    metrics.reset_step_clock();
+
+    let cache_key = cache_key_sql(&sql);
+    let cached = match &config.cache {
+        Some(path) => read_cache(path, &cache_key, &input.files)?,
+        None => None,
+    };
+    metrics.cache_hit = config.cache.as_ref().map(|_| cached.is_some());
+    let cleaned = match (&config.cache, cached) {
+        (Some(path), Some(cached)) => load_cached(&conn, config, &sql, metrics, path, cached)?,
+        _ => match load_and_clean(&conn, config, &input, &sql, metrics)? {
+            CleanOutcome::Finished(result) => return Ok(result),
+            CleanOutcome::Cleaned(cleaned) => {
+                if let Some(path) = &config.cache {
+                    info!("Caching cleaned data to {}...", path.display());
+                    write_cache(&conn, path, &cache_key, &input.files, metrics)?;
+                    // The rest of the run reads the cache rather than the input again
+                    conn.execute(&build_cached_clean_sql(path), [])
+                        .context("Failed to read back the cache")?;
+                    metrics.update_memory();
+                    metrics.record_step("Cache");
+                }
+                cleaned
+            }
+        },
+    };
+    let CleanedRows {
+        raw_rows: row_count,
+        cleaned_rows: cleaned_count,
+        removed_rows: removed,
+        profile,
+    } = cleaned;
+
+    if let Some(dedup_sql) = &sql.dedup {
+        info!("Removing duplicate rows...");
+        conn.execute(dedup_sql, [])
+            .context("Failed to deduplicate data")?;
+        let deduped_count: i64 =
+            conn.query_row("SELECT COUNT(*) FROM deduped_data", [], |row| row.get(0))?;
+        log_view_rows(config, "deduped_data", deduped_count);
+        let duplicates = cleaned_count - deduped_count;
+        info!("Removed {} duplicate rows", duplicates);
+        metrics.rows.duplicates = Some(duplicates);
+        metrics.update_memory();
+        metrics.record_step("Dedup");
+    }
+
+    // Step 3: Transform data
+    info!("Transforming data...");
+    if let (Some(rates_sql), Some(path)) = (&sql.rates, &config.rates) {
+        conn.execute(rates_sql, [])
+            .with_context(|| format!("Failed to load exchange rates from {}", path.display()))?;
+        let mut stmt = conn.prepare(DUPLICATE_RATES_SQL)?;
+        let duplicates = stmt
+            .query_map([], |row| row.get::<_, Option<String>>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        if !duplicates.is_empty() {
+            let names: Vec<&str> = duplicates
+                .iter()
+                .map(|c| c.as_deref().unwrap_or("(null)"))
+                .collect();
+            anyhow::bail!(
+                "{} lists more than one rate for: {}",
+                path.display(),
+                names.join(", ")
+            );
+        }
+    }
+    if let (Some(user_sql), Some(file)) = (&sql.user_transform, &config.sql_file) {
+        run_user_transform(&conn, config, file, user_sql)?;
+    }
+    conn.execute(&sql.transform, []).with_context(|| {
+        if config.revenue_expr == RevenueExpr::default() {
+            "Failed to transform data".to_string()
+        } else {
+            format!(
+                "--revenue-expr '{}' failed to compile",
+                config.revenue_expr.sql
+            )
+        }
+    })?;
+
+    if let Some(count_sql) = build_currency_count_sql(config) {
+        let mut stmt = conn.prepare(&count_sql)?;
+        metrics.currencies = stmt
+            .query_map([], |row| {
+                Ok(CurrencyRows {
+                    currency: row.get(0)?,
+                    rows: row.get(1)?,
+                    has_rate: row.get(2)?,
+                })
+            })?
+            .collect::<std::result::Result<_, _>>()
+            .context("Failed to count rows per currency")?;
+        let seen: Vec<String> = metrics
+            .currencies
+            .iter()
+            .map(|c| {
+                format!(
+                    "{} ({} rows)",
+                    c.currency.as_deref().unwrap_or("null"),
+                    c.rows
+                )
+            })
+            .collect();
+        info!("Currencies: {}", seen.join(", "));
+        let unknown: Vec<&CurrencyRows> =
+            metrics.currencies.iter().filter(|c| !c.has_rate).collect();
+        if !unknown.is_empty() {
+            let names: Vec<&str> = unknown
+                .iter()
+                .map(|c| c.currency.as_deref().unwrap_or("null"))
+                .collect();
+            warn!(
+                "{} rows have a currency without a rate ({}); their revenue is left out",
+                unknown.iter().map(|c| c.rows).sum::<i64>(),
+                names.join(", ")
+            );
+        }
+    }
+    info!("Transformations complete");
+    metrics.update_memory();
+    metrics.record_step("Transform");
+    if config.verbose {
+        let transformed: i64 =
+            conn.query_row("SELECT COUNT(*) FROM transformed_data", [], |row| {
+                row.get(0)
+            })?;
+        log_view_rows(config, "transformed_data", transformed);
+        metrics.rows.transformed = Some(transformed);
+        // The extra pass isn't part of any step
+        metrics.reset_step_clock();
+    }
+    dump_view(
+        &conn,
+        config,
+        metrics,
+        "transformed_data",
+        &sql.dump_transform,
+    )?;
+    let histogram = match &config.histogram {
+        Some(spec) => {
+            if !view_columns(&conn, "transformed_data")?.contains(&spec.column) {
+                return Err(failure(
+                    FailureKind::Schema,
+                    format!("Unknown --histogram column: {}", spec.column),
+                ));
+            }
+            let histogram = histogram_view(&conn, "transformed_data", spec)?;
+            // Like --profile, kept out of the step timings
+            metrics.reset_step_clock();
+            Some(histogram)
+        }
+        None => None,
+    };
+
+    // Step 4: Aggregate data
+    info!("Aggregating data...");
+    let available = view_columns(&conn, "transformed_data")?;
+    for (option, columns) in [
+        ("--group-by", &config.group_by[..]),
+        ("--window-order", config.window_periods()),
+    ] {
+        let unknown: Vec<&str> = columns
+            .iter()
+            .filter(|c| !available.contains(c))
+            .map(|c| c.as_str())
+            .collect();
+        if !unknown.is_empty() {
+            return Err(failure(
+                FailureKind::Schema,
+                format!(
+                    "Unknown {} column(s): {} (available: {})",
+                    option,
+                    unknown.join(", "),
+                    available.join(", ")
+                ),
+            ));
+        }
+    }
+    if config.yoy && !available.iter().any(|c| c == YOY_PERIOD) {
+        return Err(failure(
+            FailureKind::Schema,
+            format!(
+                "--yoy needs a {} column (available: {}); add it to --time-dims",
+                YOY_PERIOD,
+                available.join(", ")
+            ),
+        ));
+    }
+    let unknown: Vec<String> = config
+        .aggregations
+        .iter()
+        .filter(|a| a.column != "*" && !available.contains(&a.column))
+        .map(|a| a.to_string())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(failure(
+            FailureKind::Schema,
+            format!(
+                "Unknown --aggregations column(s): {} (available: {})",
+                unknown.join(", "),
+                available.join(", ")
+            ),
+        ));
+    }
+    conn.execute(&sql.aggregate, [])
+        .context("Failed to aggregate data")?;
+
+    metrics.start_progress("Aggregating");
+    let agg_count: Result<i64, _> =
+        conn.query_row("SELECT COUNT(*) FROM aggregated_data", [], |row| row.get(0));
+    metrics.finish_progress();
+    let agg_count = agg_count?;
+    log_view_rows(config, "aggregated_data", agg_count);
+    let groups = agg_count - i64::from(config.with_totals);
+    let mut cap_note = if top_n_dropped_groups(&conn, config, groups)? {
+        format!(" (capped by --top-n {})", groups)
+    } else {
+        String::new()
+    };
+    if config.with_totals {
+        cap_note.push_str(" plus a totals row");
+    }
+    if config.group_by == ["product_id"] {
+        info!("Aggregated to {} products{}", groups, cap_note);
+    } else {
+        info!(
+            "Aggregated to {} groups by {}{}",
+            groups,
+            config.group_by.join(", "),
+            cap_note
+        );
+    }
+    metrics.rows.aggregated = Some(agg_count);
+
+    metrics.update_memory();
+    metrics.record_step("Aggregate");
+    if config.quarterly {
+        let mut stmt = conn.prepare(&build_quarter_revenue_sql())?;
+        let quarters = stmt
+            .query_map([], |row| {
+                Ok(QuarterRevenue {
+                    year: row.get(0)?,
+                    quarter: row.get(1)?,
+                    revenue: row.get(2)?,
+                })
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            .context("Failed to total the revenue per quarter")?;
+        info!("Found {} quarters", quarters.len());
+        metrics.quarters = quarters;
+        metrics.reset_step_clock();
+    }
+
+    let plans = match config.explain {
+        Some(mode) => {
+            info!("Explaining queries...");
+            let plans = explain_steps(&conn, config, mode)?;
+            // Kept out of the step timings, especially for EXPLAIN ANALYZE
+            metrics.reset_step_clock();
+            plans
+        }
+        None => Vec::new(),
+    };
+
+    // Step 5: Save results
+    if let Some(materialize) = &sql.materialize {
+        conn.execute(materialize, [])
+            .context("Failed to compute results")?;
+    }
+    if let Some(expectations) = &config.expect {
+        check_expectations(&conn, expectations, output_source(config), groups)?;
+        metrics.reset_step_clock();
+    }
+    let mut output_files = 0;
+    let mut file_bytes = 0;
+    let destinations = config.destinations();
+    let saves = destinations.iter().zip(&sql.copies).zip(&sql.appends);
+    for ((destination, copy), append) in saves {
+        let output_path = &destination.path;
+        info!(
+            "{} results to {} ({})...",
+            if append.is_some() {
+                "Appending"
+            } else {
+                "Saving"
+            },
+            output_path.display(),
+            destination.format
+        );
+
+        // Everything is written to the pending path and renamed into place
+        // at the end, so a killed run never leaves a truncated output
+        let pending = pending_output_path(output_path);
+        if let Some(dir) = output_path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create output directory")?;
+        }
+        if let Some(column) = config.partition_columns().first() {
+            prepare_partition_dir(output_path, &pending, column)?;
+        }
+
+        if let Some(append) = append {
+            check_append_columns(&conn, config, destination)?;
+            for statement in append {
+                conn.execute(statement, []).with_context(|| {
+                    format!("Failed to append results to {}", output_path.display())
+                })?;
+            }
+            if destination.format == OutputFormat::Csv {
+                append_staged_csv(output_path, &pending)?;
+            }
+        } else if config.streams(destination) {
+            stream_jsonl(&conn, copy, &pending).with_context(|| {
+                format!("Failed to stream results to {}", output_path.display())
+            })?;
+        } else if config.formats_numbers(destination) {
+            // check_output_options made sure the delimiter is one byte
+            write_formatted_csv(
+                &conn,
+                copy,
+                &pending,
+                config.output_delimiter as u8,
+                format_number,
+            )
+            .with_context(|| format!("Failed to save results to {}", output_path.display()))?;
+        } else {
+            conn.execute(copy, [])
+                .with_context(|| format!("Failed to save results to {}", output_path.display()))?;
+        }
+        let partitioned = config.partition_option().is_some();
+        move_into_place(&pending, output_path, partitioned)?;
+
+        let (files, bytes) = if partitioned {
+            partition_files(output_path)?
+        } else {
+            (1, std::fs::metadata(output_path)?.len())
+        };
+        let file_size = bytes as f64 / 1024.0 / 1024.0;
+        if partitioned {
+            metrics.output_files = Some(files);
+            info!(
+                "Results saved ({} partition files, {:.2} MB)",
+                files, file_size
+            );
+        } else {
+            info!("Results saved ({:.2} MB)", file_size);
+        }
+        let (sha256, checksums) = match (config.checksum, partitioned) {
+            (false, _) => (None, Vec::new()),
+            (true, true) => {
+                let (combined, files) = checksum_dir(output_path)?;
+                (Some(combined), files)
+            }
+            (true, false) => (Some(checksum_file(output_path)?), Vec::new()),
+        };
+        metrics.outputs.push(WrittenOutput {
+            path: output_path.to_string_lossy().into_owned(),
+            format: destination.format.to_string(),
+            bytes,
+            sha256,
+            files: checksums,
+        });
+        output_files += files;
+        file_bytes += bytes;
+    }
+    if let Some(target) = &config.to_table {
+        write_table(&conn, config, &sql.table)?;
+        info!("Inserted {} rows into table {}", agg_count, target);
+        metrics.table = Some(WrittenTable {
+            database: target.database.to_string_lossy().into_owned(),
+            table: target.table.clone(),
+            rows: agg_count,
+        });
+    }
+    let file_bytes = (!destinations.is_empty()).then_some(file_bytes);
+    metrics.output_size_bytes = file_bytes;
+    if let Some(path) = &config.state_file {
+        let latest: Option<String> = conn
+            .query_row(&build_max_date_sql(config), [], |row| row.get(0))
+            .context("Failed to find the latest processed date")?;
+        // A run with nothing new keeps the previous watermark
+        if let Some(latest) = latest {
+            let latest = NaiveDate::parse_from_str(&latest, "%Y-%m-%d")
+                .with_context(|| format!("Latest processed date '{}' isn't a date", latest))?;
+            write_watermark(path, latest)?;
+            info!("Watermark {} saved to {}", latest, path.display());
+        }
+    }
+
+    metrics.update_memory();
+    metrics.record_step("Save");
+    record_spill(&conn, metrics)?;
+
+    Ok(PipelineResult {
+        raw_rows: row_count,
+        cleaned_rows: cleaned_count,
+        removed_rows: removed,
+        aggregated_rows: agg_count,
+        duration: metrics.start_time.elapsed(),
+        peak_memory_mb: metrics.peak_memory_mb(),
+        output_size_bytes: file_bytes,
+        output_files,
+        plans,
+        profile,
+        histogram,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::failure::failure_kind;
+    use crate::sql::sql_string;
+    use duckdb::params;
+    use std::path::Path;
+
+    const SALES_CSV: &str = "date,product_id,quantity,price\n\
+                             2023-01-05,P1,2,10.0\n\
+                             2023-02-05,P1,1,10.0\n\
+                             2023-03-05,P2,4,2.5\n\
+                             2023-03-06,P3,0,1.0\n";
+
+    /// Writes the sales fixture into `<dir>/data` and returns a config reading it.
+    fn fixture_config(dir: &Path) -> PipelineConfig {
+        let data_dir = dir.join("data");
+        std::fs::create_dir(&data_dir).unwrap();
+        std::fs::write(data_dir.join("sales.csv"), SALES_CSV).unwrap();
+        PipelineConfig {
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            output_path: dir.join("out").to_str().unwrap().to_string(),
+            ..PipelineConfig::default()
+        }
+    }
+
+    #[test]
+    fn parquet_output_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            format: OutputFormat::Parquet,
+            ..fixture_config(dir.path())
+        };
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(
+            (
+                result.raw_rows,
+                result.cleaned_rows,
+                result.removed_rows,
+                result.aggregated_rows
+            ),
+            (4, 3, 1, 2)
+        );
+
+        let parquet_path = dir.path().join("out.parquet");
+        assert!(parquet_path.exists());
+
+        let conn = Connection::open_in_memory().unwrap();
+        let (products, quantity, revenue): (i64, f64, f64) = conn
+            .query_row(
+                "SELECT COUNT(*), SUM(total_quantity)::DOUBLE, SUM(total_revenue) FROM read_parquet(?)",
+                params![parquet_path.to_str().unwrap()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(products, 2);
+        assert_eq!(quantity, 7.0);
+        assert_eq!(revenue, 40.0);
+    }
+
+    #[test]
+    fn groups_by_requested_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            group_by: vec!["year".to_string(), "quarter".to_string()],
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.aggregated, Some(1));
+
+        let config = PipelineConfig {
+            group_by: vec!["region".to_string()],
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unknown --group-by column(s): region"));
+    }
+
+    #[test]
+    fn missing_columns_fail_before_cleaning() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "order_date,sku,quantity,price\n2023-01-05,P1,2,10.0\n",
+        )
+        .unwrap();
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Input is missing required column(s): product_id, date"));
+    }
+
+    #[test]
+    fn empty_input_skips_output_unless_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price\n",
+        )
+        .unwrap();
+        let output = dir.path().join("out.csv");
+
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(result.output_size_bytes, None);
+        assert!(!output.exists());
+
+        let config = PipelineConfig {
+            allow_empty: true,
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.removed, Some(0));
+        assert_eq!(metrics.rows.aggregated, Some(0));
+        assert_eq!(
+            result.output_size_bytes,
+            Some(std::fs::metadata(&output).unwrap().len())
+        );
+        let written = std::fs::read_to_string(output).unwrap();
+        assert_eq!(
+            written.trim(),
+            "product_id,total_quantity,total_revenue,avg_price"
+        );
+    }
+
+    #[test]
+    fn column_type_overrides_parse_and_count_failures() {
+        let ColumnTypes(types) =
+            parse_column_types("price=decimal(10,2), quantity=BIGINT").unwrap();
+        assert_eq!(types[0].sql_type, "DECIMAL(10,2)");
+        assert_eq!(types[1].column, "quantity");
+        assert!(parse_column_types("price").is_err());
+        assert!(parse_column_types("price=DOUBLE'); --").is_err());
+        assert!(parse_column_types("price=DOUBLE) IS NULL OR (1").is_err());
+        assert!(parse_column_types("price=DECIMAL(10,2,3)").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            column_types: vec![ColumnType {
+                column: "price".to_string(),
+                sql_type: "DOUBLE".to_string(),
+            }],
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!("{}2023-03-07,P2,1,\"1,000\"\n", SALES_CSV),
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.cast_failures[0].rows, 1);
+        assert_eq!(metrics.rows.raw, Some(5));
+        assert_eq!(metrics.rows.removed, Some(2));
+
+        let ColumnTypes(unknown) = parse_column_types("nosuch=DOUBLE").unwrap();
+        let config = PipelineConfig {
+            column_types: unknown,
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("missing required column(s): nosuch"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn revenue_expr_is_validated_and_applied() {
+        assert!(parse_revenue_expr("price; DROP TABLE raw_data").is_err());
+        assert!(parse_revenue_expr("(SELECT 1)").is_err());
+        assert!(parse_revenue_expr("quantity * 'x'").is_err());
+        assert!(parse_revenue_expr("round(quantity * price").is_err());
+        let expr = parse_revenue_expr("round(quantity * price * (1 - discount), 2)").unwrap();
+        assert_eq!(expr.columns, ["quantity", "price", "discount"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            revenue_expr: expr,
+            ..fixture_config(dir.path())
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("discount"), "{}", err);
+
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price,discount\n2023-01-05,P1,2,10.0,0.25\n",
+        )
+        .unwrap();
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert!(
+            output.lines().nth(1).unwrap().contains(",15.0,"),
+            "{}",
+            output
+        );
+
+        let config = PipelineConfig {
+            revenue_expr: parse_revenue_expr("abs(product_id)").unwrap(),
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("failed to compile"), "{}", err);
+    }
+
+    #[test]
+    fn dedup_drops_repeated_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!("{}2023-01-05,P1,2,10.0\n2023-04-01,P2,9,2.5\n", SALES_CSV),
+        )
+        .unwrap();
+
+        let full_row = PipelineConfig {
+            dedup: Dedup::FullRow,
+            ..config.clone()
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&full_row, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.duplicates, Some(1));
+
+        let by_product = PipelineConfig {
+            dedup: Dedup::OnColumns(vec!["product_id".to_string()]),
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&by_product, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.duplicates, Some(3));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().nth(1).unwrap(), "P1,2,20.0,10.0");
+        assert_eq!(output.lines().nth(2).unwrap(), "P2,4,10.0,2.5");
+    }
+
+    #[test]
+    fn custom_date_column_and_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(&data_dir).unwrap();
+        std::fs::write(
+            data_dir.join("sales.csv"),
+            "order_date,product_id,quantity,price\n\
+             01/05/2023,P1,2,10.0\n\
+             04/20/2023,P1,1,10.0\n\
+             2023-05-01,P2,1,1.0\n\
+             13/45/2023,P2,1,1.0\n",
+        )
+        .unwrap();
+        let config = PipelineConfig {
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            output_path: dir.path().join("out").to_str().unwrap().to_string(),
+            rules: default_rules("order_date"),
+            date_column: "order_date".to_string(),
+            date_formats: vec!["%m/%d/%Y".to_string()],
+            diagnose: true,
+            group_by: vec!["quarter".to_string()],
+            ..PipelineConfig::default()
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.removed, Some(2));
+        assert_eq!(metrics.removed_by_rule[3].rows, 2);
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["1,2,20.0,10.0", "2,1,10.0,10.0"]
+        );
+
+        // Each row is credited to the first format that parses it
+        let mixed = PipelineConfig {
+            date_formats: vec!["%m/%d/%Y".to_string(), "%Y-%m-%d".to_string()],
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&mixed, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.removed, Some(1));
+        let matched: Vec<i64> = metrics.date_formats.iter().map(|m| m.rows).collect();
+        assert_eq!(matched, [2, 1]);
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().nth(2).unwrap(), "2,2,11.0,5.5");
+    }
+
+    #[test]
+    fn top_n_keeps_highest_revenue_groups() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            top_n: Some(1),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.aggregated, Some(1));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["P1,3,30.0,10.0"]
+        );
+
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE VIEW transformed_data AS SELECT * FROM (VALUES ('P1'), ('P2')) t(product_id)",
+        )
+        .unwrap();
+        assert!(top_n_dropped_groups(&conn, &config, 1).unwrap());
+        let exact = PipelineConfig {
+            top_n: Some(2),
+            ..config
+        };
+        assert!(!top_n_dropped_groups(&conn, &exact, 2).unwrap());
+    }
+
+    #[test]
+    fn only_io_errors_are_retried() {
+        let conn = Connection::open_in_memory().unwrap();
+        let mut attempts = 0;
+        let err = retry_io(2, Duration::from_millis(1), || {
+            attempts += 1;
+            conn.execute(
+                "CREATE VIEW v AS SELECT * FROM read_csv_auto('/nonexistent/x.csv')",
+                [],
+            )
+        })
+        .unwrap_err();
+        assert!(is_io_error(&err), "{}", err);
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        retry_io(2, Duration::from_millis(1), || {
+            attempts += 1;
+            conn.execute("CREATE VIEW v AS SELECT missing_column", [])
+        })
+        .unwrap_err();
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn reads_gzipped_csv_alongside_plain_csv() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let archive = Path::new(&config.data_dir).join("archive.csv.gz");
+        Connection::open_in_memory()
+            .unwrap()
+            .execute(
+                &format!(
+                    "COPY (SELECT '2022-12-01' AS date, 'P9' AS product_id, 1 AS quantity, 5.0 AS price) \
+                     TO {} (HEADER, COMPRESSION gzip)",
+                    sql_string(archive.to_str().unwrap())
+                ),
+                [],
+            )
+            .unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        let input = discover_input(&conn, &config).unwrap();
+        assert_eq!(input.file_count, 2);
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.raw, Some(5));
+        assert_eq!(metrics.rows.aggregated, Some(3));
+    }
+
+    #[test]
+    fn quantiles_become_price_columns() {
+        assert_eq!(quantile_column(0.5), "price_p50");
+        assert_eq!(quantile_column(0.995), "price_p99_5");
+        assert!(parse_quantile("1.5").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            quantiles: vec![0.0, 1.0],
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().take(2).collect::<Vec<_>>(),
+            [
+                "product_id,total_quantity,total_revenue,avg_price,price_p0,price_p100",
+                "P1,3,30.0,10.0,10.0,10.0",
+            ]
+        );
+    }
+
+    #[test]
+    fn partitioned_parquet_writes_a_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            format: OutputFormat::Parquet,
+            group_by: vec!["month".to_string(), "product_id".to_string()],
+            partition_by: Some("month".to_string()),
+            checksum: true,
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(result.output_files, 3);
+        assert_eq!(metrics.output_files, Some(3));
+        assert_eq!(metrics.outputs[0].files.len(), 3);
+        assert!(metrics.outputs[0].files[0].path.starts_with("month=1/"));
+        assert!(dir.path().join("out").join("month=3").is_dir());
+        // A rerun over fewer months replaces the directory's partitions
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price\n2023-01-05,P1,2,10.0\n",
+        )
+        .unwrap();
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(result.output_files, 1);
+        assert!(!dir.path().join("out").join("month=3").exists());
+        assert!(!dir.path().join("out.tmp").exists());
+        assert!(!dir.path().join("out.old").exists());
+        // Files that aren't partitions are never deleted
+        std::fs::write(dir.path().join("out").join("notes.txt"), "keep").unwrap();
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("notes.txt"), "{}", err);
+        assert!(dir.path().join("out").join("month=1").is_dir());
+
+        let csv = PipelineConfig {
+            format: OutputFormat::Csv,
+            ..config.clone()
+        };
+        assert!(run_pipeline(&csv, &mut PipelineMetrics::new()).is_err());
+        let ungrouped = PipelineConfig {
+            group_by: vec!["product_id".to_string()],
+            ..config
+        };
+        let err = dry_run_statements(&ungrouped).unwrap_err();
+        assert!(err.to_string().contains("--group-by"), "{}", err);
+    }
+
+    #[test]
+    fn null_strategy_imputes_instead_of_dropping() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            null_strategy: NullStrategy::Mean,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!("{}2023-03-07,P2,,\n", SALES_CSV),
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!((result.cleaned_rows, result.removed_rows), (5, 0));
+        let imputed: Vec<(&str, i64)> = metrics
+            .imputed
+            .iter()
+            .map(|i| (i.column.as_str(), i.rows))
+            .collect();
+        assert_eq!(imputed, [("quantity", 2), ("price", 1)]);
+        let csv = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        // P3's quantity of 0 fails `quantity:positive` and becomes the mean of 2, 1 and 4
+        assert!(csv.contains("\nP3,2.333"), "{}", csv);
+    }
+
+    #[test]
+    fn output_delimiter_writes_tsv() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            output_delimiter: '\t',
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let csv = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert!(csv.starts_with("product_id\ttotal_quantity\t"), "{}", csv);
+
+        let parquet = PipelineConfig {
+            format: OutputFormat::Parquet,
+            ..config
+        };
+        assert!(dry_run_statements(&parquet).is_err());
+    }
+
+    #[test]
+    fn explain_captures_a_plan_per_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            explain: Some(ExplainMode::Analyze),
+            ..fixture_config(dir.path())
+        };
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let steps: Vec<&str> = result.plans.iter().map(|p| p.step.as_str()).collect();
+        assert_eq!(steps, ["Load", "Clean", "Transform", "Aggregate"]);
+        assert!(
+            result.plans[3].plan.contains("HASH_GROUP_BY"),
+            "{}",
+            result.plans[3].plan
+        );
+
+        let plain = PipelineConfig {
+            explain: None,
+            ..config
+        };
+        assert!(run_pipeline(&plain, &mut PipelineMetrics::new())
+            .unwrap()
+            .plans
+            .is_empty());
+    }
+
+    #[test]
+    fn explicit_delimiter_and_quote() {
+        assert_eq!(parse_csv_char("\\t"), Ok('\t'));
+        assert_eq!(parse_csv_char(";"), Ok(';'));
+        assert!(parse_csv_char(";;").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            csv_delimiter: Some(';'),
+            csv_quote: Some('\''),
+            column_types: vec![ColumnType {
+                column: "price".to_string(),
+                sql_type: "DOUBLE".to_string(),
+            }],
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date;product_id;quantity;price\n2023-01-05;'P1;a';2;10.5\n2023-01-06;P2;1;2\n",
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.cast_failures[0].rows, 0);
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().nth(1).unwrap(), "P1;a,2,21.0,10.5");
+    }
+
+    #[test]
+    fn profile_reports_raw_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            profile: true,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!("{}2023-03-07,,5,\n", SALES_CSV),
+        )
+        .unwrap();
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let columns: Vec<&str> = result.profile.iter().map(|p| p.column.as_str()).collect();
+        assert_eq!(columns, ["date", "product_id", "quantity", "price"]);
+        let (product, quantity) = (&result.profile[1], &result.profile[2]);
+        assert_eq!((product.nulls, product.min.as_deref()), (1, None));
+        assert_eq!(
+            (
+                quantity.nulls,
+                quantity.min.as_deref(),
+                quantity.max.as_deref()
+            ),
+            (0, Some("0"), Some("5"))
+        );
+        assert_eq!(result.profile[3].nulls, 1);
+    }
+
+    #[test]
+    fn dry_run_lists_statements_without_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            threads: Some(2),
+            ..fixture_config(dir.path())
+        };
+        let statements = dry_run_statements(&config).unwrap();
+        assert_eq!(statements.len(), 6);
+        assert_eq!(statements[0], "PRAGMA threads=2");
+        assert!(statements[1].contains("read_csv_auto("));
+        assert!(statements[5].starts_with("COPY aggregated_data TO"));
+        assert!(!dir.path().join("out.csv").exists());
+
+        let allow_empty = PipelineConfig {
+            allow_empty: true,
+            ..config
+        };
+        let statements = dry_run_statements(&allow_empty).unwrap();
+        assert!(statements[2].starts_with("-- Only when the input has no rows\n"));
+        assert!(statements[2].contains("TRY_CAST(quantity AS DOUBLE)"));
+    }
+
+    #[test]
+    fn reads_parquet_and_json_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_config = fixture_config(dir.path());
+        let conn = Connection::open_in_memory().unwrap();
+        for (subdir, options) in [("parquet", "FORMAT PARQUET"), ("json", "FORMAT JSON")] {
+            let data_dir = dir.path().join(subdir);
+            std::fs::create_dir(&data_dir).unwrap();
+            conn.execute_batch(&format!(
+                "COPY (SELECT * FROM read_csv_auto('{}/sales.csv')) TO '{}/sales.{}' ({})",
+                csv_config.data_dir,
+                data_dir.display(),
+                subdir,
+                options
+            ))
+            .unwrap();
+
+            let config = PipelineConfig {
+                data_dir: data_dir.to_str().unwrap().to_string(),
+                ..csv_config.clone()
+            };
+            let mut metrics = PipelineMetrics::new();
+            run_pipeline(&config, &mut metrics).unwrap();
+            assert_eq!(metrics.rows.raw, Some(4));
+            assert_eq!(metrics.rows.aggregated, Some(2));
+        }
+
+        std::fs::copy(
+            dir.path().join("parquet/sales.parquet"),
+            dir.path().join("data/sales.parquet"),
+        )
+        .unwrap();
+        let err = run_pipeline(&csv_config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("mixes CSV and Parquet"));
+    }
+
+    #[test]
+    fn dump_intermediate_writes_views_as_parquet() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumps = dir.path().join("dumps");
+        let config = PipelineConfig {
+            dump_intermediate: Some(dumps.clone()),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let views: Vec<&str> = metrics.dumped.iter().map(|d| d.view.as_str()).collect();
+        assert_eq!(views, ["cleaned_data", "transformed_data"]);
+        assert!(metrics.dumped.iter().all(|d| d.bytes > 0));
+
+        let conn = Connection::open_in_memory().unwrap();
+        let (rows, revenue): (i64, f64) = conn
+            .query_row(
+                "SELECT COUNT(*), SUM(revenue) FROM read_parquet(?)",
+                params![dumps.join("transformed_data.parquet").to_str().unwrap()],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((rows, revenue), (3, 40.0));
+        assert!(dry_run_statements(&config)
+            .unwrap()
+            .iter()
+            .any(|s| s.starts_with("COPY cleaned_data TO")));
+    }
+
+    #[test]
+    fn business_filters_are_reported_apart_from_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            exclude_products: Some("TEST_*".to_string()),
+            max_price: Some(1_000_000.0),
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!(
+                "{}2023-03-07,TEST_1,1,5.0\n2023-03-08,P2,1,2000000\n2023-03-09,TEST_2,0,1.0\n",
+                SALES_CSV
+            ),
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!((result.cleaned_rows, result.removed_rows), (3, 4));
+        // TEST_2 fails quantity:positive, so only the validity rules count it
+        assert_eq!(metrics.rows.filtered, Some(2));
+        // Per-rule counts are only taken with --diagnose
+        assert!(metrics.removed_by_rule.is_empty());
+        let filtered: Vec<(&str, i64)> = metrics
+            .removed_by_filter
+            .iter()
+            .map(|f| (f.rule.as_str(), f.rows))
+            .collect();
+        assert_eq!(
+            filtered,
+            [("exclude-products:TEST_*", 1), ("max-price:1000000", 1)]
+        );
+    }
+
+    #[test]
+    fn streamed_jsonl_matches_copy_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let copied = PipelineConfig {
+            format: OutputFormat::JsonLines,
+            group_by: vec!["product_id".to_string(), "month".to_string()],
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&copied, &mut PipelineMetrics::new()).unwrap();
+        let streamed = PipelineConfig {
+            stream_jsonl: true,
+            output_path: dir.path().join("streamed").to_str().unwrap().to_string(),
+            ..copied.clone()
+        };
+        run_pipeline(&streamed, &mut PipelineMetrics::new()).unwrap();
+        let read = |name: &str| -> Vec<serde_json::Value> {
+            std::fs::read_to_string(dir.path().join(name))
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        };
+        assert_eq!(read("streamed.jsonl"), read("out.jsonl"));
+        assert_eq!(read("streamed.jsonl").len(), 3);
+
+        let csv = PipelineConfig {
+            format: OutputFormat::Csv,
+            ..streamed
+        };
+        assert!(dry_run_statements(&csv).is_err());
+    }
+
+    #[test]
+    fn sample_is_reproducible_with_a_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut csv = String::from("date,product_id,quantity,price\n");
+        for i in 0..2000 {
+            csv.push_str(&format!("2023-01-05,P{},1,1.0\n", i));
+        }
+        let config = PipelineConfig {
+            sample: Some(0.1),
+            seed: 7,
+            threads: Some(4),
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(Path::new(&config.data_dir).join("sales.csv"), csv).unwrap();
+        let sampled = |config: &PipelineConfig| {
+            let mut metrics = PipelineMetrics::new();
+            run_pipeline(config, &mut metrics).unwrap();
+            assert_eq!(metrics.report(None).sample_fraction, Some(0.1));
+            std::fs::read_to_string(dir.path().join("out.csv")).unwrap()
+        };
+        let first = sampled(&config);
+        let rows = first.lines().count() - 1;
+        assert!((100..300).contains(&rows), "{} rows", rows);
+        assert_eq!(sampled(&config), first);
+        assert_ne!(sampled(&PipelineConfig { seed: 8, ..config }), first);
+        assert!(parse_sample_fraction("0").is_err());
+    }
+
+    #[test]
+    fn append_adds_rows_and_rejects_other_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv = PipelineConfig {
+            append: true,
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&csv, &mut PipelineMetrics::new()).unwrap();
+        run_pipeline(&csv, &mut PipelineMetrics::new()).unwrap();
+        let written = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 5, "{}", written);
+        assert_eq!(lines[1..3], lines[3..]);
+        assert!(!lines[3].starts_with("product_id"));
+        assert!(!dir.path().join("out.csv.append").exists());
+
+        let parquet = PipelineConfig {
+            format: OutputFormat::Parquet,
+            ..csv
+        };
+        run_pipeline(&parquet, &mut PipelineMetrics::new()).unwrap();
+        run_pipeline(&parquet, &mut PipelineMetrics::new()).unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        let rows: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM read_parquet(?)",
+                params![dir.path().join("out.parquet").to_str().unwrap()],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rows, 4);
+
+        let regrouped = PipelineConfig {
+            group_by: vec!["product_id".to_string(), "month".to_string()],
+            ..parquet
+        };
+        let err = run_pipeline(&regrouped, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("out.parquet: its columns (product_id, total_quantity"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn columns_projects_cleaned_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            columns: vec!["region".to_string()],
+            group_by: vec!["region".to_string(), "month".to_string()],
+            null_strategy: NullStrategy::Zero,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price,region,note\n\
+             2023-01-05,P1,2,10.0,EU,a\n\
+             2023-01-06,P2,,5.0,EU,b\n",
+        )
+        .unwrap();
+        let statements = dry_run_statements(&config).unwrap();
+        let clean = statements
+            .iter()
+            .find(|s| s.contains("VIEW cleaned_data"))
+            .unwrap();
+        assert!(
+            clean.contains(r#"SELECT "region", "product_id", CASE WHEN"#),
+            "{}",
+            clean
+        );
+        assert!(!clean.contains("note"));
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(result.aggregated_rows, 1);
+
+        let unknown = PipelineConfig {
+            columns: vec!["store".to_string()],
+            ..config
+        };
+        let err = run_pipeline(&unknown, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("missing required column(s): store"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unwritable_output_fails_before_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let blocker = dir.path().join("blocker");
+        std::fs::write(&blocker, "").unwrap();
+        let config = PipelineConfig {
+            output_path: blocker.join("out").to_str().unwrap().to_string(),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        let err = run_pipeline(&config, &mut metrics).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Failed to create output directory"),
+            "{}",
+            err
+        );
+        assert!(metrics.report(None).steps.is_empty());
+    }
+
+    #[test]
+    fn rates_convert_revenue_and_flag_unknown_currencies() {
+        let dir = tempfile::tempdir().unwrap();
+        let rates = dir.path().join("rates.csv");
+        std::fs::write(&rates, "currency,rate\nUSD,1.0\nEUR,2.0\n").unwrap();
+        let config = PipelineConfig {
+            rates: Some(rates.clone()),
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price,currency\n\
+             2023-01-05,P1,2,10.0,USD\n\
+             2023-01-06,P1,1,10.0,EUR\n\
+             2023-01-07,P1,1,10.0,GBP\n",
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().nth(1), Some("P1,4,40.0,10.0"));
+        let currencies: Vec<(&str, i64, bool)> = metrics
+            .currencies
+            .iter()
+            .map(|c| (c.currency.as_deref().unwrap(), c.rows, c.has_rate))
+            .collect();
+        assert_eq!(
+            currencies,
+            [("EUR", 1, true), ("GBP", 1, false), ("USD", 1, true)]
+        );
+
+        std::fs::write(&rates, "currency,rate\nUSD,1.0\nUSD,1.1\n").unwrap();
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("lists more than one rate for: USD"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn fail_on_removed_pct_gates_the_clean_step() {
+        let dir = tempfile::tempdir().unwrap();
+        // Cleaning drops one of the fixture's four rows
+        let config = PipelineConfig {
+            fail_on_removed_pct: Some(25.0),
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+
+        let strict = PipelineConfig {
+            fail_on_removed_pct: Some(20.0),
+            ..config
+        };
+        let err = run_pipeline(&strict, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cleaning removed 25.00% of rows (4 raw, 3 cleaned), more than --fail-on-removed-pct 20"
+        );
+    }
+
+    #[test]
+    fn validate_only_stops_after_cleaning() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            validate_only: true,
+            fail_on_removed_pct: Some(25.0),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+
+        assert_eq!((result.cleaned_rows, result.removed_rows), (3, 1));
+        assert_eq!(
+            (result.aggregated_rows, result.output_size_bytes),
+            (0, None)
+        );
+        assert!(!Path::new(&config.output_path).exists());
+        assert!(!metrics.removed_by_rule.is_empty());
+        let report = metrics.report(None);
+        let steps: Vec<&str> = report.steps.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(steps, ["Load", "Clean"]);
+    }
+
+    #[test]
+    fn load_errors_name_the_failing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let bad = Path::new(&config.data_dir).join("z_extra.csv");
+        std::fs::write(
+            &bad,
+            "date,product_id,quantity,price,note\n2024-01-01,P1,1,2.0,x\n",
+        )
+        .unwrap();
+
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "1 of 2 CSV files failed to load: {} (columns date, product_id, quantity, price, note \
+                 instead of {})",
+                bad.display(),
+                SALES_CSV.lines().next().unwrap().replace(',', ", ")
+            )
+        );
+    }
+
+    #[test]
+    fn order_by_sorts_the_output_and_picks_the_top_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            order_by: "total_quantity".to_string(),
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["P2,4,10.0,2.5", "P1,3,30.0,10.0"]
+        );
+
+        let ascending = PipelineConfig {
+            order: SortOrder::Asc,
+            top_n: Some(1),
+            ..config.clone()
+        };
+        run_pipeline(&ascending, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["P1,3,30.0,10.0"]
+        );
+
+        let unknown = PipelineConfig {
+            order_by: "revenue".to_string(),
+            ..config
+        };
+        let err = run_pipeline(&unknown, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--order-by column 'revenue' must be one of the output columns \
+             (product_id, total_quantity, total_revenue, avg_price)"
+        );
+    }
+
+    #[test]
+    fn with_totals_appends_a_grand_total_after_the_top_n() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            with_totals: true,
+            top_n: Some(1),
+            ..fixture_config(dir.path())
+        };
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(result.aggregated_rows, 2);
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["P1,3,30.0,10.0", ",7,40.0,7.5"]
+        );
+
+        let ascending = PipelineConfig {
+            order: SortOrder::Asc,
+            top_n: None,
+            ..config
+        };
+        run_pipeline(&ascending, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["P2,4,10.0,2.5", "P1,3,30.0,10.0", ",7,40.0,7.5"]
+        );
+    }
+
+    #[test]
+    fn limit_rows_reads_the_first_rows_in_file_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut csv = String::from("date,product_id,quantity,price\n");
+        for i in 0..5000 {
+            csv.push_str(&format!("2023-01-05,P{},1,1.0\n", i));
+        }
+        let config = PipelineConfig {
+            limit_rows: Some(3),
+            threads: Some(4),
+            order_by: "product_id".to_string(),
+            order: SortOrder::Asc,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(Path::new(&config.data_dir).join("sales.csv"), csv).unwrap();
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+
+        assert_eq!(result.raw_rows, 3);
+        assert_eq!(metrics.report(None).limit_rows, Some(3));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        let products: Vec<&str> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(products, ["P0", "P1", "P2"]);
+    }
+
+    #[test]
+    fn temp_dir_is_set_and_spilling_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let spill_dir = dir.path().join("it's spill");
+        let config = PipelineConfig {
+            temp_dir: Some(spill_dir.clone()),
+            ..fixture_config(dir.path())
+        };
+        let statements = dry_run_statements(&config).unwrap();
+        assert_eq!(
+            statements[0],
+            format!(
+                "PRAGMA temp_directory='{}'",
+                spill_dir.display().to_string().replace('\'', "''")
+            )
+        );
+
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.report(None).spilled_bytes, Some(0));
+    }
+
+    #[test]
+    fn rename_maps_other_systems_column_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let renames = parse_rename("qty=quantity")
+            .into_iter()
+            .chain(parse_rename(" unit_price = price "))
+            .collect();
+        let config = PipelineConfig {
+            renames,
+            ..fixture_config(dir.path())
+        };
+        let csv = SALES_CSV.replacen("quantity,price", "qty,unit_price", 1);
+        std::fs::write(Path::new(&config.data_dir).join("sales.csv"), csv).unwrap();
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert!(output.contains("P1,3,30.0,10.0"), "{}", output);
+
+        let mut missing = config.clone();
+        missing.renames[0].from = "units".to_string();
+        let err = run_pipeline(&missing, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("not in the input: units"),
+            "{:#}",
+            err
+        );
+
+        let mut clash = config;
+        clash.renames[1].to = "product_id".to_string();
+        let err = run_pipeline(&clash, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("'product_id'"), "{:#}", err);
+        assert!(parse_rename("qty").is_err());
+    }
+
+    #[test]
+    fn bom_prefixed_csv_header_is_read_as_product_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let data_dir = Path::new(&config.data_dir);
+        std::fs::write(
+            data_dir.join("sales.csv"),
+            "\u{feff}product_id,date,quantity,price\n\
+             P1,2023-01-05,2,10.0\n\
+             ,2023-01-06,1,10.0\n",
+        )
+        .unwrap();
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        // The row without a product_id is only removed if the column matched
+        assert_eq!((result.raw_rows, result.cleaned_rows), (2, 1));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert!(output.starts_with("product_id,"), "{}", output);
+
+        let utf16: Vec<u8> = "\u{feff}product_id,date\n"
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        std::fs::write(data_dir.join("sales.csv"), utf16).unwrap();
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err.to_string().contains("is UTF-16 encoded"), "{:#}", err);
+    }
+
+    #[test]
+    fn to_table_creates_replaces_and_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("bi").join("sales.duckdb");
+        let config = PipelineConfig {
+            to_table: Some(
+                parse_table_destination(&format!("{}:sales", database.display())).unwrap(),
+            ),
+            ..fixture_config(dir.path())
+        };
+        let rows = || {
+            let conn = Connection::open(&database).unwrap();
+            conn.query_row("SELECT COUNT(*) FROM sales", [], |row| row.get::<_, i64>(0))
+                .unwrap()
+        };
+        let mut metrics = PipelineMetrics::new();
+        let result = run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(rows(), 2);
+        assert_eq!(metrics.report(None).table.unwrap().rows, 2);
+        assert_eq!(result.output_size_bytes, None);
+        assert!(!dir.path().join("out.csv").exists());
+
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("--replace or --append"),
+            "{:#}",
+            err
+        );
+
+        let replace = PipelineConfig {
+            replace_table: true,
+            ..config.clone()
+        };
+        run_pipeline(&replace, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(rows(), 2);
+
+        let append = PipelineConfig {
+            append: true,
+            ..config
+        };
+        run_pipeline(&append, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(rows(), 4);
+    }
+
+    #[test]
+    fn aggregations_spec_builds_the_measures() {
+        let dir = tempfile::tempdir().unwrap();
+        let aggregations = ["count:*", "max:price", "median:quantity"]
+            .map(|spec| parse_aggregation(spec).unwrap())
+            .to_vec();
+        let config = PipelineConfig {
+            aggregations,
+            order_by: "row_count".to_string(),
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "product_id,row_count,max_price,median_quantity",
+                "P1,2,10.0,1.5",
+                "P2,1,2.5,4.0"
+            ]
+        );
+
+        let unknown = PipelineConfig {
+            aggregations: vec![parse_aggregation("sum:discount").unwrap()],
+            order_by: "total_discount".to_string(),
+            ..config
+        };
+        let err = run_pipeline(&unknown, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("column(s): sum:discount"),
+            "{:#}",
+            err
+        );
+        assert!(parse_aggregation("mode:price").is_err());
+        assert!(parse_aggregation("sum:*").is_err());
+    }
+
+    #[test]
+    fn explain_removed_writes_rows_with_their_reasons() {
+        let dir = tempfile::tempdir().unwrap();
+        let sample = dir.path().join("removed").join("rows.csv");
+        let config = PipelineConfig {
+            explain_removed: Some(20),
+            explain_removed_to: Some(sample.clone()),
+            max_price: Some(5.0),
+            ..fixture_config(dir.path())
+        };
+        let csv = format!("{}2023-13-01,,-1,3.0\n", SALES_CSV);
+        std::fs::write(Path::new(&config.data_dir).join("sales.csv"), csv).unwrap();
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let written = std::fs::read_to_string(&sample).unwrap();
+        assert_eq!(
+            written.lines().collect::<Vec<_>>(),
+            [
+                "removal_reasons,date,product_id,quantity,price",
+                "max-price:5,2023-01-05,P1,2,10.0",
+                "max-price:5,2023-02-05,P1,1,10.0",
+                "non-positive quantity,2023-03-06,P3,0,1.0",
+                "null product_id; non-positive quantity; invalid date,2023-13-01,,-1,3.0"
+            ]
+        );
+    }
+
+    #[test]
+    fn glob_patterns_normalize_path_separators() {
+        assert_eq!(
+            glob_base_for(r"C:\Users\analyst\sales data\", true),
+            "C:/Users/analyst/sales data"
+        );
+        assert_eq!(glob_base_for(r"D:\", true), "D:");
+        assert_eq!(
+            glob_base_for(r"\\fileserver\share\data", true),
+            "//fileserver/share/data"
+        );
+        assert_eq!(glob_base_for(r"odd\name/", false), r"odd\name");
+        assert_eq!(glob_base_for("/", false), "");
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let config = PipelineConfig {
+            data_dir: format!("{}/", config.data_dir),
+            ..config
+        };
+        assert!(config
+            .input_pattern(&config.data_dir, "csv")
+            .ends_with("data/*.csv"));
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        assert_eq!(result.raw_rows, 4);
+    }
+
+    #[test]
+    fn warmup_db_runs_before_the_timed_steps() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            warmup_db: true,
+            ..fixture_config(dir.path())
+        };
+        let statements = dry_run_statements(&config).unwrap();
+        let load = statements.iter().position(|s| s.contains("VIEW raw_data"));
+        let warmup = statements.iter().position(|s| s.ends_with("LIMIT 0"));
+        assert!(warmup.unwrap() < load.unwrap(), "{:?}", statements);
+
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let report = metrics.report(None);
+        assert!(report.warmup_secs.unwrap() > 0.0);
+        assert_eq!(report.steps[0].name, "Load");
+    }
+
+    #[test]
+    fn time_dims_derive_week_and_day_columns_from_a_formatted_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir_all(&data_dir).unwrap();
+        std::fs::write(
+            data_dir.join("sales.csv"),
+            "order_date,product_id,quantity,price\n\
+             05.01.2023,P1,2,10.0\n\
+             12.01.2023,P1,1,10.0\n\
+             07.01.2023,P2,4,3.0\n",
+        )
+        .unwrap();
+        let config = PipelineConfig {
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            output_path: dir.path().join("out").to_str().unwrap().to_string(),
+            rules: default_rules("order_date"),
+            date_column: "order_date".to_string(),
+            date_formats: vec!["%d.%m.%Y".to_string()],
+            time_dims: vec![TimeDim::Week, TimeDim::DayOfWeek, TimeDim::DayOfYear],
+            group_by: vec!["week".to_string(), "day_of_week".to_string()],
+            ..PipelineConfig::default()
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "week,day_of_week,total_quantity,total_revenue,avg_price",
+                "1,4,2,20.0,10.0",
+                "1,6,4,12.0,3.0",
+                "2,4,1,10.0,10.0"
+            ]
+        );
+
+        let config = PipelineConfig {
+            group_by: vec!["day_of_year".to_string(), "month".to_string()],
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unknown --group-by column(s): month"));
+    }
+
+    #[test]
+    fn window_outputs_a_moving_average_per_group_and_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            window: Some(2),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.aggregated, Some(3));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "product_id,year,month,total_quantity,total_revenue,avg_price,moving_avg_revenue",
+                "P1,2023,1,2,20.0,10.0,20.0",
+                "P1,2023,2,1,10.0,10.0,15.0",
+                "P2,2023,3,4,10.0,2.5,10.0"
+            ]
+        );
+
+        let config = PipelineConfig {
+            top_n: Some(1),
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("--top-n can't be combined with --window"));
+    }
+
+    #[test]
+    fn outputs_are_written_aside_and_renamed_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        // Left by a run killed mid-write
+        std::fs::write(dir.path().join("out.csv.tmp"), "product_id,total_qu").unwrap();
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().count(), 3);
+        assert!(!dir.path().join("out.csv.tmp").exists());
+
+        let statements = dry_run_statements(&config).unwrap();
+        let copy = statements.last().unwrap();
+        assert!(copy.contains("out.csv.tmp"), "{}", copy);
+
+        let appended = PipelineConfig {
+            append: true,
+            ..config
+        };
+        run_pipeline(&appended, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(output.lines().count(), 5);
+        assert!(!dir.path().join("out.csv.tmp").exists());
+    }
+
+    #[test]
+    fn verbose_counts_the_transformed_view() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.transformed, None);
+
+        let config = PipelineConfig {
+            verbose: true,
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.raw, Some(4));
+        assert_eq!(metrics.rows.transformed, Some(3));
+        assert_eq!(metrics.rows.aggregated, Some(2));
+    }
+
+    #[test]
+    fn sql_file_replaces_the_transform() {
+        let dir = tempfile::tempdir().unwrap();
+        let sql_path = dir.path().join("transform.sql");
+        let write_sql = |sql: &str| {
+            std::fs::write(&sql_path, sql).unwrap();
+            parse_sql_file(sql_path.to_str().unwrap()).unwrap()
+        };
+        let config = PipelineConfig {
+            sql_file: Some(write_sql(
+                "CREATE VIEW user_transformed AS
+                 SELECT *, quantity * price * 2 AS revenue,
+                        CASE WHEN price >= 5 THEN 'premium' ELSE 'budget' END AS tier
+                 FROM cleaned_data;",
+            )),
+            group_by: vec!["tier".to_string()],
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().skip(1).collect::<Vec<_>>(),
+            ["premium,3,60.0,10.0", "budget,4,20.0,2.5"]
+        );
+
+        let config = PipelineConfig {
+            sql_file: Some(write_sql(
+                "CREATE VIEW user_transformed AS SELECT *, qty * price AS revenue FROM cleaned_data",
+            )),
+            ..config
+        };
+        let err = format!(
+            "{:#}",
+            run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err()
+        );
+        assert!(
+            err.contains("cleaned_data has: date, product_id, quantity, price"),
+            "{}",
+            err
+        );
+        assert!(err.contains("qty"), "{}", err);
+
+        let config = PipelineConfig {
+            sql_file: Some(write_sql("CREATE VIEW user_transformed_v2 AS SELECT 1")),
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("didn't create a view named user_transformed"),
+            "{}",
+            err
+        );
+        assert!(parse_sql_file(dir.path().join("nope.sql").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn auto_degrade_reruns_after_out_of_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        // Too little for even one of DuckDB's blocks, so both attempts fail
+        let config = PipelineConfig {
+            memory_limit: Some("2MB".to_string()),
+            ..fixture_config(dir.path())
+        };
+        let mut metrics = PipelineMetrics::new();
+        let err = run_pipeline(&config, &mut metrics).unwrap_err();
+        assert!(is_out_of_memory(&err), "{:#}", err);
+        assert!(metrics.report(None).degraded.is_none());
+
+        let config = PipelineConfig {
+            auto_degrade: true,
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        assert!(run_pipeline(&config, &mut metrics).is_err());
+        let degraded = metrics.report(None).degraded.unwrap();
+        assert_eq!(degraded.threads, 1);
+        assert_eq!(degraded.memory_limit, "1MiB");
+        assert!(degraded.error.contains("Out of Memory Error"));
+    }
+
+    #[test]
+    fn histogram_buckets_the_cleaned_rows() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            histogram: Some(parse_histogram("price:3").unwrap()),
+            ..fixture_config(dir.path())
+        };
+        let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let histogram = result.histogram.unwrap();
+        let buckets: Vec<(f64, f64, i64)> = histogram
+            .buckets
+            .iter()
+            .map(|b| (b.lower, b.upper, b.count))
+            .collect();
+        assert_eq!(buckets, [(2.5, 5.0, 1), (5.0, 7.5, 0), (7.5, 10.0, 2)]);
+
+        let config = PipelineConfig {
+            histogram: Some(parse_histogram("discount:5").unwrap()),
+            ..config
+        };
+        let err = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unknown --histogram column: discount"));
+        assert!(parse_histogram("price").is_err());
+        assert!(parse_histogram("price:0").is_err());
+    }
+
+    #[test]
+    fn files_reads_only_the_listed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        let data = dir.path().join("data");
+        std::fs::write(data.join("more.csv"), SALES_CSV).unwrap();
+        std::fs::write(data.join("ignored.csv"), SALES_CSV).unwrap();
+        let files: Vec<String> = ["sales.csv", "more.csv"]
+            .iter()
+            .map(|f| data.join(f).to_str().unwrap().to_string())
+            .collect();
+        let config = PipelineConfig {
+            files: files.clone(),
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        assert_eq!(metrics.rows.raw, Some(8));
+
+        let missing = PipelineConfig {
+            files: vec![
+                files[0].clone(),
+                data.join("nope.csv").to_str().unwrap().into(),
+            ],
+            ..config
+        };
+        let err = run_pipeline(&missing, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(failure_kind(&err), Some(FailureKind::Input));
+        assert!(
+            err.to_string().contains("nope.csv doesn't exist"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn json_unnest_reads_one_row_per_array_element() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().join("data");
+        std::fs::create_dir(&data_dir).unwrap();
+        let order = |date: &str, items: &str| {
+            format!(
+                r#"{{"order": {{"date": "{}", "items": [{}]}}}}"#,
+                date, items
+            )
+        };
+        let item = |product: &str, quantity: i64, price: f64| {
+            format!(
+                r#"{{"product_id": "{}", "quantity": {}, "price": {}}}"#,
+                product, quantity, price
+            )
+        };
+        let documents = [
+            order(
+                "2023-01-05",
+                &format!("{}, {}", item("P1", 2, 10.0), item("P2", 4, 2.5)),
+            ),
+            order("2023-02-05", &item("P1", 1, 10.0)),
+            order("2023-03-05", ""),
+        ];
+        std::fs::write(data_dir.join("orders.json"), documents.join("\n")).unwrap();
+        let config = PipelineConfig {
+            data_dir: data_dir.to_str().unwrap().to_string(),
+            output_path: dir.path().join("out").to_str().unwrap().to_string(),
+            json_unnest: Some(parse_json_unnest("order.items").unwrap()),
+            ..PipelineConfig::default()
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let unnested = metrics.unnested.clone().unwrap();
+        assert_eq!((unnested.documents, unnested.elements), (3, 3));
+        assert_eq!(metrics.rows.cleaned, Some(3));
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert!(output.contains("P1,3,30.0,10.0"), "{}", output);
+
+        let wrong = PipelineConfig {
+            json_unnest: Some(parse_json_unnest("order.date").unwrap()),
+            ..config
+        };
+        let err = run_pipeline(&wrong, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(failure_kind(&err), Some(FailureKind::Schema));
+        assert!(
+            err.to_string().contains("not an array of objects"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn expect_fails_the_run_before_writing_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, text).unwrap();
+            Some(crate::expect::parse_expectations(path.to_str().unwrap()).unwrap())
+        };
+        let config = PipelineConfig {
+            with_totals: true,
+            expect: expect(
+                "met.toml",
+                "min-rows = 2\nnot-null = [\"product_id\"]\nsum.total_revenue = { min = 40.0, max = 40.0 }\n",
+            ),
+            ..fixture_config(dir.path())
+        };
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = dir.path().join("out.csv");
+        std::fs::remove_file(&output).unwrap();
+
+        let missed = PipelineConfig {
+            expect: expect(
+                "missed.json",
+                r#"{"max-rows": 1, "sum": {"total_revenue": {"min": 50.0}}}"#,
+            ),
+            ..config.clone()
+        };
+        let err = run_pipeline(&missed, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(failure_kind(&err), Some(FailureKind::QualityGate));
+        assert!(
+            err.to_string().ends_with(
+                "2 output rows, more than max-rows 1; sum of total_revenue is 40, below 50"
+            ),
+            "{}",
+            err
+        );
+        assert!(!output.exists());
+
+        let unknown = PipelineConfig {
+            expect: expect("unknown.toml", "not-null = [\"category\"]\n"),
+            ..config
+        };
+        let err = run_pipeline(&unknown, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("names column 'category'"),
+            "{}",
+            err
+        );
+        let path = dir.path().join("typo.toml");
+        std::fs::write(&path, "min_rows = 2\n").unwrap();
+        assert!(crate::expect::parse_expectations(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn quarterly_orders_by_quarter_and_totals_each() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = fixture_config(dir.path());
+        std::fs::write(
+            dir.path().join("data").join("sales.csv"),
+            "date,product_id,quantity,price\n\
+             2024-02-01,P2,1,3.0\n\
+             2023-05-05,P2,4,2.5\n\
+             2023-04-05,P1,3,10.0\n\
+             2023-01-05,P1,2,10.0\n",
+        )
+        .unwrap();
+        let config = PipelineConfig {
+            group_by: vec!["product_id".into(), "year".into(), "quarter".into()],
+            quarterly: true,
+            ..config
+        };
+        let mut metrics = PipelineMetrics::new();
+        run_pipeline(&config, &mut metrics).unwrap();
+        let quarters: Vec<(i64, i64, f64)> = metrics
+            .quarters
+            .iter()
+            .map(|q| (q.year, q.quarter, q.revenue))
+            .collect();
+        assert_eq!(quarters, [(2023, 1, 20.0), (2023, 2, 40.0), (2024, 1, 3.0)]);
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        let keys: Vec<&str> = output.lines().skip(1).map(|l| &l[..12]).collect();
+        assert_eq!(
+            keys,
+            [
+                "P1,2023,1,2,",
+                "P1,2023,2,3,",
+                "P2,2023,2,4,",
+                "P2,2024,1,1,"
+            ]
+        );
+
+        let split = PipelineConfig {
+            split_quarters: true,
+            format: OutputFormat::Parquet,
+            output_path: dir.path().join("quarters").to_str().unwrap().to_string(),
+            ..config.clone()
+        };
+        run_pipeline(&split, &mut PipelineMetrics::new()).unwrap();
+        assert!(dir
+            .path()
+            .join("quarters/year=2023/quarter=2/data_0.parquet")
+            .exists());
+
+        let ungrouped = PipelineConfig {
+            group_by: vec!["product_id".into(), "year".into()],
+            ..config
+        };
+        let err = run_pipeline(&ungrouped, &mut PipelineMetrics::new()).unwrap_err();
+        assert!(
+            err.to_string().contains("--quarterly needs quarter"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn explain_schema_describes_raw_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            renames: vec![parse_rename("product_id=sku").unwrap()],
+            ..fixture_config(dir.path())
+        };
+        let columns = explain_schema(&config).unwrap();
+        let described: Vec<(&str, &str)> = columns
+            .iter()
+            .map(|c| (c.column.as_str(), c.sql_type.as_str()))
+            .collect();
+        assert_eq!(
+            described,
+            [
+                ("date", "DATE"),
+                ("quantity", "BIGINT"),
+                ("price", "DOUBLE"),
+                ("sku", "VARCHAR")
+            ]
+        );
+        assert_eq!(columns[3].samples, ["P1", "P2", "P3"]);
+        assert_eq!(columns[1].samples, ["0", "1", "2"]);
+        assert!(!Path::new(&config.output_path).exists());
+    }
+
+    #[test]
+    fn format_numbers_groups_digits_in_csv_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            format_numbers: true,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price\n2023-01-05,P1,1500,1000.5\n2023-01-06,P2,3,2.25\n",
+        )
+        .unwrap();
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "product_id,total_quantity,total_revenue,avg_price",
+                "P1,\"1,500\",\"1,500,750.00\",\"1,000.50\"",
+                "P2,3,6.75,2.25"
+            ]
+        );
+
+        let semicolons = PipelineConfig {
+            output_delimiter: ';',
+            ..config.clone()
+        };
+        run_pipeline(&semicolons, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().nth(1),
+            Some("P1;1,500;1,500,750.00;1,000.50")
+        );
+
+        let parquet = PipelineConfig {
+            format: OutputFormat::Parquet,
+            ..config
+        };
+        assert!(dry_run_statements(&parquet).is_err());
+    }
+
+    #[test]
+    fn cache_skips_load_and_clean_until_the_input_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            cache: Some(dir.path().join("cache").join("cleaned.parquet")),
+            ..fixture_config(dir.path())
+        };
+        let output = || std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        let run = |config: &PipelineConfig| {
+            let mut metrics = PipelineMetrics::new();
+            run_pipeline(config, &mut metrics).unwrap();
+            metrics
+        };
+
+        assert_eq!(run(&config).cache_hit, Some(false));
+        let uncached = output();
+        let metrics = run(&config);
+        assert_eq!(metrics.cache_hit, Some(true));
+        assert_eq!((metrics.rows.raw, metrics.rows.removed), (Some(4), Some(1)));
+        let steps: Vec<String> = metrics
+            .report(None)
+            .steps
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert!(!steps.contains(&"Clean".to_string()));
+        assert_eq!(output(), uncached);
+
+        let exclude_p2 = PipelineConfig {
+            exclude_products: Some("P2".to_string()),
+            ..config.clone()
+        };
+        assert_eq!(run(&exclude_p2).cache_hit, Some(false));
+        assert!(!output().contains("P2"));
+        // The counts cleaning recorded come back from the cache
+        let metrics = run(&exclude_p2);
+        assert_eq!(metrics.cache_hit, Some(true));
+        assert_eq!(metrics.rows.filtered, Some(1));
+        assert_eq!(metrics.removed_by_filter[0].rows, 1);
+        // and the quality gate still applies to them
+        let strict = PipelineConfig {
+            fail_on_removed_pct: Some(25.0),
+            ..exclude_p2
+        };
+        let err = run_pipeline(&strict, &mut PipelineMetrics::new()).unwrap_err();
+        assert_eq!(failure_kind(&err), Some(FailureKind::QualityGate));
+
+        let extra = Path::new(&config.data_dir).join("extra.csv");
+        std::fs::write(
+            &extra,
+            "date,product_id,quantity,price\n2023-04-01,P5,1,1.0\n",
+        )
+        .unwrap();
+        assert_eq!(run(&config).cache_hit, Some(false));
+        assert_eq!(run(&config).cache_hit, Some(true));
+        std::fs::remove_file(&extra).unwrap();
+        assert_eq!(run(&config).cache_hit, Some(false));
+        assert!(!output().contains("P5"));
+
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            format!("{}2023-03-07,P4,1,5.0\n", SALES_CSV),
+        )
+        .unwrap();
+        assert_eq!(run(&config).cache_hit, Some(false));
+        assert!(output().contains("P4"));
+
+        let profiled = PipelineConfig {
+            profile: true,
+            ..config
+        };
+        assert!(dry_run_statements(&profiled).is_err());
+    }
+
+    #[test]
+    fn yoy_compares_revenue_with_the_year_before() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = PipelineConfig {
+            yoy: true,
+            ..fixture_config(dir.path())
+        };
+        std::fs::write(
+            Path::new(&config.data_dir).join("sales.csv"),
+            "date,product_id,quantity,price\n\
+             2021-01-05,P1,2,10.0\n\
+             2022-03-05,P1,3,10.0\n\
+             2022-04-05,P1,1,10.0\n\
+             2021-05-05,P2,4,2.5\n\
+             2023-06-05,P2,4,5.0\n",
+        )
+        .unwrap();
+        run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+        let output = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+        assert_eq!(
+            output.lines().collect::<Vec<_>>(),
+            [
+                "product_id,year,revenue,prev_year_revenue,yoy_pct",
+                "P1,2021,20.0,,",
+                "P1,2022,40.0,20.0,100.0",
+                // 2022 had no P2 sales, so 2021 isn't the prior year
+                "P2,2021,10.0,,",
+                "P2,2023,20.0,,"
+            ]
+        );
+
+        let windowed = PipelineConfig {
+            window: Some(3),
+            ..config.clone()
+        };
+        assert!(dry_run_statements(&windowed).is_err());
+        let by_year = PipelineConfig {
+            group_by: vec!["product_id".to_string(), "year".to_string()],
+            ..config
+        };
+        assert!(dry_run_statements(&by_year).is_err());
+    }
+}
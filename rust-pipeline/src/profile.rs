@@ -0,0 +1,160 @@
+//! `--profile`: per-column statistics of the raw input, before cleaning.
+//! `--explain-schema`: the raw input's columns and types, with samples.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+
+use crate::sql::{build_sample_values_sql, quote_ident};
+use crate::term::{rule, title};
+
+/// One row of the profile table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnProfile {
+    pub column: String,
+    pub sql_type: String,
+    pub nulls: i64,
+    /// DuckDB's HyperLogLog estimate, so slightly off for large columns.
+    pub approx_distinct: i64,
+    /// Only filled in for numeric columns.
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+/// One column of `--explain-schema`: DuckDB's inferred type and a few of
+/// the values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSchema {
+    pub column: String,
+    pub sql_type: String,
+    pub samples: Vec<String>,
+}
+
+/// How many distinct values `--explain-schema` shows per column.
+const SCHEMA_SAMPLES: u64 = 3;
+
+fn is_numeric(sql_type: &str) -> bool {
+    const NUMERIC: &[&str] = &[
+        "TINYINT",
+        "SMALLINT",
+        "INTEGER",
+        "BIGINT",
+        "HUGEINT",
+        "UTINYINT",
+        "USMALLINT",
+        "UINTEGER",
+        "UBIGINT",
+        "FLOAT",
+        "DOUBLE",
+    ];
+    NUMERIC.contains(&sql_type) || sql_type.starts_with("DECIMAL")
+}
+
+/// `SUMMARIZE view` plus exact null counts, which SUMMARIZE only gives as a
+/// rounded percentage.
+pub(crate) fn profile_view(conn: &Connection, view: &str) -> Result<Vec<ColumnProfile>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT column_name, column_type, min, max, approx_unique FROM (SUMMARIZE {})",
+        view
+    ))?;
+    let mut profiles: Vec<ColumnProfile> = stmt
+        .query_map([], |row| {
+            let sql_type: String = row.get(1)?;
+            let numeric = is_numeric(&sql_type);
+            Ok(ColumnProfile {
+                column: row.get(0)?,
+                min: if numeric { row.get(2)? } else { None },
+                max: if numeric { row.get(3)? } else { None },
+                approx_distinct: row.get(4)?,
+                sql_type,
+                nulls: 0,
+            })
+        })?
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Failed to summarize {}", view))?;
+    if profiles.is_empty() {
+        return Ok(profiles);
+    }
+
+    let counts: Vec<String> = profiles
+        .iter()
+        .map(|p| format!("COUNT(*) - COUNT({})", quote_ident(&p.column)))
+        .collect();
+    let nulls: Vec<i64> = conn
+        .query_row(
+            &format!("SELECT {} FROM {}", counts.join(", "), view),
+            [],
+            |row| (0..profiles.len()).map(|i| row.get(i)).collect(),
+        )
+        .with_context(|| format!("Failed to count NULLs in {}", view))?;
+    for (profile, nulls) in profiles.iter_mut().zip(nulls) {
+        profile.nulls = nulls;
+    }
+    Ok(profiles)
+}
+
+/// `DESCRIBE view` plus up to `SCHEMA_SAMPLES` values of each column.
+pub(crate) fn describe_view(conn: &Connection, view: &str) -> Result<Vec<ColumnSchema>> {
+    let mut stmt = conn.prepare(&format!("DESCRIBE {}", view))?;
+    let mut columns: Vec<ColumnSchema> = stmt
+        .query_map([], |row| {
+            Ok(ColumnSchema {
+                column: row.get(0)?,
+                sql_type: row.get(1)?,
+                samples: Vec::new(),
+            })
+        })?
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Failed to describe {}", view))?;
+    for column in &mut columns {
+        let mut stmt = conn.prepare(&build_sample_values_sql(
+            view,
+            &column.column,
+            SCHEMA_SAMPLES,
+        ))?;
+        column.samples = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()
+            .with_context(|| format!("Failed to sample {}.{}", view, column.column))?;
+    }
+    Ok(columns)
+}
+
+pub fn print_schema(columns: &[ColumnSchema]) {
+    println!("\n{}", rule(78));
+    println!("{}", title("Input Schema (raw_data)"));
+    println!("{}", rule(78));
+    println!("{:<16} {:<14} samples", "column", "type");
+    for c in columns {
+        let samples = if c.samples.is_empty() {
+            "(all NULL)".to_string()
+        } else {
+            c.samples.join(", ")
+        };
+        println!("{:<16} {:<14} {}", c.column, c.sql_type, samples);
+    }
+    println!("{}", rule(78));
+    println!();
+}
+
+pub fn print_profile(profiles: &[ColumnProfile]) {
+    println!("\n{}", rule(78));
+    println!("{}", title("Raw Data Profile (before cleaning)"));
+    println!("{}", rule(78));
+    println!(
+        "{:<16} {:<10} {:>10} {:>10} {:>14} {:>14}",
+        "column", "type", "nulls", "~distinct", "min", "max"
+    );
+    for p in profiles {
+        println!(
+            "{:<16} {:<10} {:>10} {:>10} {:>14} {:>14}",
+            p.column,
+            p.sql_type,
+            p.nulls,
+            p.approx_distinct,
+            p.min.as_deref().unwrap_or("-"),
+            p.max.as_deref().unwrap_or("-")
+        );
+    }
+    println!("{}", rule(78));
+    println!();
+}
@@ -0,0 +1,114 @@
+//! `--push-gateway`: a run's metrics in the Prometheus text exposition
+//! format, POSTed to a pushgateway.
+
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use std::time::Duration;
+
+use crate::metrics::MetricsReport;
+
+/// Job name in the pushgateway grouping key.
+const JOB: &str = "rust_pipeline";
+
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Gauges for the report, each labelled with `run_timestamp` (Unix seconds
+/// at the start of the run). Stages the run didn't reach, and memory that
+/// wasn't tracked, are left out.
+pub fn format_metrics(report: &MetricsReport, run_timestamp: i64) -> String {
+    let label = format!("run_timestamp=\"{}\"", run_timestamp);
+    let mut text = String::new();
+    let mut gauge = |name: &str, help: &str, samples: &[(Option<&str>, f64)]| {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+        for (stage, value) in samples {
+            match stage {
+                Some(stage) => {
+                    let _ = writeln!(text, "{}{{stage=\"{}\",{}}} {}", name, stage, label, value);
+                }
+                None => {
+                    let _ = writeln!(text, "{}{{{}}} {}", name, label, value);
+                }
+            }
+        }
+    };
+    gauge(
+        "pipeline_duration_seconds",
+        "Wall-clock duration of the run.",
+        &[(None, report.duration_secs)],
+    );
+    gauge(
+        "pipeline_peak_memory_bytes",
+        "Peak resident memory of the process.",
+        &report
+            .peak_memory_mb
+            .map(|mb| (None, mb * 1024.0 * 1024.0))
+            .into_iter()
+            .collect::<Vec<_>>(),
+    );
+    gauge(
+        "pipeline_peak_virtual_memory_bytes",
+        "Peak virtual memory size of the process.",
+        &report
+            .peak_virtual_memory_mb
+            .map(|mb| (None, mb * 1024.0 * 1024.0))
+            .into_iter()
+            .collect::<Vec<_>>(),
+    );
+    let rows = &report.rows;
+    let stages: Vec<(Option<&str>, f64)> = [
+        ("raw", rows.raw),
+        ("cleaned", rows.cleaned),
+        ("removed", rows.removed),
+        ("duplicates", rows.duplicates),
+        ("aggregated", rows.aggregated),
+    ]
+    .into_iter()
+    .filter_map(|(stage, count)| count.map(|c| (Some(stage), c as f64)))
+    .collect();
+    gauge("pipeline_rows", "Rows at each pipeline stage.", &stages);
+    text
+}
+
+/// POSTs the report to `<url>/metrics/job/rust_pipeline`, replacing the
+/// previous run's values for the same metric names.
+pub fn push_metrics(url: &str, report: &MetricsReport, run_timestamp: i64) -> Result<()> {
+    let endpoint = format!("{}/metrics/job/{}", url.trim_end_matches('/'), JOB);
+    reqwest::blocking::Client::builder()
+        .timeout(PUSH_TIMEOUT)
+        .build()
+        .context("Failed to create HTTP client")?
+        .post(&endpoint)
+        .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(format_metrics(report, run_timestamp))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("Failed to push metrics to {}", endpoint))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::PipelineMetrics;
+
+    #[test]
+    fn formats_reached_stages_with_the_timestamp_label() {
+        let mut metrics = PipelineMetrics::new();
+        metrics.rows.raw = Some(4);
+        metrics.rows.cleaned = Some(3);
+        let text = format_metrics(&metrics.report(None), 1700000000);
+        assert!(text.contains("# TYPE pipeline_duration_seconds gauge\n"));
+        assert!(text.contains("pipeline_rows{stage=\"raw\",run_timestamp=\"1700000000\"} 4\n"));
+        assert!(text.contains("pipeline_rows{stage=\"cleaned\",run_timestamp=\"1700000000\"} 3\n"));
+        assert!(!text.contains("stage=\"aggregated\""));
+
+        // Nothing listens on port 1, so the push fails rather than hanging
+        let err = push_metrics("http://127.0.0.1:1", &metrics.report(None), 0).unwrap_err();
+        assert!(
+            err.to_string().contains("/metrics/job/rust_pipeline"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! `s3://` input: loads DuckDB's httpfs extension and registers the AWS
+//! credentials from the environment as a DuckDB secret.
+
+use anyhow::{Context, Result};
+use duckdb::Connection;
+
+use crate::config::PipelineConfig;
+use crate::sql::sql_string;
+
+/// Whether a data location is read from S3 rather than the local disk.
+pub(crate) fn is_s3(location: &str) -> bool {
+    location.starts_with("s3://")
+}
+
+/// The standard AWS variables; unset or empty ones are left to DuckDB. No
+/// `Debug`, so the secret can't end up in a log line.
+pub(crate) struct S3Credentials {
+    pub(crate) key_id: Option<String>,
+    pub(crate) secret: Option<String>,
+    pub(crate) session_token: Option<String>,
+}
+
+impl S3Credentials {
+    pub(crate) fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        Self {
+            key_id: var("AWS_ACCESS_KEY_ID"),
+            secret: var("AWS_SECRET_ACCESS_KEY"),
+            session_token: var("AWS_SESSION_TOKEN"),
+        }
+    }
+}
+
+/// Statements that load httpfs and create the S3 secret, or nothing when no
+/// data location is on S3. With `redact` the secret key and session token
+/// are masked, for `--dry-run`.
+pub(crate) fn build_s3_setup_sql(
+    config: &PipelineConfig,
+    credentials: &S3Credentials,
+    redact: bool,
+) -> Vec<String> {
+    if !config.data_dirs().into_iter().any(is_s3) {
+        return Vec::new();
+    }
+    let mut statements = vec!["LOAD httpfs".to_string()];
+    let hidden = |value: &String| {
+        if redact {
+            "'***'".to_string()
+        } else {
+            sql_string(value)
+        }
+    };
+    let mut options = Vec::new();
+    if let Some(key_id) = &credentials.key_id {
+        options.push(format!("KEY_ID {}", sql_string(key_id)));
+    }
+    if let Some(secret) = &credentials.secret {
+        options.push(format!("SECRET {}", hidden(secret)));
+    }
+    if let Some(token) = &credentials.session_token {
+        options.push(format!("SESSION_TOKEN {}", hidden(token)));
+    }
+    if let Some(region) = &config.s3_region {
+        options.push(format!("REGION {}", sql_string(region)));
+    }
+    // S3-compatible stores such as MinIO serve path-style URLs, often over
+    // plain HTTP; DuckDB wants the endpoint without its scheme
+    if let Some(endpoint) = &config.s3_endpoint {
+        let (host, use_ssl) = match endpoint.strip_prefix("http://") {
+            Some(host) => (host, false),
+            None => (endpoint.strip_prefix("https://").unwrap_or(endpoint), true),
+        };
+        options.push(format!(
+            "ENDPOINT {}",
+            sql_string(host.trim_end_matches('/'))
+        ));
+        options.push("URL_STYLE 'path'".to_string());
+        options.push(format!("USE_SSL {}", use_ssl));
+    }
+    if !options.is_empty() {
+        statements.push(format!(
+            "CREATE OR REPLACE SECRET pipeline_s3 (TYPE S3, {})",
+            options.join(", ")
+        ));
+    }
+    statements
+}
+
+/// Loads httpfs, installing it first if this machine doesn't have it yet,
+/// and registers the credentials. Runs before input discovery, which already
+/// lists the bucket.
+pub(crate) fn setup_s3(conn: &Connection, config: &PipelineConfig) -> Result<()> {
+    let statements = build_s3_setup_sql(config, &S3Credentials::from_env(), false);
+    let Some((load, secret)) = statements.split_first() else {
+        return Ok(());
+    };
+    conn.execute_batch(load)
+        .or_else(|_| conn.execute_batch("INSTALL httpfs; LOAD httpfs"))
+        .context(
+            "s3:// input needs DuckDB's httpfs extension, which couldn't be loaded or \
+             installed; allow access to extensions.duckdb.org or install it beforehand",
+        )?;
+    for statement in secret {
+        conn.execute_batch(statement)
+            .context("Failed to register the S3 credentials")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secret_carries_credentials_and_endpoint() {
+        let config = PipelineConfig {
+            data_dir: "s3://bucket/sales".to_string(),
+            s3_region: Some("eu-west-1".to_string()),
+            s3_endpoint: Some("http://localhost:9000/".to_string()),
+            ..PipelineConfig::default()
+        };
+        let credentials = S3Credentials {
+            key_id: Some("AKIA".to_string()),
+            secret: Some("it's secret".to_string()),
+            session_token: None,
+        };
+        assert_eq!(
+            build_s3_setup_sql(&config, &credentials, false),
+            [
+                "LOAD httpfs",
+                "CREATE OR REPLACE SECRET pipeline_s3 (TYPE S3, KEY_ID 'AKIA', \
+                 SECRET 'it''s secret', REGION 'eu-west-1', ENDPOINT 'localhost:9000', \
+                 URL_STYLE 'path', USE_SSL false)"
+            ]
+        );
+        assert!(build_s3_setup_sql(&config, &credentials, true)[1].contains("SECRET '***'"));
+
+        let local = PipelineConfig {
+            data_dir: "data".to_string(),
+            ..config
+        };
+        assert!(build_s3_setup_sql(&local, &credentials, false).is_empty());
+    }
+}
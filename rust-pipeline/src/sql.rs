@@ -0,0 +1,1113 @@
+//! Builders for the SQL statements each pipeline step executes.
+
+use crate::config::{
+    quantile_column, Dedup, JsonUnnest, NullStrategy, OutputDestination, OutputFormat,
+    PipelineConfig, ValidationRule, MOVING_AVERAGE_COLUMN, USER_TRANSFORM_VIEW, YOY_COLUMNS,
+    YOY_PERIOD,
+};
+use crate::expect::Expectations;
+use crate::input::InputSource;
+use crate::s3::{build_s3_setup_sql, S3Credentials};
+use std::path::{Path, PathBuf};
+
+/// ` WHERE ...` requiring every cleaning rule and business filter to pass,
+/// or nothing when there are none.
+fn where_sql(config: &PipelineConfig) -> String {
+    let mut conditions = rule_conditions(&config.cleaning_rules(), &config.date_formats);
+    conditions.extend(config.business_filters().into_iter().map(|f| f.condition));
+    if conditions.is_empty() {
+        return String::new();
+    }
+    format!(" WHERE {}", conditions.join(" AND "))
+}
+
+fn rule_conditions(rules: &[ValidationRule], date_formats: &[String]) -> Vec<String> {
+    rules.iter().map(|r| r.condition(date_formats)).collect()
+}
+
+/// Counts rows that pass the validity rules but are removed by the business
+/// filters: per filter, then in total, as raw rows may fail several.
+pub(crate) fn build_filter_count_sql(config: &PipelineConfig) -> Option<String> {
+    let filters = config.business_filters();
+    if filters.is_empty() {
+        return None;
+    }
+    let valid: Vec<String> = rule_conditions(&config.cleaning_rules(), &config.date_formats)
+        .into_iter()
+        .map(|c| format!("COALESCE({}, false)", c))
+        .chain(std::iter::once("true".to_string()))
+        .collect();
+    let valid = valid.join(" AND ");
+    let all: Vec<&str> = filters.iter().map(|f| f.condition.as_str()).collect();
+    let counts: Vec<String> = filters
+        .iter()
+        .map(|f| f.condition.clone())
+        .chain(std::iter::once(all.join(" AND ")))
+        .map(|kept| format!("COUNT(*) FILTER (WHERE {} AND NOT ({}))", valid, kept))
+        .collect();
+    Some(format!("SELECT {} FROM raw_data", counts.join(", ")))
+}
+
+/// The first `limit` raw rows the cleaning step removes, in input order,
+/// with a leading `removal_reasons` column listing each cleaning rule and
+/// business filter the row failed.
+pub(crate) fn build_removed_sample_sql(config: &PipelineConfig, limit: u64) -> String {
+    let rules = config.cleaning_rules();
+    let checks: Vec<(String, String)> = rules
+        .iter()
+        .map(|r| (r.condition(&config.date_formats), r.describe_failure()))
+        .chain(
+            config
+                .business_filters()
+                .into_iter()
+                .map(|f| (f.condition, f.name)),
+        )
+        .collect();
+    let reasons: Vec<String> = checks
+        .iter()
+        .map(|(kept, reason)| {
+            format!(
+                "CASE WHEN NOT COALESCE({}, false) THEN {} END",
+                kept,
+                sql_string(reason)
+            )
+        })
+        .collect();
+    let reasons = if reasons.is_empty() {
+        "''".to_string()
+    } else {
+        format!("concat_ws('; ', {})", reasons.join(", "))
+    };
+    format!(
+        "SELECT * FROM (SELECT {} AS removal_reasons, * FROM raw_data) \
+         WHERE removal_reasons <> '' LIMIT {}",
+        reasons, limit
+    )
+}
+
+/// Writes the `--explain-removed` sample to a CSV file.
+pub(crate) fn build_removed_copy_sql(sample: &str, path: &Path) -> String {
+    format!(
+        "COPY ({}) TO {} (HEADER, DELIMITER ',')",
+        sample,
+        sql_string(&path.to_string_lossy())
+    )
+}
+
+/// `query` with each of its `columns` cast to text, for printing.
+pub(crate) fn build_text_sql(query: &str, columns: &[String]) -> String {
+    let as_text: Vec<String> = columns
+        .iter()
+        .map(|c| format!("CAST({0} AS VARCHAR) AS {0}", quote_ident(c)))
+        .collect();
+    format!("SELECT {} FROM ({})", as_text.join(", "), query)
+}
+
+/// Holds for values of `column` that are kept as loaded: present and passing
+/// every rule on that column.
+fn valid_value_sql(config: &PipelineConfig, column: &str) -> String {
+    let mut conditions = vec![format!("{} IS NOT NULL", quote_ident(column))];
+    conditions.extend(
+        config
+            .rules
+            .iter()
+            .filter(|r| r.column == column)
+            .map(|r| r.condition(&config.date_formats)),
+    );
+    format!("COALESCE({}, false)", conditions.join(" AND "))
+}
+
+/// Filters on the cleaning rules and, unless `--null-strategy` is drop,
+/// replaces bad quantity and price values instead of dropping their rows.
+fn build_clean_sql(config: &PipelineConfig) -> String {
+    let replacements: Vec<String> = config
+        .imputed_columns()
+        .iter()
+        .map(|column| {
+            let valid = valid_value_sql(config, column);
+            let fill = match config.null_strategy {
+                NullStrategy::Mean => format!(
+                    "(SELECT AVG({}) FROM raw_data WHERE {})",
+                    quote_ident(column),
+                    valid
+                ),
+                NullStrategy::Zero | NullStrategy::Drop => "0".to_string(),
+            };
+            format!(
+                "CASE WHEN {} THEN {} ELSE {} END AS {}",
+                valid,
+                quote_ident(column),
+                fill,
+                quote_ident(column)
+            )
+        })
+        .collect();
+    let columns = match config.projected_columns() {
+        Some(projected) => {
+            let imputed = config.imputed_columns();
+            let columns: Vec<String> = projected
+                .iter()
+                .map(|column| match imputed.iter().position(|c| c == column) {
+                    Some(i) => replacements[i].clone(),
+                    None => quote_ident(column),
+                })
+                .collect();
+            columns.join(", ")
+        }
+        None if replacements.is_empty() => "*".to_string(),
+        None => format!("* REPLACE ({})", replacements.join(", ")),
+    };
+    format!(
+        "CREATE OR REPLACE VIEW cleaned_data AS SELECT {} FROM raw_data{}",
+        columns,
+        where_sql(config)
+    )
+}
+
+/// Counts, per imputed column, the values `build_clean_sql` replaces.
+pub(crate) fn build_imputed_count_sql(config: &PipelineConfig) -> Option<String> {
+    let imputed = config.imputed_columns();
+    if imputed.is_empty() {
+        return None;
+    }
+    let counts: Vec<String> = imputed
+        .iter()
+        .map(|column| {
+            format!(
+                "COUNT(*) FILTER (WHERE NOT {})",
+                valid_value_sql(config, column)
+            )
+        })
+        .collect();
+    Some(format!(
+        "SELECT {} FROM raw_data{}",
+        counts.join(", "),
+        where_sql(config)
+    ))
+}
+
+fn build_aggregate_sql(config: &PipelineConfig) -> String {
+    let keys: Vec<String> = config.group_by.iter().map(|c| quote_ident(c)).collect();
+    let keys = keys.join(", ");
+    let money = |expr: String| match config.round {
+        Some(places) => format!("ROUND({}, {})", expr, places),
+        None => expr,
+    };
+    let quantiles: String = config
+        .quantiles
+        .iter()
+        .map(|q| {
+            format!(
+                ",\n             {} AS {}",
+                money(format!("APPROX_QUANTILE(price, {})", q)),
+                quote_ident(&quantile_column(*q))
+            )
+        })
+        .collect();
+    let measures: Vec<String> = config
+        .aggregations
+        .iter()
+        .map(|a| {
+            let sql = if a.is_monetary() {
+                money(a.sql())
+            } else {
+                a.sql()
+            };
+            format!(",\n             {} AS {}", sql, quote_ident(&a.alias()))
+        })
+        .collect();
+    let measures = measures.concat();
+    if let Some(periods) = config.window {
+        return build_window_sql(config, periods, &measures, &quantiles);
+    }
+    if config.yoy {
+        return build_yoy_sql(config);
+    }
+    let mut order = format!("{} {}", quote_ident(&config.order_by), config.order.sql());
+    if config.quarterly {
+        order = format!(
+            "{}, {}, {}",
+            quote_ident("year"),
+            quote_ident("quarter"),
+            order
+        );
+    }
+    // The totals row has NULL keys and always comes last; it isn't one of
+    // the --top-n groups, and it totals every group, not just the kept ones
+    let (grouping, qualify, order, limit) = if config.with_totals {
+        let is_total = format!("GROUPING({})", keys);
+        let qualify = config.top_n.map(|k| {
+            format!(
+                "\n         QUALIFY {0} > 0 OR row_number() OVER (PARTITION BY {0} ORDER BY {1}) <= {2}",
+                is_total, order, k
+            )
+        });
+        (
+            format!("GROUPING SETS (({}), ())", keys),
+            qualify,
+            format!("{}, {}", is_total, order),
+            None,
+        )
+    } else {
+        let limit = config.top_n.map(|k| format!("\n         LIMIT {}", k));
+        (keys.clone(), None, order, limit)
+    };
+    let (qualify, limit) = (qualify.unwrap_or_default(), limit.unwrap_or_default());
+    format!(
+        "CREATE OR REPLACE VIEW aggregated_data AS 
+         SELECT 
+             {keys}{measures}{quantiles}
+         FROM transformed_data
+         GROUP BY {grouping}{qualify}
+         ORDER BY {order}{limit}"
+    )
+}
+
+/// The `--window` form of `aggregated_data`: the measures per group and
+/// period, plus the revenue averaged over the last `periods` periods of the
+/// group, oldest first. A group's first periods average over fewer.
+fn build_window_sql(
+    config: &PipelineConfig,
+    periods: u32,
+    measures: &str,
+    quantiles: &str,
+) -> String {
+    let partition: Vec<String> = config.group_by.iter().map(|c| quote_ident(c)).collect();
+    let order: Vec<String> = config.window_order.iter().map(|c| quote_ident(c)).collect();
+    let keys = [partition.as_slice(), order.as_slice()].concat().join(", ");
+    let partition = if partition.is_empty() {
+        String::new()
+    } else {
+        format!("PARTITION BY {} ", partition.join(", "))
+    };
+    let mut moving = format!(
+        "AVG(SUM(revenue)) OVER ({}ORDER BY {} ROWS BETWEEN {} PRECEDING AND CURRENT ROW)",
+        partition,
+        order.join(", "),
+        periods - 1
+    );
+    if let Some(places) = config.round {
+        moving = format!("ROUND({}, {})", moving, places);
+    }
+    format!(
+        "CREATE OR REPLACE VIEW aggregated_data AS 
+         SELECT 
+             {keys}{measures}{quantiles},
+             {moving} AS {}
+         FROM transformed_data
+         GROUP BY {keys}
+         ORDER BY {keys}",
+        quote_ident(MOVING_AVERAGE_COLUMN)
+    )
+}
+
+/// The `--yoy` form of `aggregated_data`: revenue per group and year, the
+/// group's revenue in the year before and the change in percent. Those two
+/// are NULL when the group sold nothing the year before, and the change is
+/// also NULL when that year's revenue was 0.
+fn build_yoy_sql(config: &PipelineConfig) -> String {
+    let partition: Vec<String> = config.group_by.iter().map(|c| quote_ident(c)).collect();
+    let year = quote_ident(YOY_PERIOD);
+    let keys = [partition.as_slice(), std::slice::from_ref(&year)]
+        .concat()
+        .join(", ");
+    let partition = if partition.is_empty() {
+        String::new()
+    } else {
+        format!("PARTITION BY {} ", partition.join(", "))
+    };
+    // A RANGE frame rather than LAG, so a gap year doesn't become the prior
+    let prev = format!(
+        "SUM(SUM(revenue)) OVER ({}ORDER BY {} RANGE BETWEEN 1 PRECEDING AND 1 PRECEDING)",
+        partition, year
+    );
+    let pct = format!("(SUM(revenue) - {0}) / NULLIF({0}, 0) * 100", prev);
+    let measures: Vec<String> = ["SUM(revenue)".to_string(), prev, pct]
+        .into_iter()
+        .map(|sql| match config.round {
+            Some(places) => format!("ROUND({}, {})", sql, places),
+            None => sql,
+        })
+        .zip(YOY_COLUMNS)
+        .map(|(sql, column)| format!(",\n             {} AS {}", sql, quote_ident(column)))
+        .collect();
+    let measures = measures.concat();
+    format!(
+        "CREATE OR REPLACE VIEW aggregated_data AS 
+         SELECT 
+             {keys}{measures}
+         FROM transformed_data
+         GROUP BY {keys}
+         ORDER BY {keys}"
+    )
+}
+
+/// Revenue per year and quarter for the `--quarterly` summary, over every
+/// transformed row rather than only the groups the output kept.
+pub(crate) fn build_quarter_revenue_sql() -> String {
+    "SELECT \"year\", \"quarter\", SUM(revenue)::DOUBLE FROM transformed_data GROUP BY ALL ORDER BY ALL"
+        .to_string()
+}
+
+/// Writes `cleaned_data` to the `--cache` file.
+pub(crate) fn build_cache_copy_sql(path: &Path) -> String {
+    format!(
+        "COPY cleaned_data TO {} (FORMAT PARQUET)",
+        sql_string(&path.to_string_lossy())
+    )
+}
+
+/// Points `cleaned_data` at the `--cache` file, in place of loading and
+/// cleaning the input.
+pub(crate) fn build_cached_clean_sql(path: &Path) -> String {
+    format!(
+        "CREATE OR REPLACE VIEW cleaned_data AS SELECT * FROM read_parquet({})",
+        sql_string(&path.to_string_lossy())
+    )
+}
+
+/// The first `limit` distinct non-NULL values of `column` in `view`, as
+/// text, for `--explain-schema`. Sorted so the samples don't change between
+/// runs.
+pub(crate) fn build_sample_values_sql(view: &str, column: &str, limit: u64) -> String {
+    let column = quote_ident(column);
+    format!(
+        "SELECT DISTINCT {0}::VARCHAR AS v FROM {1} WHERE {0} IS NOT NULL ORDER BY v LIMIT {2}",
+        column, view, limit
+    )
+}
+
+/// Counts the groups before any `--top-n` limit, stopping at `limit`.
+pub(crate) fn build_group_count_sql(config: &PipelineConfig, limit: u64) -> String {
+    let keys: Vec<String> = config.group_by.iter().map(|c| quote_ident(c)).collect();
+    format!(
+        "SELECT COUNT(*) FROM (SELECT 1 FROM transformed_data GROUP BY {} LIMIT {})",
+        keys.join(", "),
+        limit
+    )
+}
+
+/// Quotes a column name as a SQL identifier.
+pub(crate) fn quote_ident(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a value as a SQL string literal, escaping embedded single quotes.
+pub(crate) fn sql_string(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+fn engine_settings_sql(config: &PipelineConfig) -> Vec<String> {
+    let mut statements = Vec::new();
+    if let Some(limit) = &config.memory_limit {
+        statements.push(format!("PRAGMA memory_limit={}", sql_string(limit)));
+    }
+    if let Some(threads) = config.threads {
+        statements.push(format!("PRAGMA threads={}", threads));
+    }
+    if let Some(dir) = &config.temp_dir {
+        statements.push(format!(
+            "PRAGMA temp_directory={}",
+            sql_string(&dir.to_string_lossy())
+        ));
+    }
+    statements
+}
+
+// Views can't be prepared with parameters, so paths are inlined as literals
+fn build_raw_sql(config: &PipelineConfig, input: &InputSource) -> String {
+    // With insertion order preserved, as by default, LIMIT keeps the first
+    // rows of the files rather than whichever a thread reads first
+    let limit = config
+        .limit_rows
+        .map(|n| format!(" LIMIT {}", n))
+        .unwrap_or_default();
+    format!(
+        "CREATE OR REPLACE VIEW raw_data AS SELECT * FROM {}{}{}",
+        renamed_reader_sql(config, input),
+        sample_sql(config),
+        limit
+    )
+}
+
+/// Holds each `--json-unnest` array element before its fields are spread
+/// into columns.
+const UNNEST_ELEMENT: &str = "__json_element";
+
+/// The input reader, with one row per `--json-unnest` array element rather
+/// than per document. The structs on the way to the array are spread into
+/// columns too, so `order.date` becomes `date` next to the element's
+/// fields; the document's other top-level columns are kept.
+pub(crate) fn source_reader_sql(config: &PipelineConfig, input: &InputSource) -> String {
+    let mut reader = input.reader_sql();
+    let Some((array, parents)) = config
+        .json_unnest
+        .as_ref()
+        .and_then(|unnest| unnest.path.split_last())
+    else {
+        return reader;
+    };
+    for field in parents {
+        reader = format!(
+            "(SELECT * EXCLUDE ({0}), UNNEST({0}) FROM {1})",
+            quote_ident(field),
+            reader
+        );
+    }
+    format!(
+        "(SELECT * EXCLUDE ({0}), UNNEST({0}) FROM (SELECT * EXCLUDE ({1}), UNNEST({1}) AS {0} FROM {2}))",
+        UNNEST_ELEMENT,
+        quote_ident(array),
+        reader
+    )
+}
+
+/// The `--json-unnest` path as a struct field access, e.g. `"order"."items"`.
+fn unnest_path_sql(unnest: &JsonUnnest) -> String {
+    let path: Vec<String> = unnest.path.iter().map(|f| quote_ident(f)).collect();
+    path.join(".")
+}
+
+/// DuckDB's type for the `--json-unnest` path, checked before the load.
+pub(crate) fn build_unnest_type_sql(unnest: &JsonUnnest, input: &InputSource) -> String {
+    format!(
+        "SELECT typeof({}) FROM {} LIMIT 1",
+        unnest_path_sql(unnest),
+        input.reader_sql()
+    )
+}
+
+/// Counts the JSON documents and the `--json-unnest` array elements in
+/// them, which are the rows the load sees.
+pub(crate) fn build_unnest_count_sql(unnest: &JsonUnnest, input: &InputSource) -> String {
+    format!(
+        "SELECT COUNT(*), COALESCE(SUM(len({})), 0)::BIGINT FROM {}",
+        unnest_path_sql(unnest),
+        input.reader_sql()
+    )
+}
+
+/// The input reader with the `--rename` aliases applied. DuckDB 0.10 has no
+/// `* RENAME`, so the renamed columns move to the end.
+fn renamed_reader_sql(config: &PipelineConfig, input: &InputSource) -> String {
+    if config.renames.is_empty() {
+        return source_reader_sql(config, input);
+    }
+    let sources: Vec<String> = config
+        .renames
+        .iter()
+        .map(|r| quote_ident(&r.from))
+        .collect();
+    let aliases: Vec<String> = config
+        .renames
+        .iter()
+        .map(|r| format!("{} AS {}", quote_ident(&r.from), quote_ident(&r.to)))
+        .collect();
+    format!(
+        "(SELECT * EXCLUDE ({}), {} FROM {})",
+        sources.join(", "),
+        aliases.join(", "),
+        source_reader_sql(config, input)
+    )
+}
+
+/// `--warmup-db`: a trivial query, which sets up the executor, and a
+/// zero-row read of the input, which opens it the way the load will: the
+/// CSV sniffer reads the header and a sample, Parquet its footers, and the
+/// OS caches those blocks. DuckDB sniffs again in the timed load, so this
+/// warms that work rather than skipping it.
+fn build_warmup_sql(config: &PipelineConfig, input: &InputSource) -> Vec<String> {
+    if !config.warmup_db {
+        return Vec::new();
+    }
+    vec![
+        "SELECT 1".to_string(),
+        format!(
+            "SELECT * FROM {} LIMIT 0",
+            renamed_reader_sql(config, input)
+        ),
+    ]
+}
+
+/// ` USING SAMPLE ...` for `--sample`. Bernoulli keeps each row with the same
+/// probability, and the seed makes it pick the same rows every run.
+fn sample_sql(config: &PipelineConfig) -> String {
+    match config.sample {
+        Some(fraction) => format!(
+            " USING SAMPLE {} PERCENT (bernoulli, {})",
+            fraction * 100.0,
+            config.seed
+        ),
+        None => String::new(),
+    }
+}
+
+/// Header-only CSVs infer every column as VARCHAR; this gives the numeric
+/// columns real types so the cleaning and revenue arithmetic still bind.
+fn build_empty_raw_sql(config: &PipelineConfig, input: &InputSource) -> String {
+    format!(
+        "CREATE OR REPLACE VIEW raw_data AS SELECT * REPLACE (
+                     TRY_CAST(quantity AS DOUBLE) AS quantity,
+                     TRY_CAST(price AS DOUBLE) AS price
+                 ) FROM {}",
+        renamed_reader_sql(config, input)
+    )
+}
+
+/// Bytes in DuckDB's spill files. They aren't shrunk once a query is done
+/// with them, so checked at the end of a run this shows whether it spilled.
+pub(crate) const SPILLED_BYTES_SQL: &str =
+    "SELECT COALESCE(SUM(size), 0)::UBIGINT FROM duckdb_temporary_files()";
+
+/// Every statement a run executes, built in one place so that `--dry-run`
+/// lists exactly what `run_pipeline` runs.
+pub(crate) struct PipelineSql {
+    /// Loads httpfs for `s3://` input. Shown with the credentials redacted;
+    /// `setup_s3` runs the real ones before discovery.
+    pub(crate) s3: Vec<String>,
+    pub(crate) settings: Vec<String>,
+    /// `--warmup-db` queries, run untimed before the load.
+    pub(crate) warmup: Vec<String>,
+    pub(crate) raw: String,
+    /// Replaces `raw` when the input has no rows and `--allow-empty` is set.
+    pub(crate) empty_raw: Option<String>,
+    pub(crate) clean: String,
+    pub(crate) dedup: Option<String>,
+    /// `--rates` table the transform joins.
+    pub(crate) rates: Option<String>,
+    /// The `--sql-file` statements, run before `transform`.
+    pub(crate) user_transform: Option<String>,
+    pub(crate) transform: String,
+    pub(crate) aggregate: String,
+    /// Computes the aggregate once when there are several destinations.
+    pub(crate) materialize: Option<String>,
+    /// One COPY per destination, in `PipelineConfig::destinations` order, or
+    /// the query a `--stream-jsonl` destination is written from.
+    pub(crate) copies: Vec<String>,
+    /// `--append` statements replacing the copy, for outputs that exist.
+    pub(crate) appends: Vec<Option<Vec<String>>>,
+    /// `--to-table` statements, replacing the copies; starts with the sqlite
+    /// extension's LOAD for a SQLite database.
+    pub(crate) table: Vec<String>,
+    /// `--dump-intermediate` copies of `cleaned_data` and `transformed_data`.
+    pub(crate) dump_clean: Option<String>,
+    pub(crate) dump_transform: Option<String>,
+}
+
+impl PipelineSql {
+    pub(crate) fn new(config: &PipelineConfig, input: &InputSource) -> Self {
+        Self {
+            s3: build_s3_setup_sql(config, &S3Credentials::from_env(), true),
+            settings: engine_settings_sql(config),
+            warmup: build_warmup_sql(config, input),
+            raw: build_raw_sql(config, input),
+            empty_raw: config
+                .allow_empty
+                .then(|| build_empty_raw_sql(config, input)),
+            clean: build_clean_sql(config),
+            dedup: build_dedup_sql(&config.dedup),
+            rates: build_rates_sql(config),
+            user_transform: config.sql_file.as_ref().map(|f| f.sql.trim().to_string()),
+            transform: build_transform_sql(config),
+            aggregate: build_aggregate_sql(config),
+            materialize: (!config.extra_outputs.is_empty()).then(|| {
+                format!(
+                    "CREATE OR REPLACE TEMP TABLE {} AS SELECT * FROM aggregated_data",
+                    MATERIALIZED_OUTPUT
+                )
+            }),
+            copies: config
+                .destinations()
+                .iter()
+                .map(|destination| {
+                    if config.streams(destination) {
+                        build_stream_sql(config, destination, "JSON Lines")
+                    } else if config.formats_numbers(destination) {
+                        build_stream_sql(config, destination, "formatted CSV")
+                    } else {
+                        build_copy_sql(config, destination)
+                    }
+                })
+                .collect(),
+            appends: config
+                .destinations()
+                .iter()
+                .map(|destination| {
+                    (config.append && destination.path.exists())
+                        .then(|| build_append_sql(config, destination))
+                })
+                .collect(),
+            table: build_table_sql(config),
+            dump_clean: build_dump_sql(config, "cleaned_data"),
+            dump_transform: build_dump_sql(config, "transformed_data"),
+        }
+    }
+
+    /// All statements in execution order. The empty-input replacement only
+    /// runs when no rows were loaded, which the leading comment says.
+    pub(crate) fn statements(&self) -> Vec<String> {
+        let mut statements = self.s3.clone();
+        statements.extend(self.settings.clone());
+        statements.extend(self.warmup.clone());
+        statements.push(self.raw.clone());
+        if let Some(sql) = &self.empty_raw {
+            statements.push(format!("-- Only when the input has no rows\n{}", sql));
+        }
+        statements.push(self.clean.clone());
+        statements.extend(self.dump_clean.clone());
+        statements.extend(self.dedup.clone());
+        statements.extend(self.rates.clone());
+        if let Some(sql) = &self.user_transform {
+            statements.push(sql.trim_end_matches(';').to_string());
+        }
+        statements.push(self.transform.clone());
+        statements.extend(self.dump_transform.clone());
+        statements.push(self.aggregate.clone());
+        statements.extend(self.materialize.clone());
+        for (copy, append) in self.copies.iter().zip(&self.appends) {
+            match append {
+                Some(append) => statements.extend(append.iter().cloned()),
+                None => statements.push(copy.clone()),
+            }
+        }
+        statements.extend(self.table.clone());
+        statements
+    }
+}
+
+/// Alias the `--to-table` database is attached under.
+const TABLE_DATABASE: &str = "pipeline_sink";
+
+/// Attaches the `--to-table` database, writes the table and detaches it.
+/// `--append` creates the table on the first run and then inserts by column
+/// name; `--replace` drops it first.
+fn build_table_sql(config: &PipelineConfig) -> Vec<String> {
+    let Some(target) = &config.to_table else {
+        return Vec::new();
+    };
+    let mut statements = Vec::new();
+    let attach_type = if target.is_sqlite() {
+        statements.push("LOAD sqlite".to_string());
+        " (TYPE SQLITE)"
+    } else {
+        ""
+    };
+    statements.push(format!(
+        "ATTACH {} AS {}{}",
+        sql_string(&target.database.to_string_lossy()),
+        TABLE_DATABASE,
+        attach_type
+    ));
+    let table = format!("{}.{}", TABLE_DATABASE, quote_ident(&target.table));
+    if config.append {
+        statements.push(format!(
+            "CREATE TABLE IF NOT EXISTS {} AS SELECT * FROM aggregated_data LIMIT 0",
+            table
+        ));
+        statements.push(format!(
+            "INSERT INTO {} BY NAME SELECT * FROM aggregated_data",
+            table
+        ));
+    } else {
+        if config.replace_table {
+            statements.push(format!("DROP TABLE IF EXISTS {}", table));
+        }
+        statements.push(format!(
+            "CREATE TABLE {} AS SELECT * FROM aggregated_data",
+            table
+        ));
+    }
+    statements.push(format!("DETACH {}", TABLE_DATABASE));
+    statements
+}
+
+/// `deduped_data` view, or `None` when deduplication is off.
+fn build_dedup_sql(dedup: &Dedup) -> Option<String> {
+    match dedup {
+        Dedup::Off => None,
+        Dedup::FullRow => Some(
+            "CREATE OR REPLACE VIEW deduped_data AS SELECT DISTINCT * FROM cleaned_data"
+                .to_string(),
+        ),
+        Dedup::OnColumns(columns) => {
+            let keys: Vec<String> = columns.iter().map(|c| quote_ident(c)).collect();
+            // The inner row_number() follows scan order, which is what "first" means here
+            Some(format!(
+                "CREATE OR REPLACE VIEW deduped_data AS 
+         SELECT * EXCLUDE (__input_order)
+         FROM (SELECT *, row_number() OVER () AS __input_order FROM cleaned_data)
+         QUALIFY row_number() OVER (PARTITION BY {} ORDER BY __input_order) = 1",
+                keys.join(", ")
+            ))
+        }
+    }
+}
+
+/// View the transform step reads from.
+pub(crate) fn transform_source(dedup: &Dedup) -> &'static str {
+    match dedup {
+        Dedup::Off => "cleaned_data",
+        _ => "deduped_data",
+    }
+}
+
+/// `column` parsed with the first of `formats` that matches, or NULL.
+pub(crate) fn try_strptime_sql(column: &str, formats: &[String]) -> String {
+    let attempts: Vec<String> = formats
+        .iter()
+        .map(|f| format!("TRY_STRPTIME({}, {})", quote_ident(column), sql_string(f)))
+        .collect();
+    match attempts.as_slice() {
+        [single] => single.clone(),
+        _ => format!("COALESCE({})", attempts.join(", ")),
+    }
+}
+
+/// SQL turning the date column into a DATE. A single format goes through
+/// strptime, which fails loudly on a mismatch; several are tried in order.
+fn date_sql(column: &str, date_formats: &[String]) -> String {
+    match date_formats {
+        [] => format!("CAST({} AS DATE)", quote_ident(column)),
+        [format] => format!(
+            "CAST(strptime({}, {}) AS DATE)",
+            quote_ident(column),
+            sql_string(format)
+        ),
+        formats => format!("CAST({} AS DATE)", try_strptime_sql(column, formats)),
+    }
+}
+
+/// Like `date_sql`, but NULL instead of an error for a date that doesn't
+/// parse, for conditions evaluated on raw rows.
+pub(crate) fn try_date_sql(column: &str, date_formats: &[String]) -> String {
+    match date_formats {
+        [] => format!("TRY_CAST({} AS DATE)", quote_ident(column)),
+        formats => format!("CAST({} AS DATE)", try_strptime_sql(column, formats)),
+    }
+}
+
+/// Latest date in the transformed rows, for `--state-file`.
+pub(crate) fn build_max_date_sql(config: &PipelineConfig) -> String {
+    format!(
+        "SELECT CAST(MAX({}) AS VARCHAR) FROM transformed_data",
+        date_sql(&config.date_column, &config.date_formats)
+    )
+}
+
+/// Counts the raw rows each of several `--date-formats` parsed, crediting a
+/// row to the first format that matches.
+pub(crate) fn build_date_format_count_sql(config: &PipelineConfig) -> Option<String> {
+    if config.date_formats.len() < 2 {
+        return None;
+    }
+    let matches: Vec<String> = config
+        .date_formats
+        .iter()
+        .map(|f| try_strptime_sql(&config.date_column, std::slice::from_ref(f)))
+        .collect();
+    let counts: Vec<String> = matches
+        .iter()
+        .enumerate()
+        .map(|(i, parsed)| {
+            let mut conditions = vec![format!("{} IS NOT NULL", parsed)];
+            conditions.extend(matches[..i].iter().map(|m| format!("{} IS NULL", m)));
+            format!("COUNT(*) FILTER (WHERE {})", conditions.join(" AND "))
+        })
+        .collect();
+    Some(format!("SELECT {} FROM raw_data", counts.join(", ")))
+}
+
+/// Loads the `--rates` file, with prefixed column names so they can't
+/// clash with input columns the revenue expression reads.
+fn build_rates_sql(config: &PipelineConfig) -> Option<String> {
+    let path = config.rates.as_ref()?;
+    Some(format!(
+        "CREATE OR REPLACE TEMP TABLE exchange_rates AS 
+         SELECT CAST(currency AS VARCHAR) AS __rate_currency, CAST(rate AS DOUBLE) AS __rate
+         FROM read_csv_auto({}, header = true)",
+        sql_string(&path.to_string_lossy())
+    ))
+}
+
+/// Joins the transform source to `exchange_rates`; a row whose currency has
+/// no rate gets a NULL `__rate`.
+fn rates_join_sql(source: &str) -> String {
+    format!(
+        "{} LEFT JOIN exchange_rates ON CAST(currency AS VARCHAR) = __rate_currency",
+        source
+    )
+}
+
+fn build_transform_sql(config: &PipelineConfig) -> String {
+    // The --sql-file view stands in for the built-in transform
+    if config.sql_file.is_some() {
+        return format!(
+            "CREATE OR REPLACE VIEW transformed_data AS SELECT * FROM {}",
+            USER_TRANSFORM_VIEW
+        );
+    }
+    let date = date_sql(&config.date_column, &config.date_formats);
+    let source = transform_source(&config.dedup);
+    let (columns, revenue, source) = match config.rates {
+        Some(_) => (
+            "* EXCLUDE (__rate_currency, __rate)".to_string(),
+            format!("({}) * __rate", config.revenue_expr.sql),
+            rates_join_sql(source),
+        ),
+        None => (
+            "*".to_string(),
+            config.revenue_expr.sql.clone(),
+            source.to_string(),
+        ),
+    };
+    let time_dims: String = config
+        .time_dims
+        .iter()
+        .map(|d| {
+            format!(
+                ",\n             EXTRACT({} FROM {}) AS {}",
+                d.part(),
+                date,
+                d.column()
+            )
+        })
+        .collect();
+    format!(
+        "CREATE OR REPLACE VIEW transformed_data AS 
+         SELECT 
+             {columns},
+             {revenue} AS revenue{time_dims}
+         FROM {source}"
+    )
+}
+
+/// Rows per currency in the transform source, and whether `--rates` has a
+/// rate for it; NULL currencies come last.
+pub(crate) fn build_currency_count_sql(config: &PipelineConfig) -> Option<String> {
+    config.rates.as_ref()?;
+    Some(format!(
+        "SELECT CAST(currency AS VARCHAR), COUNT(*), bool_or(__rate IS NOT NULL) 
+         FROM {}
+         GROUP BY 1
+         ORDER BY 1 NULLS LAST",
+        rates_join_sql(transform_source(&config.dedup))
+    ))
+}
+
+/// Whether the `--sql-file` created `user_transformed`, as a view or a table.
+pub(crate) fn build_user_transform_exists_sql() -> String {
+    format!(
+        "SELECT COUNT(*) FROM information_schema.tables WHERE table_name = {}",
+        sql_string(USER_TRANSFORM_VIEW)
+    )
+}
+
+/// Currencies `--rates` lists more than once, which would duplicate rows
+/// in the join.
+pub(crate) const DUPLICATE_RATES_SQL: &str =
+    "SELECT __rate_currency FROM exchange_rates GROUP BY 1 HAVING COUNT(*) > 1 ORDER BY 1";
+
+/// Where `--dump-intermediate` writes `view`.
+pub(crate) fn dump_path(dir: &Path, view: &str) -> PathBuf {
+    dir.join(format!("{}.parquet", view))
+}
+
+fn build_dump_sql(config: &PipelineConfig, view: &str) -> Option<String> {
+    let dir = config.dump_intermediate.as_ref()?;
+    Some(format!(
+        "COPY {} TO {} (FORMAT PARQUET)",
+        view,
+        sql_string(&dump_path(dir, view).to_string_lossy())
+    ))
+}
+
+/// Row count, NULL count per `not-null` column and sum per `sum` column of
+/// the first `rows` rows of `source`, for `--expect`.
+pub(crate) fn build_expectations_sql(
+    expectations: &Expectations,
+    source: &str,
+    rows: i64,
+) -> String {
+    let mut columns = vec!["COUNT(*)".to_string()];
+    columns.extend(
+        expectations
+            .not_null
+            .iter()
+            .map(|c| format!("COUNT(*) FILTER (WHERE {} IS NULL)", quote_ident(c))),
+    );
+    columns.extend(
+        expectations
+            .sum
+            .keys()
+            .map(|c| format!("COALESCE(SUM({}), 0)::DOUBLE", quote_ident(c))),
+    );
+    format!(
+        "SELECT {} FROM (SELECT * FROM {} LIMIT {})",
+        columns.join(", "),
+        source,
+        rows
+    )
+}
+
+/// Table the COPYs read when the aggregate is materialized.
+const MATERIALIZED_OUTPUT: &str = "aggregated_output";
+
+/// What the save step writes out.
+pub(crate) fn output_source(config: &PipelineConfig) -> &'static str {
+    if config.extra_outputs.is_empty() {
+        "aggregated_data"
+    } else {
+        MATERIALIZED_OUTPUT
+    }
+}
+
+/// The query `--stream-jsonl` or `--format-numbers` iterates; the comment
+/// says where rows go.
+fn build_stream_sql(
+    config: &PipelineConfig,
+    destination: &OutputDestination,
+    written_as: &str,
+) -> String {
+    format!(
+        "-- Streamed to {} as {}\nSELECT * FROM {}",
+        sql_string(&destination.path.to_string_lossy()),
+        written_as,
+        output_source(config)
+    )
+}
+
+fn build_copy_sql(config: &PipelineConfig, destination: &OutputDestination) -> String {
+    let mut options = destination.format.copy_options().to_string();
+    if destination.format == OutputFormat::Csv {
+        options.push_str(&format!(
+            ", DELIMITER {}",
+            sql_string(&config.output_delimiter.to_string())
+        ));
+    }
+    let partitions: Vec<String> = config
+        .partition_columns()
+        .into_iter()
+        .map(quote_ident)
+        .collect();
+    if !partitions.is_empty() {
+        // The directory is emptied first, so no overwrite option is needed
+        options.push_str(&format!(", PARTITION_BY ({})", partitions.join(", ")));
+    }
+    format!(
+        "COPY {} TO {} ({})",
+        output_source(config),
+        sql_string(&pending_output_path(&destination.path).to_string_lossy()),
+        options
+    )
+}
+
+/// Query reading an existing output back, for `--append`.
+pub(crate) fn build_read_output_sql(
+    config: &PipelineConfig,
+    destination: &OutputDestination,
+) -> String {
+    let path = sql_string(&destination.path.to_string_lossy());
+    match destination.format {
+        OutputFormat::Csv => format!(
+            "SELECT * FROM read_csv_auto({}, delim = {}, header = true)",
+            path,
+            sql_string(&config.output_delimiter.to_string())
+        ),
+        OutputFormat::Parquet => format!("SELECT * FROM read_parquet({})", path),
+        OutputFormat::Json | OutputFormat::JsonLines => {
+            format!("SELECT * FROM read_json_auto({})", path)
+        }
+    }
+}
+
+/// Table `--append` collects the existing and new rows of a non-CSV output in.
+const APPENDED_OUTPUT: &str = "appended_output";
+
+/// Where an output is written before being renamed over `path`, so the file
+/// at `path` is never a partial one. It's next to `path`, keeping the rename
+/// on one filesystem; for a partitioned output it's a directory.
+pub(crate) fn pending_output_path(path: &Path) -> PathBuf {
+    let mut pending = path.as_os_str().to_owned();
+    pending.push(".tmp");
+    PathBuf::from(pending)
+}
+
+/// Where `--append` stages the new CSV rows before adding them to the file.
+pub(crate) fn append_staging_path(path: &Path) -> PathBuf {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".append");
+    PathBuf::from(staging)
+}
+
+/// `--append` onto an existing output. New CSV rows are written without a
+/// header to `append_staging_path`, for the caller to add to a copy of the
+/// file. Other formats can't be appended to, so the file is read into a
+/// table, the new rows inserted and the whole file written again. Either
+/// way the result goes to `pending_output_path` first.
+fn build_append_sql(config: &PipelineConfig, destination: &OutputDestination) -> Vec<String> {
+    if destination.format == OutputFormat::Csv {
+        return vec![format!(
+            "COPY {} TO {} (HEADER false, DELIMITER {})",
+            output_source(config),
+            sql_string(&append_staging_path(&destination.path).to_string_lossy()),
+            sql_string(&config.output_delimiter.to_string())
+        )];
+    }
+    vec![
+        format!(
+            "CREATE OR REPLACE TEMP TABLE {} AS {}",
+            APPENDED_OUTPUT,
+            build_read_output_sql(config, destination)
+        ),
+        format!(
+            "INSERT INTO {} SELECT * FROM {}",
+            APPENDED_OUTPUT,
+            output_source(config)
+        ),
+        format!(
+            "COPY {} TO {} ({})",
+            APPENDED_OUTPUT,
+            sql_string(&pending_output_path(&destination.path).to_string_lossy()),
+            destination.format.copy_options()
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{parse_rule, Predicate};
+
+    #[test]
+    fn rules_parse_and_build_where_clause() {
+        let rule = parse_rule("order_id:not-null").unwrap();
+        assert_eq!(rule, ValidationRule::new("order_id", Predicate::NotNull));
+        assert!(parse_rule("order_id").is_err());
+        assert!(parse_rule("order_id:even").is_err());
+
+        let config = PipelineConfig {
+            rules: vec![
+                ValidationRule::new("order_id", Predicate::NotNull),
+                ValidationRule::new("amount", Predicate::Positive),
+            ],
+            ..PipelineConfig::default()
+        };
+        let sql = build_clean_sql(&config);
+        assert!(sql.ends_with(r#"WHERE "order_id" IS NOT NULL AND "amount" > 0"#));
+    }
+
+    #[test]
+    fn round_wraps_only_the_monetary_columns() {
+        let config = PipelineConfig {
+            round: Some(2),
+            quantiles: vec![0.5],
+            ..PipelineConfig::default()
+        };
+        let sql = build_aggregate_sql(&config);
+        assert!(sql.contains(r#"ROUND(SUM("revenue"), 2) AS "total_revenue""#));
+        assert!(sql.contains(r#"ROUND(AVG("price"), 2) AS "avg_price""#));
+        assert!(sql.contains("ROUND(APPROX_QUANTILE(price, 0.5), 2) AS"));
+        assert!(sql.contains(r#"SUM("quantity") AS "total_quantity""#));
+        assert!(!build_aggregate_sql(&PipelineConfig::default()).contains("ROUND("));
+    }
+}
@@ -0,0 +1,87 @@
+//! Statistics over repeated `--runs`.
+
+use crate::term::{summary_rule, title};
+
+/// Summary statistics over the samples of a repeated measurement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub stddev: f64,
+}
+
+impl Stats {
+    pub fn from_samples(samples: &[f64]) -> Option<Stats> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let n = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / n as f64;
+        let median = if n % 2 == 1 {
+            sorted[n / 2]
+        } else {
+            (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+        };
+        // Sample standard deviation; a single run has no spread
+        let stddev = if n > 1 {
+            (sorted.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+        Some(Stats {
+            min: sorted[0],
+            max: sorted[n - 1],
+            mean,
+            median,
+            stddev,
+        })
+    }
+}
+
+pub fn print_run_statistics(durations: &[f64], peak_memory: &[f64], rows_per_sec: &[f64]) {
+    println!("\n{}", summary_rule());
+    println!(
+        "{}",
+        title(format!("Benchmark Statistics ({} runs)", durations.len()))
+    );
+    println!("{}", summary_rule());
+    println!(
+        "{:<16} {:>9} {:>9} {:>9} {:>9} {:>9}",
+        "", "min", "max", "mean", "median", "stddev"
+    );
+    for (label, samples) in [
+        ("Duration (s)", durations),
+        ("Peak Mem (MB)", peak_memory),
+        ("Rows/sec", rows_per_sec),
+    ] {
+        if let Some(stats) = Stats::from_samples(samples) {
+            println!(
+                "{:<16} {:>9.2} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+                label, stats.min, stats.max, stats.mean, stats.median, stats.stddev
+            );
+        }
+    }
+    println!("{}", summary_rule());
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_over_samples() {
+        let stats = Stats::from_samples(&[4.0, 1.0, 3.0, 2.0]).unwrap();
+        assert_eq!(
+            (stats.min, stats.max, stats.mean, stats.median),
+            (1.0, 4.0, 2.5, 2.5)
+        );
+        assert!((stats.stddev - 1.2910).abs() < 1e-4);
+        assert_eq!(Stats::from_samples(&[7.0]).unwrap().stddev, 0.0);
+        assert!(Stats::from_samples(&[]).is_none());
+    }
+}
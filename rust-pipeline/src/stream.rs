@@ -0,0 +1,77 @@
+//! `--stream-jsonl`: writes JSON Lines output row by row from a query
+//! instead of through `COPY`, so a reader can start before the run ends.
+
+use anyhow::{Context, Result};
+use chrono::{Duration, NaiveDate};
+use duckdb::types::Value;
+use duckdb::Connection;
+use serde_json::{Map, Number, Value as Json};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Rows written between flushes, so consumers see output as it's produced.
+const FLUSH_EVERY_ROWS: u64 = 1000;
+
+/// A DuckDB DATE, stored as days since the epoch, in ISO form.
+pub(crate) fn date_string(days: i32) -> String {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).expect("valid date");
+    (epoch + Duration::days(i64::from(days))).to_string()
+}
+
+/// DuckDB values as JSON the way `COPY (FORMAT JSON)` writes them: numbers
+/// stay numbers, dates become ISO strings and anything exotic is quoted.
+fn to_json(value: Value) -> Json {
+    let number = |n: f64| Number::from_f64(n).map_or(Json::Null, Json::Number);
+    match value {
+        Value::Null => Json::Null,
+        Value::Boolean(b) => Json::Bool(b),
+        Value::TinyInt(n) => Json::from(n),
+        Value::SmallInt(n) => Json::from(n),
+        Value::Int(n) => Json::from(n),
+        Value::BigInt(n) => Json::from(n),
+        Value::UTinyInt(n) => Json::from(n),
+        Value::USmallInt(n) => Json::from(n),
+        Value::UInt(n) => Json::from(n),
+        Value::UBigInt(n) => Json::from(n),
+        // COPY writes HUGEINT, e.g. SUM(BIGINT), as a double too
+        Value::HugeInt(n) => number(n as f64),
+        Value::Float(n) => number(f64::from(n)),
+        Value::Double(n) => number(n),
+        Value::Decimal(d) => Json::String(d.to_string()),
+        Value::Text(s) | Value::Enum(s) => Json::String(s),
+        Value::Date32(days) => Json::String(date_string(days)),
+        Value::List(values) => Json::Array(values.into_iter().map(to_json).collect()),
+        other => Json::String(format!("{:?}", other)),
+    }
+}
+
+/// Runs `query` and writes one JSON object per row to `path`; returns the
+/// number of rows written.
+pub(crate) fn stream_jsonl(conn: &Connection, query: &str, path: &Path) -> Result<u64> {
+    let mut out = BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    let mut stmt = conn.prepare(query)?;
+    let mut rows = stmt.query([])?;
+    let columns = rows
+        .as_ref()
+        .map(|stmt| stmt.column_names())
+        .unwrap_or_default();
+    let mut written = 0;
+    while let Some(row) = rows.next()? {
+        let mut object = Map::with_capacity(columns.len());
+        for (i, column) in columns.iter().enumerate() {
+            object.insert(column.clone(), to_json(row.get::<_, Value>(i)?));
+        }
+        serde_json::to_writer(&mut out, &object)?;
+        out.write_all(b"\n")?;
+        written += 1;
+        if written % FLUSH_EVERY_ROWS == 0 {
+            out.flush()?;
+        }
+    }
+    out.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(written)
+}
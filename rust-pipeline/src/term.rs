@@ -0,0 +1,64 @@
+//! Terminal-aware summary output: rules as wide as the terminal and colour
+//! for the figures worth a look. Piped output keeps the plain 60 columns,
+//! and `NO_COLOR` or `--color never` turns the colour off.
+
+use console::{style, StyledObject, Term};
+use std::fmt::Display;
+
+/// Rule width when stdout isn't a terminal, so logged summaries don't change.
+const PLAIN_WIDTH: usize = 60;
+/// Wider rules would only stretch the tables' whitespace.
+const MAX_WIDTH: usize = 120;
+
+/// The `=` rule around each summary table.
+pub fn summary_rule() -> String {
+    rule(PLAIN_WIDTH)
+}
+
+/// A rule as wide as the terminal, or `plain_width` when piped; for tables
+/// wider than the usual 60 columns.
+pub(crate) fn rule(plain_width: usize) -> String {
+    let term = Term::stdout();
+    let width = match term.size_checked() {
+        Some((_, columns)) if term.is_term() => usize::from(columns).min(MAX_WIDTH),
+        _ => plain_width,
+    };
+    "=".repeat(width)
+}
+
+/// Table titles.
+pub(crate) fn title<D: Display>(text: D) -> StyledObject<D> {
+    style(text).bold()
+}
+
+/// A figure past a threshold, e.g. peak memory over `--memory-warn-mb`.
+pub(crate) fn warning<D: Display>(value: D) -> StyledObject<D> {
+    style(value).yellow()
+}
+
+/// A regression or failure.
+pub(crate) fn bad<D: Display>(value: D) -> StyledObject<D> {
+    style(value).red().bold()
+}
+
+/// An improvement.
+pub(crate) fn good<D: Display>(value: D) -> StyledObject<D> {
+    style(value).green()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piped_output_stays_plain() {
+        // Test output is captured, so stdout isn't a terminal here
+        assert_eq!(summary_rule(), "=".repeat(PLAIN_WIDTH));
+        assert_eq!(rule(78).len(), 78);
+        console::set_colors_enabled(false);
+        assert_eq!(warning("1024.00 MB").to_string(), "1024.00 MB");
+        console::set_colors_enabled(true);
+        assert!(bad("REGRESSION").to_string().contains("\u{1b}["));
+        console::set_colors_enabled(false);
+    }
+}
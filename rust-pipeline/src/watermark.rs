@@ -0,0 +1,31 @@
+//! `--state-file`: the latest date a run processed, which `--since auto`
+//! picks up as the next run's high-water mark.
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use std::path::Path;
+
+/// The date stored in `path`, or `None` before the first run wrote one.
+pub fn read_watermark(path: &Path) -> Result<Option<NaiveDate>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let date = NaiveDate::parse_from_str(text.trim(), "%Y-%m-%d").with_context(|| {
+        format!(
+            "{} doesn't hold a YYYY-MM-DD date: '{}'",
+            path.display(),
+            text.trim()
+        )
+    })?;
+    Ok(Some(date))
+}
+
+pub(crate) fn write_watermark(path: &Path, date: NaiveDate) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).context("Failed to create state directory")?;
+    }
+    std::fs::write(path, format!("{}\n", date))
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
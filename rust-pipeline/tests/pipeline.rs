@@ -0,0 +1,512 @@
+//! End-to-end runs of the pipeline, through the binary and the library API,
+//! against generated fixtures.
+
+use rust_pipeline::{run_pipeline, OutputFormat, PipelineConfig, PipelineMetrics};
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Deterministic sales data: 8 valid rows over three products plus one row
+/// failing each default cleaning rule.
+fn write_fixture(data_dir: &Path) {
+    let mut csv = String::from("date,product_id,quantity,price\n");
+    for i in 0..8 {
+        let product = ["P1", "P2", "P3"][i % 3];
+        csv.push_str(&format!(
+            "2023-{:02}-15,{},{},{}.5\n",
+            i + 1,
+            product,
+            i + 1,
+            i
+        ));
+    }
+    csv.push_str("2023-09-01,,3,1.0\n"); // missing product_id
+    csv.push_str("2023-09-02,P1,-1,1.0\n"); // non-positive quantity
+    csv.push_str("2023-09-03,P2,2,0\n"); // non-positive price
+    csv.push_str("not-a-date,P3,2,1.0\n"); // invalid date
+    std::fs::create_dir_all(data_dir).unwrap();
+    std::fs::write(data_dir.join("sales.csv"), csv).unwrap();
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rust-pipeline"))
+        .args(args)
+        .env("RUST_LOG", "warn")
+        .output()
+        .expect("failed to run the pipeline binary")
+}
+
+#[test]
+fn aggregates_fixture_and_reports_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+    let metrics = dir.path().join("metrics.json");
+
+    let result = run(&[
+        data_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--metrics-json",
+        metrics.to_str().unwrap(),
+        "--diagnose",
+    ]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    let csv = std::fs::read_to_string(&output).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "product_id,total_quantity,total_revenue,avg_price",
+            "P2,15,85.5,4.5",
+            "P1,12,60.0,3.5",
+            "P3,9,40.5,4.0",
+        ]
+    );
+
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&metrics).unwrap()).unwrap();
+    assert_eq!(report["success"], true);
+    assert!(report["duckdb_version"].as_str().unwrap().starts_with('v'));
+    assert_eq!(report["rows"]["raw"], 12);
+    assert_eq!(report["rows"]["cleaned"], 8);
+    assert_eq!(report["rows"]["removed"], 4);
+    assert_eq!(report["rows"]["aggregated"], 3);
+    let (raw_per_sec, cleaned_per_sec) = (
+        report["rows_per_sec"].as_f64().unwrap(),
+        report["cleaned_rows_per_sec"].as_f64().unwrap(),
+    );
+    assert!(raw_per_sec > cleaned_per_sec && cleaned_per_sec > 0.0);
+    let removed_by_rule: Vec<i64> = report["removed_by_rule"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|r| r["rows"].as_i64().unwrap())
+        .collect();
+    assert_eq!(removed_by_rule, [1, 1, 1, 1]);
+}
+
+#[test]
+fn library_api_returns_counts() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let config = PipelineConfig {
+        data_dir: data_dir.to_str().unwrap().to_string(),
+        output_path: dir.path().join("out").to_str().unwrap().to_string(),
+        format: OutputFormat::Parquet,
+        ..PipelineConfig::default()
+    };
+
+    let result = run_pipeline(&config, &mut PipelineMetrics::new()).unwrap();
+    assert_eq!(result.raw_rows, 12);
+    assert_eq!(result.cleaned_rows, 8);
+    assert_eq!(result.removed_rows, 4);
+    assert_eq!(result.aggregated_rows, 3);
+    assert!(result.output_size_bytes.unwrap() > 0);
+    assert!(dir.path().join("out.parquet").exists());
+}
+
+#[test]
+fn reads_csv_from_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_rust-pipeline"))
+        .args(["-", output.to_str().unwrap()])
+        .env("RUST_LOG", "warn")
+        .stdin(std::fs::File::open(data_dir.join("sales.csv")).unwrap())
+        .output()
+        .expect("failed to run the pipeline binary");
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(String::from_utf8_lossy(&result.stdout).contains("Input: stdin"));
+    assert_eq!(std::fs::read_to_string(&output).unwrap().lines().count(), 4);
+}
+
+#[test]
+fn env_vars_fill_in_options_not_given_as_args() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("env_output");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_rust-pipeline"))
+        .args(["--format", "csv"])
+        .env("RUST_LOG", "info")
+        .env("PIPELINE_DATA_DIR", &data_dir)
+        .env("PIPELINE_OUTPUT", &output)
+        .env("PIPELINE_FORMAT", "parquet")
+        .env("PIPELINE_TOP_N", "1")
+        .output()
+        .expect("failed to run the pipeline binary");
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    // --format beats PIPELINE_FORMAT
+    let csv = std::fs::read_to_string(output.with_extension("csv")).unwrap();
+    assert_eq!(csv.lines().count(), 2);
+    // The banner shows values that came from the environment
+    let log = String::from_utf8_lossy(&result.stderr);
+    assert!(log.contains("Top N: 1"), "{}", log);
+}
+
+#[test]
+fn failure_message_includes_the_underlying_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+
+    // Measured and warmup runs report failures the same way
+    for warmup in ["0", "1"] {
+        let result = run(&[
+            data_dir.to_str().unwrap(),
+            output.to_str().unwrap(),
+            "--revenue-expr",
+            "quantity *",
+            "--warmup",
+            warmup,
+        ]);
+        assert_eq!(result.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        assert!(
+            stderr.contains("failed to compile: Parser Error"),
+            "{}",
+            stderr
+        );
+    }
+}
+
+#[test]
+fn missing_data_dir_is_a_usage_error() {
+    let dir = tempfile::tempdir().unwrap();
+    let result = run(&[dir.path().join("nope").to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("not an existing directory"));
+}
+
+#[test]
+fn failures_exit_with_their_category_code() {
+    let dir = tempfile::tempdir().unwrap();
+    let empty_dir = dir.path().join("empty");
+    std::fs::create_dir_all(&empty_dir).unwrap();
+    let output = dir.path().join("out.csv");
+
+    let result = run(&[empty_dir.to_str().unwrap(), output.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("No CSV, Parquet or JSON files found"),
+        "{}",
+        stderr
+    );
+
+    let schema_dir = dir.path().join("schema");
+    std::fs::create_dir_all(&schema_dir).unwrap();
+    std::fs::write(
+        schema_dir.join("sales.csv"),
+        "date,sku,quantity,price\n2023-01-05,P1,2,10\n",
+    )
+    .unwrap();
+    let result = run(&[schema_dir.to_str().unwrap(), output.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("missing required column(s): product_id"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn repeated_output_writes_each_format() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let csv = dir.path().join("out.csv");
+    let parquet = dir.path().join("lake").join("out.parquet");
+    let metrics = dir.path().join("metrics.json");
+
+    let result = run(&[
+        data_dir.to_str().unwrap(),
+        "--output",
+        csv.to_str().unwrap(),
+        "--output",
+        parquet.to_str().unwrap(),
+        "--metrics-json",
+        metrics.to_str().unwrap(),
+    ]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert_eq!(std::fs::read_to_string(&csv).unwrap().lines().count(), 4);
+    assert!(std::fs::read(&parquet).unwrap().starts_with(b"PAR1"));
+    let report = rust_pipeline::read_metrics_json(&metrics).unwrap();
+    let formats: Vec<&str> = report.outputs.iter().map(|o| o.format.as_str()).collect();
+    assert_eq!(formats, ["CSV", "Parquet"]);
+
+    let result = run(&[data_dir.to_str().unwrap(), "--output", "out.txt"]);
+    assert_eq!(result.status.code(), Some(2));
+}
+
+#[test]
+fn checksum_is_stable_across_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let checksum = |name: &str| {
+        let metrics = dir.path().join(format!("{}.json", name));
+        let result = run(&[
+            data_dir.to_str().unwrap(),
+            dir.path().join(name).to_str().unwrap(),
+            "--checksum",
+            "--metrics-json",
+            metrics.to_str().unwrap(),
+        ]);
+        assert!(
+            result.status.success(),
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        let sha256 = rust_pipeline::read_metrics_json(&metrics).unwrap().outputs[0]
+            .sha256
+            .clone()
+            .unwrap();
+        assert!(String::from_utf8_lossy(&result.stdout).contains(&sha256));
+        sha256
+    };
+    assert_eq!(checksum("first"), checksum("second"));
+}
+
+#[test]
+fn timeout_aborts_a_run_that_takes_too_long() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+
+    // Twenty runs of even this fixture take well over 50ms
+    let result = run(&[
+        data_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--runs",
+        "20",
+        "--timeout",
+        "0.05",
+    ]);
+    assert_eq!(result.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("Pipeline exceeded the --timeout of 0.05s"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn since_auto_resumes_from_the_state_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+    let state = dir.path().join("state").join("watermark");
+    let incremental_run = || {
+        let result = Command::new(env!("CARGO_BIN_EXE_rust-pipeline"))
+            .args([data_dir.to_str().unwrap(), output.to_str().unwrap()])
+            .args(["--since", "auto", "--state-file", state.to_str().unwrap()])
+            .env("RUST_LOG", "info")
+            .output()
+            .expect("failed to run the pipeline binary");
+        assert!(
+            result.status.success(),
+            "{}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+        String::from_utf8_lossy(&result.stderr).into_owned()
+    };
+
+    incremental_run();
+    assert_eq!(std::fs::read_to_string(&state).unwrap(), "2023-08-15\n");
+    assert_eq!(std::fs::read_to_string(&output).unwrap().lines().count(), 4);
+
+    let log = incremental_run();
+    assert!(
+        log.contains("Skipped 7 rows before watermark 2023-08-15"),
+        "{}",
+        log
+    );
+    let csv = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(csv.lines().nth(1), Some("P2,8,60.0,7.5"));
+    assert_eq!(csv.lines().count(), 2);
+}
+
+#[test]
+fn config_file_fills_in_options_below_the_command_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+    let config = dir.path().join("benchmark.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "data-dir = {:?}\noutput_path = {:?}\ntop-n = 1\ngroup-by = [\"product_id\"]\n\
+             diagnose = true\nround = 4\n",
+            data_dir.to_str().unwrap(),
+            output.to_str().unwrap()
+        ),
+    )
+    .unwrap();
+
+    // --top-n on the command line beats the file
+    let result = run(&["--config", config.to_str().unwrap(), "--top-n", "2"]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let csv = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(csv.lines().count(), 3);
+
+    std::fs::write(&config, "top-n = 1\nthreds = 4\nfrobnicate = true\n").unwrap();
+    let result = run(&["--config", config.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("has unknown option(s): frobnicate, threds"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn comma_separated_data_dirs_are_read_as_one_input() {
+    let dir = tempfile::tempdir().unwrap();
+    let region_a = dir.path().join("region-a");
+    let region_b = dir.path().join("region-b");
+    write_fixture(&region_a);
+    write_fixture(&region_b);
+    let output = dir.path().join("out.csv");
+    let metrics = dir.path().join("metrics.json");
+    let data_dirs = format!("{},{}", region_a.display(), region_b.display());
+
+    let result = run(&[
+        &data_dirs,
+        output.to_str().unwrap(),
+        "--metrics-json",
+        metrics.to_str().unwrap(),
+    ]);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    let report = rust_pipeline::read_metrics_json(&metrics).unwrap();
+    assert_eq!(report.rows.raw, Some(24));
+    let csv = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(csv.lines().nth(1), Some("P2,30,171.0,4.5"));
+
+    let missing = format!(
+        "{},{}",
+        region_a.display(),
+        dir.path().join("region-c").display()
+    );
+    let result = run(&[&missing, output.to_str().unwrap()]);
+    assert_eq!(result.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(
+        stderr.contains("region-c' is not an existing directory"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn json_only_prints_just_the_report() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+
+    let result = run(&[
+        data_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--json-only",
+    ]);
+    assert!(result.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&result.stdout).unwrap();
+    assert_eq!(report["success"], true);
+    assert_eq!(report["rows"]["aggregated"], 3);
+
+    let result = run(&[
+        data_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--json-only",
+        "--fail-on-removed-pct",
+        "10",
+    ]);
+    assert_eq!(result.status.code(), Some(4));
+    let report: serde_json::Value = serde_json::from_slice(&result.stdout).unwrap();
+    assert_eq!(report["success"], false);
+    assert!(report["error"]
+        .as_str()
+        .unwrap()
+        .starts_with("Cleaning removed 33.33% of rows"));
+}
+
+#[test]
+fn baseline_fails_a_run_that_regressed() {
+    let dir = tempfile::tempdir().unwrap();
+    let data_dir = dir.path().join("data");
+    write_fixture(&data_dir);
+    let output = dir.path().join("out.csv");
+    let baseline = dir.path().join("baseline.json");
+    let args = [
+        data_dir.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--baseline",
+        baseline.to_str().unwrap(),
+    ];
+
+    let result = run(&args);
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&result.stderr).contains("--update-baseline writes it"));
+
+    let result = run(&[&args[..], &["--update-baseline"]].concat());
+    assert!(result.status.success());
+    let mut report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&baseline).unwrap()).unwrap();
+
+    // Far slower and bigger than any run, then far faster
+    report["duration_secs"] = 1000.0.into();
+    report["peak_memory_mb"] = 1e6.into();
+    std::fs::write(&baseline, report.to_string()).unwrap();
+    let result = run(&args);
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    assert!(String::from_utf8_lossy(&result.stdout).contains("Metrics Comparison"));
+
+    report["duration_secs"] = 1e-6.into();
+    std::fs::write(&baseline, report.to_string()).unwrap();
+    let result = run(&[&args[..], &["--tolerance", "50"]].concat());
+    assert_eq!(result.status.code(), Some(6));
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("Regressed by more than 50%"), "{}", stderr);
+}